@@ -16,7 +16,7 @@ fn main() {
         .plot_xyargs(&vec![0, 1, 2, 3, 4, 5, 6], &vec![0, 1, 2, 3, 2, 1, 0], "+")
         .show();
     program.background_run();
-    handles.0.join();
-    handles.1.join();
-    handles.2.join();
+    handles.0.join().unwrap().unwrap();
+    handles.1.join().unwrap().unwrap();
+    handles.2.join().unwrap().unwrap();
 }
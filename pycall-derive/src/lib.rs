@@ -0,0 +1,592 @@
+//! `#[derive(AsPythonLitteral)]` and `py!`, re-exported by `pycall` behind its `derive` feature.
+//!
+//! Structs become `dict`s by default. `#[python(dataclass)]`, `#[python(namedtuple)]` and `#[python(namespace)]`
+//! turn them into instances of a `dataclasses.dataclass`, a `collections.namedtuple` or a `types.SimpleNamespace`,
+//! the class being declared once per program. Tuple structs become tuples and newtypes their inner value,
+//! unless one of these attributes is used.
+//!
+//! Enums become string tags by default, data-carrying variants being written as `{tag: payload}`.
+//! `#[python(enum)]` instead declares an `enum.Enum` whose members are the (unit only) variants.
+//!
+//! Like serde, `#[python(rename = "...")]` renames a field, a variant or the generated class,
+//! `#[python(rename_all = "...")]` renames every field or variant of a type, and `#[python(skip)]` skips a field.
+//! Names that Python sees as identifiers (classes, attributes and enum members) are checked at compile time,
+//! while dict keys and string tags may be any string. Where a hashable value is required, dicts are written
+//! as tuples of `(key, value)` pairs.
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+mod py;
+
+#[proc_macro_derive(AsPythonLitteral, attributes(python))]
+pub fn derive_as_python_litteral(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Writes a line, or a multi-line snippet, of python to a program: `py!(program, "plt.plot({x}, {y}, label={name})")`.
+///
+/// `{name}` writes the rust value `name` as a literal, through `AsPythonLitteral`; like in `format!`,
+/// `{}` takes the next positional argument and `{name}` a named argument (`name = value`) or a variable in scope.
+/// `{name:raw}` writes `name` as python source instead, through `AsPythonSource`: use it for identifiers and `Expr`s.
+/// `{{` and `}}` are python braces.
+///
+/// Snippets are dedented, then reindented at the program's current level.
+/// Unmatched braces, unknown placeholders, unbalanced python brackets or strings,
+/// placeholders inside python strings and unused arguments fail to compile.
+/// Evaluates to the program, as a `&mut PythonProgram`.
+#[proc_macro]
+pub fn py(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as py::PyInput);
+    py::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Dict,
+    Dataclass,
+    NamedTuple,
+    Namespace,
+    Enum,
+}
+
+#[derive(Clone, Copy)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+    Kebab,
+    ScreamingKebab,
+}
+
+impl RenameRule {
+    fn parse(name: &LitStr) -> syn::Result<Self> {
+        Ok(match name.value().as_str() {
+            "lowercase" => RenameRule::Lower,
+            "UPPERCASE" => RenameRule::Upper,
+            "PascalCase" => RenameRule::Pascal,
+            "camelCase" => RenameRule::Camel,
+            "snake_case" => RenameRule::Snake,
+            "SCREAMING_SNAKE_CASE" => RenameRule::ScreamingSnake,
+            "kebab-case" => RenameRule::Kebab,
+            "SCREAMING-KEBAB-CASE" => RenameRule::ScreamingKebab,
+            _ => return Err(syn::Error::new(name.span(), "unknown rename rule")),
+        })
+    }
+
+    fn apply(self, name: &str) -> String {
+        let mut words: Vec<String> = Vec::new();
+        let mut previous_lowercase = false;
+        for c in name.chars() {
+            if c == '_' {
+                words.push(String::new());
+                previous_lowercase = false;
+                continue;
+            }
+            if words.is_empty() || (c.is_uppercase() && previous_lowercase) {
+                words.push(String::new());
+            }
+            previous_lowercase = c.is_lowercase() || c.is_numeric();
+            words.last_mut().unwrap().push(c);
+        }
+        words.retain(|word| !word.is_empty());
+        let capitalize = |word: &String| {
+            let mut chars = word.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first
+                    .to_uppercase()
+                    .chain(chars.flat_map(char::to_lowercase))
+                    .collect()
+            })
+        };
+        let words = words.iter();
+        match self {
+            RenameRule::Lower => words.map(|w| w.to_lowercase()).collect(),
+            RenameRule::Upper => words.map(|w| w.to_uppercase()).collect(),
+            RenameRule::Pascal => words.map(capitalize).collect(),
+            RenameRule::Camel => words
+                .enumerate()
+                .map(|(i, w)| {
+                    if i == 0 {
+                        w.to_lowercase()
+                    } else {
+                        capitalize(w)
+                    }
+                })
+                .collect(),
+            RenameRule::Snake => words
+                .map(|w| w.to_lowercase())
+                .collect::<Vec<_>>()
+                .join("_"),
+            RenameRule::ScreamingSnake => words
+                .map(|w| w.to_uppercase())
+                .collect::<Vec<_>>()
+                .join("_"),
+            RenameRule::Kebab => words
+                .map(|w| w.to_lowercase())
+                .collect::<Vec<_>>()
+                .join("-"),
+            RenameRule::ScreamingKebab => words
+                .map(|w| w.to_uppercase())
+                .collect::<Vec<_>>()
+                .join("-"),
+        }
+    }
+}
+
+struct ContainerAttrs {
+    style: Style,
+    rename: Option<String>,
+    rename_all: Option<RenameRule>,
+}
+
+#[derive(Default)]
+struct MemberAttrs {
+    rename: Option<String>,
+    skip: bool,
+}
+
+fn container_attrs(attrs: &[syn::Attribute]) -> syn::Result<ContainerAttrs> {
+    let mut result = ContainerAttrs {
+        style: Style::Dict,
+        rename: None,
+        rename_all: None,
+    };
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("python")) {
+        attr.parse_nested_meta(|meta| {
+            let style = if meta.path.is_ident("dict") {
+                Style::Dict
+            } else if meta.path.is_ident("dataclass") {
+                Style::Dataclass
+            } else if meta.path.is_ident("namedtuple") {
+                Style::NamedTuple
+            } else if meta.path.is_ident("namespace") {
+                Style::Namespace
+            } else if meta.path.is_ident("enum") {
+                Style::Enum
+            } else if meta.path.is_ident("rename") {
+                result.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+                return Ok(());
+            } else if meta.path.is_ident("rename_all") {
+                result.rename_all = Some(RenameRule::parse(&meta.value()?.parse()?)?);
+                return Ok(());
+            } else {
+                return Err(meta.error("unknown python attribute"));
+            };
+            result.style = style;
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+fn member_attrs(attrs: &[syn::Attribute]) -> syn::Result<MemberAttrs> {
+    let mut result = MemberAttrs::default();
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("python")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                result.rename = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("skip") {
+                result.skip = true;
+            } else {
+                return Err(meta.error("unknown python attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+/// Python's keywords, which can't name classes, attributes or enum members.
+/// Kept in sync with the lists of `pycall::is_keyword`, so that types are named by the same rules as variables.
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+/// Python's soft keywords, which are only keywords in some contexts, but make confusing names.
+const SOFT_KEYWORDS: &[&str] = &["_", "case", "match", "type"];
+
+/// Checks that `name` can be used as a Python identifier, reporting errors at `span`.
+fn check_identifier(name: &str, span: Span) -> syn::Result<()> {
+    let mut chars = name.chars();
+    let identifier = match chars.next() {
+        Some(first) => {
+            (first == '_' || unicode_ident::is_xid_start(first))
+                && chars.all(unicode_ident::is_xid_continue)
+        }
+        None => false,
+    };
+    if !identifier {
+        Err(syn::Error::new(
+            span,
+            format!("`{}` is not a python identifier", name),
+        ))
+    } else if KEYWORDS.contains(&name) {
+        Err(syn::Error::new(
+            span,
+            format!("`{}` is a python keyword", name),
+        ))
+    } else if SOFT_KEYWORDS.contains(&name) {
+        Err(syn::Error::new(
+            span,
+            format!("`{}` is a python soft keyword", name),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// A field that is written to Python.
+struct Field {
+    /// How the field is accessed on `self`, or the binding it was destructured into.
+    access: TokenStream,
+    /// The Python name of the field, `None` for tuple fields.
+    name: Option<String>,
+    ty: syn::Type,
+    span: Span,
+}
+
+/// The name the `i`th field of a variant is bound to, which can't shadow the formatter `f`.
+fn binding(i: usize) -> syn::Ident {
+    format_ident!("__pycall_field{}", i)
+}
+
+fn fields(fields: &Fields, rename_all: Option<RenameRule>, bind: bool) -> syn::Result<Vec<Field>> {
+    let mut result = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let attrs = member_attrs(&field.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+        let (access, name) = match &field.ident {
+            _ if bind => {
+                let binding = binding(i);
+                (
+                    quote!(#binding),
+                    field.ident.as_ref().map(|ident| ident.to_string()),
+                )
+            }
+            Some(ident) => (quote!(&self.#ident), Some(ident.to_string())),
+            None => {
+                let index = syn::Index::from(i);
+                (quote!(&self.#index), None)
+            }
+        };
+        let name = name.map(|name| {
+            let name = name.trim_start_matches("r#").to_owned();
+            match (attrs.rename, rename_all) {
+                (Some(rename), _) => rename,
+                (None, Some(rule)) => rule.apply(&name),
+                (None, None) => name,
+            }
+        });
+        result.push(Field {
+            access,
+            name,
+            ty: field.ty.clone(),
+            span: field.span(),
+        });
+    }
+    Ok(result)
+}
+
+/// The names Python sees for `fields`, tuple fields being called `_0`, `_1`...
+fn python_names(fields: &[Field]) -> Vec<String> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| field.name.clone().unwrap_or_else(|| format!("_{}", i)))
+        .collect()
+}
+
+fn write_str(s: &str) -> TokenStream {
+    quote!(f.write_str(#s)?;)
+}
+
+fn write_literal(access: &TokenStream) -> TokenStream {
+    quote!(::pycall::AsPythonLitteral::fmt(#access, f)?;)
+}
+
+/// Writes a dict of `items`, or the tuple of its `(key, value)` pairs when a hashable value is required.
+fn write_items(items: &[(TokenStream, TokenStream)]) -> TokenStream {
+    let entries = items.iter().map(|(key, value)| {
+        quote! {
+            #key
+            f.write_str(":")?;
+            #value
+            f.write_str(",")?;
+        }
+    });
+    let pairs = items.iter().map(|(key, value)| {
+        quote! {
+            f.write_str("(")?;
+            #key
+            f.write_str(",")?;
+            #value
+            f.write_str("),")?;
+        }
+    });
+    quote! {
+        if f.alternate() {
+            f.write_str("(")?;
+            #(#pairs)*
+            f.write_str(")")?;
+        } else {
+            f.write_str("{")?;
+            #(#entries)*
+            f.write_str("}")?;
+        }
+    }
+}
+
+/// Writes a dict, with string keys.
+fn write_dict(fields: &[Field]) -> TokenStream {
+    let items: Vec<_> = fields
+        .iter()
+        .zip(python_names(fields))
+        .map(|(field, name)| {
+            (
+                quote!(::pycall::AsPythonLitteral::fmt(#name, f)?;),
+                write_literal(&field.access),
+            )
+        })
+        .collect();
+    write_items(&items)
+}
+
+/// Writes a tuple, or the value itself for single (newtype) fields.
+fn write_tuple(fields: &[Field]) -> TokenStream {
+    if let [field] = fields {
+        return write_literal(&field.access);
+    }
+    let items = fields.iter().map(|field| {
+        let value = write_literal(&field.access);
+        quote!(#value f.write_str(",")?;)
+    });
+    quote! {
+        f.write_str("(")?;
+        #(#items)*
+        f.write_str(")")?;
+    }
+}
+
+/// Writes a call to `callee`, passing the fields positionally or by keyword.
+fn write_call(callee: &str, fields: &[Field], keywords: bool) -> TokenStream {
+    let open = write_str(&format!("{}(", callee));
+    let args = fields
+        .iter()
+        .zip(python_names(fields))
+        .map(|(field, name)| {
+            let keyword = if keywords {
+                write_str(&format!("{}=", name))
+            } else {
+                TokenStream::new()
+            };
+            let value = write_literal(&field.access);
+            quote!(#keyword #value f.write_str(",")?;)
+        });
+    quote! {
+        #open
+        #(#args)*
+        f.write_str(")")?;
+    }
+}
+
+/// The Python code defining `class_name` for `style`, if any is needed.
+fn class_declarations(style: Style, class_name: &str, names: &[String]) -> Vec<String> {
+    match style {
+        Style::Dict | Style::Enum => Vec::new(),
+        Style::Dataclass => {
+            let mut class = format!("@dataclasses.dataclass\nclass {}:", class_name);
+            for name in names {
+                class.push_str(&format!("\n\t{}: object", name));
+            }
+            if names.is_empty() {
+                class.push_str("\n\tpass");
+            }
+            vec!["import dataclasses".to_owned(), class]
+        }
+        Style::NamedTuple => {
+            let fields: Vec<String> = names.iter().map(|name| format!("'{}'", name)).collect();
+            vec![
+                "import collections".to_owned(),
+                format!(
+                    "{} = collections.namedtuple('{}', [{}], rename=True)",
+                    class_name,
+                    class_name,
+                    fields.join(", ")
+                ),
+            ]
+        }
+        Style::Namespace => vec!["import types".to_owned()],
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let attrs = container_attrs(&input.attrs)?;
+    let ident = &input.ident;
+    let class_name = attrs.rename.clone().unwrap_or_else(|| ident.to_string());
+    let (body, declarations, field_types) = match &input.data {
+        Data::Struct(data) => {
+            if attrs.style == Style::Enum {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    "`#[python(enum)]` only applies to enums",
+                ));
+            }
+            let fields = fields(&data.fields, attrs.rename_all, false)?;
+            if matches!(attrs.style, Style::Dataclass | Style::NamedTuple) {
+                check_identifier(&class_name, ident.span())?;
+            }
+            if attrs.style != Style::Dict {
+                for field in &fields {
+                    if let Some(name) = &field.name {
+                        check_identifier(name, field.span)?;
+                    }
+                }
+            }
+            let body = match (attrs.style, &data.fields) {
+                (Style::Dict, Fields::Named(_)) => write_dict(&fields),
+                (Style::Dict, Fields::Unnamed(_)) => write_tuple(&fields),
+                (Style::Dict, Fields::Unit) => write_str("None"),
+                (Style::Dataclass, Fields::Named(_)) | (Style::NamedTuple, _) => write_call(
+                    &class_name,
+                    &fields,
+                    matches!(data.fields, Fields::Named(_)),
+                ),
+                (Style::Dataclass, _) => write_call(&class_name, &fields, false),
+                (Style::Namespace, _) => write_call("types.SimpleNamespace", &fields, true),
+                (Style::Enum, _) => unreachable!(),
+            };
+            let declarations = class_declarations(attrs.style, &class_name, &python_names(&fields));
+            let types: Vec<syn::Type> = fields.into_iter().map(|field| field.ty).collect();
+            (body, declarations, types)
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            let mut members = Vec::new();
+            let mut types = Vec::new();
+            for variant in &data.variants {
+                let variant_attrs = member_attrs(&variant.attrs)?;
+                if variant_attrs.skip {
+                    return Err(syn::Error::new(
+                        variant.span(),
+                        "enum variants cannot be skipped",
+                    ));
+                }
+                let variant_ident = &variant.ident;
+                let tag = match (variant_attrs.rename, attrs.rename_all) {
+                    (Some(rename), _) => rename,
+                    (None, Some(rule)) => rule.apply(&variant_ident.to_string()),
+                    (None, None) => variant_ident.to_string(),
+                };
+                if attrs.style == Style::Enum {
+                    check_identifier(&tag, variant.span())?;
+                    if !matches!(variant.fields, Fields::Unit) {
+                        return Err(syn::Error::new(
+                            variant.span(),
+                            "`#[python(enum)]` only supports unit variants",
+                        ));
+                    }
+                    let member = write_str(&format!("{}.{}", class_name, tag));
+                    arms.push(quote!(#ident::#variant_ident => { #member }));
+                    members.push(tag);
+                    continue;
+                } else if attrs.style != Style::Dict {
+                    return Err(syn::Error::new(
+                        ident.span(),
+                        "enums only support `#[python(enum)]` and the default string tags",
+                    ));
+                }
+                let variant_fields = fields(&variant.fields, None, true)?;
+                let bindings = variant.fields.iter().enumerate().map(|(i, field)| {
+                    let binding = binding(i);
+                    match &field.ident {
+                        Some(ident) => quote!(#ident: #binding),
+                        None => quote!(#binding),
+                    }
+                });
+                let tag_literal = quote!(::pycall::AsPythonLitteral::fmt(#tag, f)?;);
+                let arm = match &variant.fields {
+                    Fields::Unit => quote!(#ident::#variant_ident => { #tag_literal }),
+                    Fields::Named(_) => {
+                        let payload = write_items(&[(tag_literal, write_dict(&variant_fields))]);
+                        quote! {
+                            #[allow(unused_variables)]
+                            #ident::#variant_ident { #(#bindings),* } => { #payload }
+                        }
+                    }
+                    Fields::Unnamed(_) => {
+                        let payload = write_items(&[(tag_literal, write_tuple(&variant_fields))]);
+                        quote! {
+                            #[allow(unused_variables)]
+                            #ident::#variant_ident ( #(#bindings),* ) => { #payload }
+                        }
+                    }
+                };
+                arms.push(arm);
+                types.extend(variant_fields.into_iter().map(|field| field.ty));
+            }
+            let body = quote! {
+                match self {
+                    #(#arms)*
+                }
+            };
+            let declarations = if attrs.style == Style::Enum {
+                check_identifier(&class_name, ident.span())?;
+                let mut class = format!("class {}(enum.Enum):", class_name);
+                for member in &members {
+                    class.push_str(&format!("\n\t{} = '{}'", member, member));
+                }
+                if members.is_empty() {
+                    class.push_str("\n\tpass");
+                }
+                vec!["import enum".to_owned(), class]
+            } else {
+                Vec::new()
+            };
+            (body, declarations, types)
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new(
+                ident.span(),
+                "unions cannot be derived as python literals",
+            ))
+        }
+    };
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(syn::parse_quote!(::pycall::AsPythonLitteral));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::pycall::AsPythonLitteral for #ident #ty_generics #where_clause {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                #body
+                Ok(())
+            }
+
+            fn declare(declarations: &mut ::pycall::Declarations) {
+                if declarations.first_visit(::std::any::type_name::<Self>()) {
+                    #(<#field_types as ::pycall::AsPythonLitteral>::declare(declarations);)*
+                    #(declarations.declare(#declarations);)*
+                }
+            }
+        }
+    })
+}
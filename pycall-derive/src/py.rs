@@ -0,0 +1,328 @@
+//! `py!`: python templates, checked at compile time, whose placeholders interpolate rust values.
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::{Expr, Ident, LitStr, Token};
+
+/// The arguments of `py!`: a program, a template, then positional and named values.
+pub struct PyInput {
+    program: Expr,
+    template: LitStr,
+    args: Vec<(Option<Ident>, Expr)>,
+}
+
+impl Parse for PyInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let program = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let template = input.parse()?;
+        let mut args = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let name = if input.peek(Ident) && input.peek2(Token![=]) {
+                let name = input.parse()?;
+                input.parse::<Token![=]>()?;
+                Some(name)
+            } else {
+                None
+            };
+            args.push((name, input.parse()?));
+        }
+        Ok(PyInput {
+            program,
+            template,
+            args,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    /// Python source, with escaped braces already unescaped.
+    Source(String),
+    /// A placeholder: `{}` (no name) or `{name}`, written as a literal, or as source if `raw`.
+    Value { name: Option<String>, raw: bool },
+}
+
+/// Splits a template into its python source and its placeholders.
+fn parse_template(template: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut source = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                source.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                source.push('}');
+            }
+            '}' => return Err("unmatched `}` in template: use `}}` for a python brace".to_owned()),
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some('{') | None => {
+                            return Err(
+                                "unmatched `{` in template: use `{{` for a python brace".to_owned()
+                            )
+                        }
+                        Some(c) => placeholder.push(c),
+                    }
+                }
+                let (name, format) = match placeholder.split_once(':') {
+                    Some((name, format)) => (name.trim(), Some(format.trim())),
+                    None => (placeholder.trim(), None),
+                };
+                let raw = match format {
+                    None => false,
+                    Some("raw") => true,
+                    Some(format) => {
+                        return Err(format!(
+                            "unknown interpolation `{}` in `{{{}}}`: expected `raw`",
+                            format, placeholder
+                        ))
+                    }
+                };
+                // Names are rust identifiers, or empty for positional values.
+                let mut chars = name.chars();
+                let valid_name = match chars.next() {
+                    Some('_') => name != "_" && chars.all(unicode_ident::is_xid_continue),
+                    Some(first) => {
+                        unicode_ident::is_xid_start(first)
+                            && chars.all(unicode_ident::is_xid_continue)
+                    }
+                    None => true,
+                };
+                if !valid_name {
+                    return Err(format!(
+                        "invalid placeholder `{{{}}}`: expected `{{}}`, `{{name}}` or `{{name:raw}}`",
+                        placeholder
+                    ));
+                }
+                if !source.is_empty() {
+                    pieces.push(Piece::Source(std::mem::take(&mut source)));
+                }
+                pieces.push(Piece::Value {
+                    name: Some(name.to_owned()).filter(|name| !name.is_empty()),
+                    raw,
+                });
+            }
+            c => source.push(c),
+        }
+    }
+    if !source.is_empty() {
+        pieces.push(Piece::Source(source));
+    }
+    Ok(pieces)
+}
+
+/// Checks that the python source of a template is balanced: brackets are closed, strings terminated,
+/// and placeholders aren't inside strings, where their values would be spliced as source.
+fn check_python(pieces: &[Piece]) -> Result<(), String> {
+    // Stands for placeholders, which are atoms.
+    const PLACEHOLDER: char = '\0';
+    let text: Vec<char> = pieces
+        .iter()
+        .flat_map(|piece| match piece {
+            Piece::Source(source) => source.chars().collect(),
+            Piece::Value { .. } => vec![PLACEHOLDER],
+        })
+        .collect();
+    let mut brackets = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i];
+        i += 1;
+        match c {
+            '#' => {
+                while i < text.len() && text[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '\'' | '"' => {
+                let triple = text.get(i) == Some(&c) && text.get(i + 1) == Some(&c);
+                if triple {
+                    i += 2;
+                }
+                loop {
+                    match text.get(i) {
+                        None => return Err("unterminated python string in template".to_owned()),
+                        Some('\\') => i += 1,
+                        Some(&PLACEHOLDER) => {
+                            return Err("placeholders can't be inside python strings: interpolate the whole string instead".to_owned())
+                        }
+                        Some('\n') if !triple => {
+                            return Err("unterminated python string in template".to_owned())
+                        }
+                        Some(&quote) if quote == c => {
+                            if !triple {
+                                i += 1;
+                                break;
+                            }
+                            if text.get(i + 1) == Some(&c) && text.get(i + 2) == Some(&c) {
+                                i += 3;
+                                break;
+                            }
+                        }
+                        Some(_) => {}
+                    }
+                    i += 1;
+                }
+            }
+            '(' | '[' | '{' => brackets.push(c),
+            ')' | ']' | '}' => {
+                let open = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if brackets.pop() != Some(open) {
+                    return Err(format!("unmatched `{}` in python source", c));
+                }
+            }
+            _ => {}
+        }
+    }
+    match brackets.last() {
+        Some(open) => Err(format!("unclosed `{}` in python source", open)),
+        None => Ok(()),
+    }
+}
+
+pub fn expand(input: PyInput) -> syn::Result<TokenStream> {
+    let span = input.template.span();
+    let error = |message: String| syn::Error::new(span, message);
+    let pieces = parse_template(&input.template.value()).map_err(error)?;
+    check_python(&pieces).map_err(error)?;
+
+    let mut bindings = Vec::new();
+    let mut positional = Vec::new();
+    let mut named: Vec<(&Ident, Ident, bool)> = Vec::new();
+    for (i, (name, value)) in input.args.iter().enumerate() {
+        let binding = format_ident!("__pycall_arg{}", i, span = Span::mixed_site());
+        bindings.push(quote!(let #binding = &(#value);));
+        match name {
+            Some(name) => {
+                if named.iter().any(|(other, _, _)| *other == name) {
+                    return Err(syn::Error::new(name.span(), "duplicate argument in `py!`"));
+                }
+                named.push((name, binding, false))
+            }
+            None => positional.push(binding),
+        }
+    }
+
+    let snippet = Ident::new("__pycall_snippet", Span::mixed_site());
+    let mut positional = positional.into_iter();
+    let mut writes = Vec::new();
+    for piece in &pieces {
+        let (name, raw) = match piece {
+            Piece::Source(source) => {
+                writes.push(quote!(#snippet.source(#source);));
+                continue;
+            }
+            Piece::Value { name, raw } => (name, *raw),
+        };
+        let value = match name {
+            None => {
+                let binding = positional.next().ok_or_else(|| {
+                    error("more `{}` placeholders than positional arguments".to_owned())
+                })?;
+                quote!(#binding)
+            }
+            Some(name) => match named.iter_mut().find(|(other, _, _)| **other == *name) {
+                Some((_, binding, used)) => {
+                    *used = true;
+                    quote!(#binding)
+                }
+                // Captured from the scope of the macro call, like `format!` does.
+                None => {
+                    let name = Ident::new(name, span);
+                    quote!(&#name)
+                }
+            },
+        };
+        writes.push(if raw {
+            quote!(#snippet.raw(#value);)
+        } else {
+            quote!(#snippet.literal(#value);)
+        });
+    }
+    if positional.next().is_some() {
+        return Err(error(
+            "more positional arguments than `{}` placeholders".to_owned(),
+        ));
+    }
+    if let Some((name, _, _)) = named.iter().find(|(_, _, used)| !used) {
+        return Err(syn::Error::new(
+            name.span(),
+            "named argument never used in the template",
+        ));
+    }
+
+    let program = &input.program;
+    Ok(quote! {{
+        #(#bindings)*
+        let mut #snippet = (#program).snippet();
+        #(#writes)*
+        #snippet.write()
+    }})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(template: &str) -> Result<Vec<Piece>, String> {
+        let pieces = parse_template(template)?;
+        check_python(&pieces)?;
+        Ok(pieces)
+    }
+
+    #[test]
+    fn templates() {
+        assert_eq!(
+            check("plt.plot({x}, {}, label={name:raw}) # {{}}").unwrap(),
+            vec![
+                Piece::Source("plt.plot(".to_owned()),
+                Piece::Value {
+                    name: Some("x".to_owned()),
+                    raw: false
+                },
+                Piece::Source(", ".to_owned()),
+                Piece::Value {
+                    name: None,
+                    raw: false
+                },
+                Piece::Source(", label=".to_owned()),
+                Piece::Value {
+                    name: Some("name".to_owned()),
+                    raw: true
+                },
+                Piece::Source(") # {}".to_owned()),
+            ]
+        );
+        assert!(check("d = {{'a': [1, (2, 3)]}}").is_ok());
+        assert!(check("s = '''it's\n)'''\nt = \"\\\"(\" + f'{{x}}'").is_ok());
+
+        let error = |template| check(template).unwrap_err();
+        assert!(error("f({x)").contains("unmatched `{`"));
+        assert!(error("f(x})").contains("unmatched `}`"));
+        assert!(error("f({x:repr})").contains("unknown interpolation `repr`"));
+        assert!(error("f({x.y})").contains("invalid placeholder"));
+        assert!(error("f({x²})").contains("invalid placeholder"));
+        assert!(error("f({1x})").contains("invalid placeholder"));
+        assert!(error("f({_})").contains("invalid placeholder"));
+        assert!(error("f({x}").contains("unclosed `(`"));
+        assert!(error("f[{x})").contains("unmatched `)`"));
+        assert!(error("print('{x}')").contains("inside python strings"));
+        assert!(error("print('oops)").contains("unterminated"));
+    }
+}
@@ -0,0 +1,394 @@
+//! Scoped blocks: closures and guards that close the blocks they open, so that no `end_block` can be forgotten.
+use crate::{AsPythonSource, PycallError, PythonProgram};
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+
+/// The statement that opened a block, as far as the `elif` and `else` that may continue it care.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BlockKind {
+    If,
+    Elif,
+    Else,
+    For,
+    While,
+    Except,
+    Other,
+}
+
+impl BlockKind {
+    /// Whether a block opened by `self` may directly follow one opened by `previous`.
+    fn may_follow(self, previous: BlockKind) -> bool {
+        use BlockKind::*;
+        match self {
+            Elif => matches!(previous, If | Elif),
+            Else => matches!(previous, If | Elif | For | While | Except),
+            _ => true,
+        }
+    }
+}
+
+/// A block opened by a control-flow statement, such as `if` or `for`.
+#[derive(Clone, Debug)]
+pub(crate) struct Block {
+    pub(crate) kind: BlockKind,
+    /// The indentation level of the statement that opened the block.
+    pub(crate) indents: isize,
+    /// How many statements the block holds so far.
+    pub(crate) statements: usize,
+    pub(crate) opened_by: &'static Location<'static>,
+}
+
+/// Dedents the block it was created for when dropped, closing any block left open inside it on the way.
+/// Dereferences to the program, so that the block's body can be written through it.
+pub struct BlockGuard<'p> {
+    program: &'p mut PythonProgram,
+    depth: usize,
+}
+
+impl<'p> Deref for BlockGuard<'p> {
+    type Target = PythonProgram;
+    fn deref(&self) -> &PythonProgram {
+        self.program
+    }
+}
+
+impl<'p> DerefMut for BlockGuard<'p> {
+    fn deref_mut(&mut self) -> &mut PythonProgram {
+        self.program
+    }
+}
+
+impl<'p> Drop for BlockGuard<'p> {
+    fn drop(&mut self) {
+        self.program.close_blocks(self.depth);
+    }
+}
+
+impl PythonProgram {
+    /// Writes `header` at the current indentation, and opens a block of `kind` under it.
+    #[track_caller]
+    pub(crate) fn open_block(&mut self, kind: BlockKind, header: &str) -> &mut Self {
+        self.write_line(header);
+        self.blocks.push(Block {
+            kind,
+            indents: self.indents,
+            statements: 0,
+            opened_by: Location::caller(),
+        });
+        self.indents += 1;
+        self
+    }
+
+    /// Closes the innermost block, filling it with `pass` if it is empty, and lets an `elif` or `else` follow it.
+    /// Returns `false` if no block was open.
+    pub(crate) fn close_block(&mut self) -> bool {
+        let block = match self.blocks.pop() {
+            Some(block) => block,
+            None => return false,
+        };
+        if block.statements == 0 {
+            let pass = format!("{}pass\n", self.indentation());
+            self.write_raw(&pass, Some(block.opened_by));
+        }
+        self.indents = block.indents;
+        self.closed = Some((block.indents, block.kind));
+        true
+    }
+
+    /// Fails the program with `PycallError::MisplacedBlock` unless a block of `kind` may be opened here,
+    /// right after the block closed last. Must be called before anything else is written.
+    #[track_caller]
+    pub(crate) fn check_follows(&mut self, kind: BlockKind) {
+        let follows = match self.closed {
+            Some((indents, previous)) => indents == self.indents && kind.may_follow(previous),
+            None => false,
+        };
+        if !follows && self.error.is_none() {
+            self.error = Some(PycallError::MisplacedBlock(Location::caller()));
+        }
+    }
+
+    /// Closes blocks until only `depth` of them remain open.
+    pub(crate) fn close_blocks(&mut self, depth: usize) {
+        while self.blocks.len() > depth && self.close_block() {}
+    }
+
+    /// Fails with the error that interrupted the program, or with `PycallError::UnclosedBlock` if a block is still open
+    /// or `indent` left the indentation unbalanced.
+    pub(crate) fn check_blocks(&self) -> Result<(), PycallError> {
+        self.script()?;
+        let balanced = self.blocks.last().map_or(0, |block| block.indents + 1);
+        match (self.indented_by, self.blocks.last()) {
+            (Some(location), _) if self.indents != balanced => {
+                Err(PycallError::UnclosedBlock(location))
+            }
+            (_, Some(block)) => Err(PycallError::UnclosedBlock(block.opened_by)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens a block of `kind` with `header`, writes its body with `body`, and closes it.
+    #[track_caller]
+    fn scoped<F: FnOnce(&mut Self)>(
+        &mut self,
+        kind: BlockKind,
+        header: &str,
+        body: F,
+    ) -> &mut Self {
+        let depth = self.blocks.len();
+        self.open_block(kind, header);
+        body(self);
+        self.close_blocks(depth);
+        self
+    }
+
+    /// Opens a block of `kind` with `header`, closed when the returned guard is dropped.
+    #[track_caller]
+    fn guarded(&mut self, kind: BlockKind, header: &str) -> BlockGuard<'_> {
+        let depth = self.blocks.len();
+        self.open_block(kind, header);
+        BlockGuard {
+            program: self,
+            depth,
+        }
+    }
+
+    /// Writes an if, using your condition as a test, with the body written by `body`.
+    #[track_caller]
+    pub fn if_<C: AsPythonSource + ?Sized, F: FnOnce(&mut Self)>(
+        &mut self,
+        condition: &C,
+        body: F,
+    ) -> &mut Self {
+        let condition = self.source(condition);
+        self.scoped(BlockKind::If, &format!("if {}:", condition), body)
+    }
+
+    /// Writes an elif, using your condition as a test, with the body written by `body`.
+    /// Must directly follow an `if_` or another `elif_`, or the program fails with `PycallError::MisplacedBlock`.
+    #[track_caller]
+    pub fn elif_<C: AsPythonSource + ?Sized, F: FnOnce(&mut Self)>(
+        &mut self,
+        condition: &C,
+        body: F,
+    ) -> &mut Self {
+        self.check_follows(BlockKind::Elif);
+        let condition = self.source(condition);
+        self.scoped(BlockKind::Elif, &format!("elif {}:", condition), body)
+    }
+
+    /// Writes an else, with the body written by `body`.
+    /// Must directly follow an `if_`, `elif_`, `for_` or `while_`, or the program fails with `PycallError::MisplacedBlock`.
+    #[track_caller]
+    pub fn else_<F: FnOnce(&mut Self)>(&mut self, body: F) -> &mut Self {
+        self.check_follows(BlockKind::Else);
+        self.scoped(BlockKind::Else, "else:", body)
+    }
+
+    /// Writes "for `range`:", with the body written by `body`.
+    #[track_caller]
+    pub fn for_<C: AsPythonSource + ?Sized, F: FnOnce(&mut Self)>(
+        &mut self,
+        range: &C,
+        body: F,
+    ) -> &mut Self {
+        let range = self.source(range);
+        self.scoped(BlockKind::For, &format!("for {}:", range), body)
+    }
+
+    /// Writes a while, using your condition as a test, with the body written by `body`.
+    #[track_caller]
+    pub fn while_<C: AsPythonSource + ?Sized, F: FnOnce(&mut Self)>(
+        &mut self,
+        condition: &C,
+        body: F,
+    ) -> &mut Self {
+        let condition = self.source(condition);
+        self.scoped(BlockKind::While, &format!("while {}:", condition), body)
+    }
+
+    /// Writes an if, using your condition as a test. The block ends when the returned guard is dropped.
+    #[track_caller]
+    pub fn if_block<C: AsPythonSource + ?Sized>(&mut self, condition: &C) -> BlockGuard<'_> {
+        let condition = self.source(condition);
+        self.guarded(BlockKind::If, &format!("if {}:", condition))
+    }
+
+    /// Writes an elif, using your condition as a test. The block ends when the returned guard is dropped.
+    /// Must directly follow the block of an `if_block` or another `elif_block`, like `elif_`.
+    #[track_caller]
+    pub fn elif_block<C: AsPythonSource + ?Sized>(&mut self, condition: &C) -> BlockGuard<'_> {
+        self.check_follows(BlockKind::Elif);
+        let condition = self.source(condition);
+        self.guarded(BlockKind::Elif, &format!("elif {}:", condition))
+    }
+
+    /// Writes an else. The block ends when the returned guard is dropped.
+    /// Must directly follow the block of an `if_block`, `elif_block`, `for_block` or `while_block`, like `else_`.
+    #[track_caller]
+    pub fn else_block(&mut self) -> BlockGuard<'_> {
+        self.check_follows(BlockKind::Else);
+        self.guarded(BlockKind::Else, "else:")
+    }
+
+    /// Writes "for `range`:". The block ends when the returned guard is dropped.
+    #[track_caller]
+    pub fn for_block<C: AsPythonSource + ?Sized>(&mut self, range: &C) -> BlockGuard<'_> {
+        let range = self.source(range);
+        self.guarded(BlockKind::For, &format!("for {}:", range))
+    }
+
+    /// Writes a while, using your condition as a test. The block ends when the returned guard is dropped.
+    #[track_caller]
+    pub fn while_block<C: AsPythonSource + ?Sized>(&mut self, condition: &C) -> BlockGuard<'_> {
+        let condition = self.source(condition);
+        self.guarded(BlockKind::While, &format!("while {}:", condition))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PycallError, PythonFragment, PythonProgram};
+
+    fn stdout(program: &PythonProgram) -> String {
+        let output = program.run_checked().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn closures() {
+        let mut program = PythonProgram::new();
+        program
+            .write_line("total = 0")
+            .for_("i in range(6)", |p| {
+                p.if_("i % 3 == 0", |p| {
+                    p.write_line("total += 100");
+                })
+                .elif_("i % 3 == 1", |p| {
+                    p.write_line("total += 10");
+                })
+                .else_(|p| {
+                    p.while_("False", |_| {}).write_line("total += 1");
+                });
+            })
+            .write_line("print(total)");
+        assert_eq!(stdout(&program), "222\n");
+        assert!(program
+            .to_string()
+            .contains("\t\twhile False:\n\t\t\tpass\n"));
+    }
+
+    #[test]
+    fn guards() {
+        let mut program = PythonProgram::new();
+        {
+            let mut block = program.for_block("i in range(3)");
+            {
+                let mut block = block.if_block("i == 1");
+                block.write_line("print('one')");
+                // Left open, but closed along with its guard's block.
+                block.r#if("True").write_line("print('still one')");
+            }
+            block.else_block().write_line("# empty blocks get a `pass`");
+        }
+        program.write_line("print('done')");
+        assert_eq!(stdout(&program), "one\nstill one\ndone\n");
+
+        let source = program.to_string();
+        assert!(source.contains("\telse:\n\t\t# empty blocks get a `pass`\n\t\tpass\n"));
+        assert!(source.ends_with("\nprint('done')\n"));
+    }
+
+    #[test]
+    fn builders_close_blocks() {
+        let mut program = PythonProgram::new();
+        program
+            .r#if("False")
+            .elif("False")
+            .write_line("print('no')")
+            .r#else()
+            .r#for("_ in range(2)")
+            .write_line("print('twice')")
+            .end_block()
+            .end_block()
+            .write_line("print('after')");
+        assert_eq!(stdout(&program), "twice\ntwice\nafter\n");
+        assert!(program
+            .to_string()
+            .starts_with("if False:\n\tpass\nelif False:"));
+    }
+
+    #[test]
+    fn unclosed_blocks() {
+        let mut program = PythonProgram::new();
+        let line = line!() + 1;
+        program.r#while("True");
+        assert_eq!(
+            program.to_string(),
+            format!(
+                "while True:\n# error: the block opened at {} was never closed\n",
+                program.blocks[0].opened_by
+            )
+        );
+        match program.run() {
+            Err(PycallError::UnclosedBlock(location)) => assert_eq!(location.line(), line),
+            other => panic!("{:?}", other),
+        }
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            program.save_as(dir.path().join("unclosed.py")),
+            Err(PycallError::UnclosedBlock(_))
+        ));
+
+        // So does indentation left by `indent`.
+        let mut program = PythonProgram::new();
+        let line = line!() + 1;
+        program.indent(2).write_line("x = 1").indent(-1);
+        match program.evaluate::<i64>("x") {
+            Err(PycallError::UnclosedBlock(location)) => assert_eq!(location.line(), line),
+            other => panic!("{:?}", other),
+        }
+
+        // Fragments with open blocks fail the programs they're appended to.
+        let mut fragment = PythonFragment::new();
+        fragment.r#if("True");
+        let mut program = PythonProgram::new();
+        program.append(&fragment);
+        assert!(matches!(program.run(), Err(PycallError::UnclosedBlock(_))));
+    }
+
+    #[test]
+    fn misplaced_blocks() {
+        let misplaced = |write: fn(&mut PythonProgram)| {
+            let mut program = PythonProgram::new();
+            write(&mut program);
+            matches!(program.run(), Err(PycallError::MisplacedBlock(_)))
+        };
+        assert!(misplaced(|p| {
+            p.elif_("True", |_| {});
+        }));
+        assert!(misplaced(|p| {
+            p.for_("i in []", |_| {}).elif_("True", |_| {});
+        }));
+        assert!(misplaced(|p| {
+            p.if_("True", |_| {}).write_line("x = 1").else_(|_| {});
+        }));
+        assert!(misplaced(|p| {
+            p.if_("True", |p| {
+                p.write_line("x = 1");
+            });
+            p.r#if("True").else_block();
+        }));
+        assert!(misplaced(|p| {
+            p.r#try().r#else().end_block();
+        }));
+        assert!(misplaced(|p| {
+            p.r#for("i in []").elif("True").end_block();
+        }));
+        // Loops and exception handlers take an else.
+        assert!(!misplaced(|p| {
+            p.while_("False", |_| {}).else_(|_| {});
+            p.r#try().except(None, None).r#else().end_block();
+        }));
+    }
+}
@@ -0,0 +1,339 @@
+//! Side files carrying large values to a script, so that they don't have to be parsed as source code.
+//! Values are stored in python's `marshal` format, which loads much faster, straight from the types that support it
+//! (see `AsPythonLitteral::marshal`), or from their literals if these parse as plain ones.
+//! Others (instances of declared classes, say) are stored as source and evaluated.
+use crate::{AsPythonLitteral, PyValue};
+use std::convert::TryFrom;
+use std::io::Write;
+
+/// Loads a value stored by `write_marshal`.
+pub(crate) const MARSHAL_LOADER: &str = "def __pycall_load(path):
+\timport marshal
+\twith open(path, 'rb') as data:
+\t\treturn marshal.load(data)";
+
+/// Evaluates a value stored as source.
+pub(crate) const SOURCE_LOADER: &str = "def __pycall_eval(path):
+\twith open(path, encoding='utf-8') as source:
+\t\treturn eval(source.read())";
+
+/// Writes `value` in the format read by python's `marshal.load`.
+pub(crate) fn write_marshal<W: Write + ?Sized>(
+    out: &mut W,
+    value: &PyValue,
+) -> std::io::Result<()> {
+    match value {
+        PyValue::None => out.write_all(b"N"),
+        PyValue::Bool(b) => write_bool(out, *b),
+        PyValue::Int(i) => write_int(out, *i),
+        PyValue::BigInt(decimal) => {
+            let (negative, decimal) = match decimal.strip_prefix('-') {
+                Some(decimal) => (true, decimal),
+                None => (false, decimal.as_str()),
+            };
+            write_long(out, negative, &parse_digits(decimal)?)
+        }
+        PyValue::Float(x) => write_float(out, *x),
+        PyValue::Str(s) => write_str(out, s),
+        PyValue::Bytes(bytes) => {
+            out.write_all(b"s")?;
+            write_len(out, bytes.len())?;
+            out.write_all(bytes)
+        }
+        PyValue::Tuple(items) => write_items(out, b"(", items),
+        PyValue::List(items) => write_items(out, b"[", items),
+        PyValue::Set(items) => write_items(out, b"<", items),
+        PyValue::FrozenSet(items) => write_items(out, b">", items),
+        PyValue::Dict(items) => {
+            out.write_all(b"{")?;
+            for (key, value) in items {
+                write_marshal(out, key)?;
+                write_marshal(out, value)?;
+            }
+            out.write_all(b"0")
+        }
+    }
+}
+
+pub(crate) fn write_bool<W: Write + ?Sized>(out: &mut W, b: bool) -> std::io::Result<()> {
+    out.write_all(if b { b"T" } else { b"F" })
+}
+
+pub(crate) fn write_int<W: Write + ?Sized>(out: &mut W, i: i128) -> std::io::Result<()> {
+    match i32::try_from(i) {
+        Ok(i) => {
+            out.write_all(b"i")?;
+            out.write_all(&i.to_le_bytes())
+        }
+        Err(_) => write_long(out, i < 0, &digits_of(i.unsigned_abs())),
+    }
+}
+
+pub(crate) fn write_uint<W: Write + ?Sized>(out: &mut W, u: u128) -> std::io::Result<()> {
+    match i128::try_from(u) {
+        Ok(i) => write_int(out, i),
+        Err(_) => write_long(out, false, &digits_of(u)),
+    }
+}
+
+pub(crate) fn write_float<W: Write + ?Sized>(out: &mut W, x: f64) -> std::io::Result<()> {
+    out.write_all(b"g")?;
+    out.write_all(&x.to_le_bytes())
+}
+
+pub(crate) fn write_str<W: Write + ?Sized>(out: &mut W, s: &str) -> std::io::Result<()> {
+    out.write_all(b"u")?;
+    write_len(out, s.len())?;
+    out.write_all(s.as_bytes())
+}
+
+/// Writes the start of a list, or of a tuple if `hashable`, of `len` items.
+pub(crate) fn write_sequence_header<W: Write + ?Sized>(
+    out: &mut W,
+    hashable: bool,
+    len: usize,
+) -> std::io::Result<()> {
+    out.write_all(if hashable { b"(" } else { b"[" })?;
+    write_len(out, len)
+}
+
+/// Marshals `items` as a list, or as a tuple if `hashable`, returning `false` if one of them can't be.
+pub(crate) fn marshal_sequence<T: AsPythonLitteral, I: ExactSizeIterator<Item = T>>(
+    out: &mut dyn Write,
+    items: I,
+    hashable: bool,
+) -> std::io::Result<bool> {
+    write_sequence_header(out, hashable, items.len())?;
+    for x in items {
+        if !x.marshal(out, hashable)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Marshals `items` as a set, or as a frozenset if `hashable`, returning `false` if one of them can't be.
+pub(crate) fn marshal_set<T: AsPythonLitteral, I: ExactSizeIterator<Item = T>>(
+    out: &mut dyn Write,
+    items: I,
+    hashable: bool,
+) -> std::io::Result<bool> {
+    out.write_all(if hashable { b">" } else { b"<" })?;
+    write_len(out, items.len())?;
+    for x in items {
+        if !x.marshal(out, true)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Marshals `items` as a dict, returning `false` if one of them can't be.
+pub(crate) fn marshal_dict<K: AsPythonLitteral, V: AsPythonLitteral, I: Iterator<Item = (K, V)>>(
+    out: &mut dyn Write,
+    items: I,
+) -> std::io::Result<bool> {
+    out.write_all(b"{")?;
+    for (key, value) in items {
+        if !key.marshal(out, true)? || !value.marshal(out, false)? {
+            return Ok(false);
+        }
+    }
+    out.write_all(b"0")?;
+    Ok(true)
+}
+
+fn write_len<W: Write + ?Sized>(out: &mut W, len: usize) -> std::io::Result<()> {
+    let len = i32::try_from(len).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "value too large for marshal",
+        )
+    })?;
+    out.write_all(&len.to_le_bytes())
+}
+
+fn write_items<W: Write + ?Sized>(
+    out: &mut W,
+    code: &[u8],
+    items: &[PyValue],
+) -> std::io::Result<()> {
+    out.write_all(code)?;
+    write_len(out, items.len())?;
+    items.iter().try_for_each(|item| write_marshal(out, item))
+}
+
+/// Writes an int from its base 2^15 digits, least significant first.
+fn write_long<W: Write + ?Sized>(
+    out: &mut W,
+    negative: bool,
+    digits: &[u16],
+) -> std::io::Result<()> {
+    out.write_all(b"l")?;
+    let len = i32::try_from(digits.len()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "int too large for marshal")
+    })?;
+    out.write_all(&(if negative { -len } else { len }).to_le_bytes())?;
+    digits
+        .iter()
+        .try_for_each(|digit| out.write_all(&digit.to_le_bytes()))
+}
+
+fn digits_of(mut magnitude: u128) -> Vec<u16> {
+    let mut digits = Vec::new();
+    while magnitude != 0 {
+        digits.push((magnitude & 0x7fff) as u16);
+        magnitude >>= 15;
+    }
+    digits
+}
+
+/// Converts a decimal magnitude to base 2^15 digits, least significant first.
+fn parse_digits(decimal: &str) -> std::io::Result<Vec<u16>> {
+    let mut digits: Vec<u16> = Vec::new();
+    for c in decimal.chars() {
+        let mut carry = c.to_digit(10).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid decimal int")
+        })?;
+        for digit in &mut digits {
+            let value = u32::from(*digit) * 10 + carry;
+            *digit = (value & 0x7fff) as u16;
+            carry = value >> 15;
+        }
+        while carry != 0 {
+            digits.push((carry & 0x7fff) as u16);
+            carry >>= 15;
+        }
+    }
+    Ok(digits)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Interpreter, PyValue, PythonProgram};
+
+    #[test]
+    fn side_files() {
+        let big = "-123456789012345678901234567890123456789012345678901234567890";
+        let value = PyValue::List(vec![
+            PyValue::None,
+            PyValue::Bool(true),
+            PyValue::Int(-7),
+            PyValue::Int(1 << 40),
+            PyValue::Int(i128::MIN + 1),
+            PyValue::BigInt(big.to_owned()),
+            PyValue::Float(-0.5),
+            PyValue::Str("é🐍\n".to_owned()),
+            PyValue::Bytes(vec![0, 255]),
+            PyValue::Tuple(vec![]),
+            PyValue::Dict(vec![(PyValue::Str("k".to_owned()), PyValue::Set(vec![]))]),
+            PyValue::FrozenSet(vec![PyValue::Int(1)]),
+        ]);
+        let series: Vec<f64> = (0..10_000).map(|i| i as f64 / 3.).collect();
+        let mut program = PythonProgram::new();
+        program
+            .data_threshold(Some(1000))
+            .define_variable("small", &vec![1, 2]);
+        program.define_variable("series", &series);
+        program.define_data("value", &value);
+        program.define_data("nan", &f64::NAN);
+        let source = program.to_string();
+        assert!(source.contains("small = [1,2,]"));
+        assert!(source.contains("series = __pycall_load("));
+        assert!(source.contains("value = __pycall_load("));
+        assert!(source.len() < 2000, "{}", source);
+        assert_eq!(program.data_files.len(), 3);
+
+        assert_eq!(program.evaluate::<Vec<f64>>("series").unwrap(), series);
+        assert_eq!(program.evaluate::<PyValue>("value").unwrap(), value);
+        assert_eq!(program.evaluate::<String>("str(nan)").unwrap(), "nan");
+        assert_eq!(program.evaluate::<String>("repr(value[5])").unwrap(), big);
+
+        // Side files live as long as the program, or the scripts it spawned.
+        let paths: Vec<_> = program
+            .data_files
+            .iter()
+            .map(|file| file.to_path_buf())
+            .collect();
+        let running = program.spawn().unwrap();
+        drop(program);
+        assert!(paths.iter().all(|path| path.exists()));
+        assert!(running.wait_with_output().unwrap().status.success());
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn marshalled_values() {
+        use std::collections::{BTreeMap, BTreeSet};
+
+        // Values are marshalled straight from rust, without a literal to parse.
+        let mut nested = BTreeMap::new();
+        nested.insert((1, "a".to_owned()), BTreeSet::from([vec![15], vec![]]));
+        nested.insert((2, "b".to_owned()), BTreeSet::new());
+        let mut program = PythonProgram::new();
+        program.define_data("nested", &nested);
+        program.define_data("huge", &u128::MAX);
+        program.define_data("text", &Some('é'));
+        let source = program.to_string();
+        assert!(!source.contains("__pycall_eval"), "{}", source);
+        assert_eq!(program.data_files.len(), 3);
+        assert_eq!(
+            program
+                .evaluate::<String>("repr(sorted((k, sorted(v)) for k, v in nested.items()))")
+                .unwrap(),
+            "[((1, 'a'), [(), (15,)]), ((2, 'b'), [])]"
+        );
+        assert_eq!(program.evaluate::<u128>("huge").unwrap(), u128::MAX);
+        assert_eq!(program.evaluate::<String>("text").unwrap(), "é");
+    }
+
+    #[test]
+    fn saved_scripts() {
+        // Saved scripts inline their values by default, so that they outlive the program.
+        let series: Vec<f64> = (0..400_000).map(|i| i as f64 / 7.).collect();
+        let mut program = PythonProgram::new();
+        program.define_variable("series", &series);
+        program.write_line("print(len(series), series[-1] == 399999 / 7)");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("saved.py");
+        program.save_as(&path).unwrap();
+        assert!(program.data_files.is_empty());
+        drop(program);
+        let output = Interpreter::discover().run(&path).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "400000 True\n");
+    }
+
+    #[test]
+    fn loaders_in_blocks() {
+        // Loaders first needed in a block are still defined at the top level.
+        let mut program = PythonProgram::new();
+        program
+            .data_threshold(Some(0))
+            .r#if("False")
+            .define_variable("a", &vec![1]);
+        program.end_block().define_variable("b", &vec![2]);
+        program.write_line("print(b)");
+        assert!(program.to_string().starts_with("def __pycall_load(path):"));
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "[2]\n");
+    }
+
+    #[test]
+    fn side_files_with_declarations() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: i32,
+        }
+        let mut program = PythonProgram::new();
+        program
+            .data_threshold(Some(0))
+            .define_variable("points", &vec![Point { x: 1 }, Point { x: 2 }]);
+        program.write_line("print(sum(point.x for point in points))");
+        assert!(program.to_string().contains("points = __pycall_eval("));
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{LiteralError, PythonException};
+use std::fmt::{Display, Error, Formatter};
+use std::panic::Location;
+
+/// Everything that can go wrong while generating or running a python program.
+#[derive(Debug)]
+pub enum PycallError {
+    /// The temporary file holding a script couldn't be created.
+    TempFile(std::io::Error),
+    /// Writing a script (or a copy of it) failed.
+    Write(std::io::Error),
+    /// The python interpreter couldn't be found: other failures to start it are `Io` errors.
+    InterpreterNotFound(std::io::Error),
+    /// Any other I/O failure, such as reading results back from python.
+    Io(std::io::Error),
+    /// The script exited with an error status, or before it could report a result.
+    NonZeroExit(std::process::Output),
+    /// The script was interrupted by an uncaught exception.
+    PythonException {
+        exception: Box<PythonException>,
+        output: std::process::Output,
+    },
+    /// The script ran, but the expression passed to `evaluate` raised an exception.
+    ExpressionFailed {
+        exception: Box<PythonException>,
+        output: std::process::Output,
+    },
+    /// The script didn't finish in time.
+    Timeout,
+    /// A value printed by python couldn't be decoded into the requested type.
+    Decode(LiteralError),
+    /// The program was dedented past its first column, by the call at this location.
+    Dedent(&'static Location<'static>),
+    /// A name given to the program can't be bound, being a keyword or already imported, say.
+    InvalidName {
+        name: String,
+        reason: &'static str,
+        location: &'static Location<'static>,
+    },
+    /// The block opened by the call at this location was never closed, or `indent` was never undone.
+    UnclosedBlock(&'static Location<'static>),
+    /// The `elif` or `else` written by the call at this location doesn't follow a block it can continue.
+    MisplacedBlock(&'static Location<'static>),
+    /// The file included by the call at `location` couldn't be read.
+    Include {
+        path: std::path::PathBuf,
+        error: std::io::Error,
+        location: &'static Location<'static>,
+    },
+}
+
+impl PycallError {
+    /// Builds the error for a finished script, if it failed.
+    pub(crate) fn check_output(
+        output: std::process::Output,
+    ) -> Result<std::process::Output, PycallError> {
+        if output.status.success() {
+            return Ok(output);
+        }
+        match PythonException::parse(&String::from_utf8_lossy(&output.stderr)) {
+            Some(exception) => Err(PycallError::PythonException {
+                exception: Box::new(exception),
+                output,
+            }),
+            None => Err(PycallError::NonZeroExit(output)),
+        }
+    }
+
+    /// A copy of an error deferred by a program's writers, which only hold I/O, indentation, block, naming and include errors.
+    pub(crate) fn duplicate(&self) -> PycallError {
+        let copy = |e: &std::io::Error| std::io::Error::new(e.kind(), e.to_string());
+        match self {
+            PycallError::TempFile(e) => PycallError::TempFile(copy(e)),
+            PycallError::Write(e) => PycallError::Write(copy(e)),
+            PycallError::Dedent(location) => PycallError::Dedent(location),
+            PycallError::UnclosedBlock(location) => PycallError::UnclosedBlock(location),
+            PycallError::MisplacedBlock(location) => PycallError::MisplacedBlock(location),
+            PycallError::InvalidName {
+                name,
+                reason,
+                location,
+            } => PycallError::InvalidName {
+                name: name.clone(),
+                reason,
+                location,
+            },
+            PycallError::Include {
+                path,
+                error,
+                location,
+            } => PycallError::Include {
+                path: path.clone(),
+                error: copy(error),
+                location,
+            },
+            other => PycallError::Io(std::io::Error::other(other.to_string())),
+        }
+    }
+}
+
+impl Display for PycallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            PycallError::TempFile(e) => write!(f, "failed to create temporary file: {}", e),
+            PycallError::Write(e) => write!(f, "failed to write python program: {}", e),
+            PycallError::InterpreterNotFound(e) => write!(f, "failed to start python: {}", e),
+            PycallError::Io(e) => write!(f, "{}", e),
+            PycallError::NonZeroExit(output) => write!(
+                f,
+                "python exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            PycallError::PythonException { exception, .. } => {
+                write!(f, "python raised an exception:\n{}", exception)
+            }
+            PycallError::ExpressionFailed { exception, .. } => {
+                write!(f, "expression failed:\n{}", exception)
+            }
+            PycallError::Timeout => write!(f, "python timed out"),
+            PycallError::Decode(e) => write!(f, "failed to decode python value: {}", e),
+            PycallError::Dedent(location) => {
+                write!(f, "dedented past the first column at {}", location)
+            }
+            PycallError::UnclosedBlock(location) => {
+                write!(f, "the block opened at {} was never closed", location)
+            }
+            PycallError::MisplacedBlock(location) => write!(
+                f,
+                "the block opened at {} doesn't follow a block it can continue",
+                location
+            ),
+            PycallError::InvalidName {
+                name,
+                reason,
+                location,
+            } => write!(f, "invalid name `{}` at {}: {}", name, location, reason),
+            PycallError::Include {
+                path,
+                error,
+                location,
+            } => write!(
+                f,
+                "failed to include {} at {}: {}",
+                path.display(),
+                location,
+                error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PycallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PycallError::TempFile(e)
+            | PycallError::Write(e)
+            | PycallError::InterpreterNotFound(e)
+            | PycallError::Io(e)
+            | PycallError::Include { error: e, .. } => Some(e),
+            PycallError::PythonException { exception, .. }
+            | PycallError::ExpressionFailed { exception, .. } => Some(&**exception),
+            PycallError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<LiteralError> for PycallError {
+    fn from(e: LiteralError) -> Self {
+        PycallError::Decode(e)
+    }
+}
@@ -0,0 +1,724 @@
+//! Python expressions as a typed tree, rendered to source with the parentheses their precedence requires.
+use crate::{AsPythonLitteral, Declarations, Parameters, PythonLiteral};
+use std::borrow::Cow;
+use std::fmt::{Display, Error, Formatter};
+
+/// Python source accepted by the builders of `PythonProgram`: raw source (`&str` or `String`), or an `Expr`.
+pub trait AsPythonSource {
+    /// The source itself.
+    fn source(&self) -> Cow<'_, str>;
+
+    /// Registers the definitions the source depends on, such as the classes of the literals it holds.
+    fn declare(&self, _declarations: &mut Declarations) {}
+}
+
+impl AsPythonSource for str {
+    fn source(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl AsPythonSource for String {
+    fn source(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<T: AsPythonSource + ?Sized> AsPythonSource for &T {
+    fn source(&self) -> Cow<'_, str> {
+        (**self).source()
+    }
+
+    fn declare(&self, declarations: &mut Declarations) {
+        (**self).declare(declarations)
+    }
+}
+
+impl AsPythonSource for Expr {
+    fn source(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.source)
+    }
+
+    fn declare(&self, declarations: &mut Declarations) {
+        for code in &self.declarations {
+            declarations.declare(code);
+        }
+    }
+}
+
+/// How tightly an expression binds, from loosest to tightest, as in python's grammar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Precedence {
+    /// Only valid as a subscript.
+    Slice,
+    Lambda,
+    Conditional,
+    Or,
+    And,
+    Not,
+    Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    Sum,
+    Product,
+    Unary,
+    Power,
+    Await,
+    Atom,
+}
+
+/// A python expression.
+///
+/// Expressions are built from names, literals and raw source, then combined with methods (and operators,
+/// such as `+` or `-`) that parenthesise operands only where python's precedence requires it.
+/// They are written with `Display`, and accepted by every builder of `PythonProgram`,
+/// which then writes the declarations the literals inside them need.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expr {
+    source: String,
+    precedence: Precedence,
+    /// The definitions needed by the literals in the expression.
+    declarations: Vec<String>,
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expr {
+    fn new(source: String, precedence: Precedence, declarations: Vec<String>) -> Expr {
+        Expr {
+            source,
+            precedence,
+            declarations,
+        }
+    }
+
+    /// A variable, function or module, such as `plt`.
+    pub fn name(name: &str) -> Expr {
+        Expr::new(name.to_owned(), Precedence::Atom, Vec::new())
+    }
+
+    /// Raw source, which is parenthesised wherever precedence could matter.
+    pub fn raw(source: &str) -> Expr {
+        Expr::new(source.to_owned(), Precedence::Lambda, Vec::new())
+    }
+
+    /// `value` as a literal, along with the declarations it needs.
+    pub fn literal<T: AsPythonLitteral + ?Sized>(value: &T) -> Expr {
+        let mut declarations = Declarations::default();
+        T::declare(&mut declarations);
+        let source = PythonLiteral(value).to_string();
+        // Negative numbers are unary minus expressions to python.
+        let precedence = if source.starts_with('-') {
+            Precedence::Unary
+        } else {
+            Precedence::Atom
+        };
+        Expr::new(source, precedence, declarations.take_pending())
+    }
+
+    /// The source of this expression, parenthesised if it binds looser than `min`, keeping its declarations.
+    fn operand(self, min: Precedence, declarations: &mut Vec<String>) -> String {
+        declarations.extend(self.declarations);
+        if self.precedence < min {
+            format!("({})", self.source)
+        } else {
+            self.source
+        }
+    }
+
+    /// Each of `items` as an operand, separated by commas.
+    fn operands<I: IntoIterator<Item = Expr>>(
+        items: I,
+        min: Precedence,
+        declarations: &mut Vec<String>,
+    ) -> Vec<String> {
+        items
+            .into_iter()
+            .map(|item| item.operand(min, declarations))
+            .collect()
+    }
+
+    /// A primary expression (attribute, call or subscript) with `self` as its target.
+    fn primary(self, suffix: &str, mut declarations: Vec<String>) -> Expr {
+        // `1.real` would lex as a float, followed by a name.
+        let target = if self.source.starts_with(|c: char| c.is_ascii_digit()) {
+            declarations.extend(self.declarations);
+            format!("({})", self.source)
+        } else {
+            self.operand(Precedence::Atom, &mut declarations)
+        };
+        Expr::new(target + suffix, Precedence::Atom, declarations)
+    }
+
+    fn binary(self, operator: &str, other: Expr, precedence: Precedence) -> Expr {
+        let (left, right) = match precedence {
+            Precedence::Power => (Precedence::Await, Precedence::Unary),
+            // Comparisons chain, so that `a < b < c` is not `(a < b) < c`.
+            Precedence::Comparison => (Precedence::BitOr, Precedence::BitOr),
+            Precedence::Or => (Precedence::Or, Precedence::And),
+            Precedence::And => (Precedence::And, Precedence::Not),
+            Precedence::BitOr => (Precedence::BitOr, Precedence::BitXor),
+            Precedence::BitXor => (Precedence::BitXor, Precedence::BitAnd),
+            Precedence::BitAnd => (Precedence::BitAnd, Precedence::Shift),
+            Precedence::Shift => (Precedence::Shift, Precedence::Sum),
+            Precedence::Sum => (Precedence::Sum, Precedence::Product),
+            _ => (Precedence::Product, Precedence::Unary),
+        };
+        let mut declarations = Vec::new();
+        let left = self.operand(left, &mut declarations);
+        let right = other.operand(right, &mut declarations);
+        Expr::new(
+            format!("{} {} {}", left, operator, right),
+            precedence,
+            declarations,
+        )
+    }
+
+    fn unary(self, operator: &str, precedence: Precedence) -> Expr {
+        let mut declarations = Vec::new();
+        let operand = self.operand(precedence, &mut declarations);
+        Expr::new(format!("{}{}", operator, operand), precedence, declarations)
+    }
+
+    /// `self.name`
+    pub fn attr(self, name: &str) -> Expr {
+        self.primary(&format!(".{}", name), Vec::new())
+    }
+
+    /// `self(args)`
+    pub fn call<I: IntoIterator<Item = Expr>>(self, args: I) -> Expr {
+        self.call_kw(args, Vec::<(&str, Expr)>::new())
+    }
+
+    /// `self(args, name=value...)`
+    pub fn call_kw<I, K, S>(self, args: I, kwargs: K) -> Expr
+    where
+        I: IntoIterator<Item = Expr>,
+        K: IntoIterator<Item = (S, Expr)>,
+        S: AsRef<str>,
+    {
+        let mut declarations = Vec::new();
+        let mut arguments = Expr::operands(args, Precedence::Lambda, &mut declarations);
+        for (name, value) in kwargs {
+            let value = value.operand(Precedence::Lambda, &mut declarations);
+            arguments.push(format!("{}={}", name.as_ref(), value));
+        }
+        self.primary(&format!("({})", arguments.join(", ")), declarations)
+    }
+
+    /// `self.name(args)`
+    pub fn method<I: IntoIterator<Item = Expr>>(self, name: &str, args: I) -> Expr {
+        self.attr(name).call(args)
+    }
+
+    /// `self[key]`, where `key` may be a `slice`.
+    pub fn index(self, key: Expr) -> Expr {
+        let mut declarations = Vec::new();
+        let key = key.operand(Precedence::Slice, &mut declarations);
+        self.primary(&format!("[{}]", key), declarations)
+    }
+
+    /// `self[key, ...]`, where keys may be slices, as when indexing numpy arrays.
+    pub fn index_many<I: IntoIterator<Item = Expr>>(self, keys: I) -> Expr {
+        let mut declarations = Vec::new();
+        let keys = Expr::operands(keys, Precedence::Slice, &mut declarations);
+        let keys = match keys.len() {
+            1 => format!("{},", keys[0]),
+            _ => keys.join(", "),
+        };
+        self.primary(&format!("[{}]", keys), declarations)
+    }
+
+    /// `start:stop:step`, only valid as a key of `index` or `index_many`.
+    pub fn slice(start: Option<Expr>, stop: Option<Expr>, step: Option<Expr>) -> Expr {
+        let mut declarations = Vec::new();
+        let mut bound = |bound: Option<Expr>| {
+            bound
+                .map(|bound| bound.operand(Precedence::Conditional, &mut declarations))
+                .unwrap_or_default()
+        };
+        let mut source = format!("{}:{}", bound(start), bound(stop));
+        if let Some(step) = step {
+            source.push(':');
+            source.push_str(&bound(Some(step)));
+        }
+        Expr::new(source, Precedence::Slice, declarations)
+    }
+
+    /// `self ** other`
+    pub fn pow(self, other: Expr) -> Expr {
+        self.binary("**", other, Precedence::Power)
+    }
+
+    /// `self // other`
+    pub fn floordiv(self, other: Expr) -> Expr {
+        self.binary("//", other, Precedence::Product)
+    }
+
+    /// `self @ other`
+    pub fn matmul(self, other: Expr) -> Expr {
+        self.binary("@", other, Precedence::Product)
+    }
+
+    /// `self and other`
+    pub fn and(self, other: Expr) -> Expr {
+        self.binary("and", other, Precedence::And)
+    }
+
+    /// `self or other`
+    pub fn or(self, other: Expr) -> Expr {
+        self.binary("or", other, Precedence::Or)
+    }
+
+    /// `~self`
+    pub fn invert(self) -> Expr {
+        self.unary("~", Precedence::Unary)
+    }
+
+    /// `await self`
+    pub fn awaited(self) -> Expr {
+        let mut declarations = Vec::new();
+        let operand = self.operand(Precedence::Atom, &mut declarations);
+        Expr::new(
+            format!("await {}", operand),
+            Precedence::Await,
+            declarations,
+        )
+    }
+
+    /// `self == other`
+    pub fn equals(self, other: Expr) -> Expr {
+        self.binary("==", other, Precedence::Comparison)
+    }
+
+    /// `self != other`
+    pub fn not_equals(self, other: Expr) -> Expr {
+        self.binary("!=", other, Precedence::Comparison)
+    }
+
+    /// `self < other`
+    pub fn less_than(self, other: Expr) -> Expr {
+        self.binary("<", other, Precedence::Comparison)
+    }
+
+    /// `self <= other`
+    pub fn less_or_equal(self, other: Expr) -> Expr {
+        self.binary("<=", other, Precedence::Comparison)
+    }
+
+    /// `self > other`
+    pub fn greater_than(self, other: Expr) -> Expr {
+        self.binary(">", other, Precedence::Comparison)
+    }
+
+    /// `self >= other`
+    pub fn greater_or_equal(self, other: Expr) -> Expr {
+        self.binary(">=", other, Precedence::Comparison)
+    }
+
+    /// `self is other`
+    pub fn is(self, other: Expr) -> Expr {
+        self.binary("is", other, Precedence::Comparison)
+    }
+
+    /// `self is not other`
+    pub fn is_not(self, other: Expr) -> Expr {
+        self.binary("is not", other, Precedence::Comparison)
+    }
+
+    /// `self in other`, which also makes the header of a `for` loop.
+    pub fn is_in(self, other: Expr) -> Expr {
+        self.binary("in", other, Precedence::Comparison)
+    }
+
+    /// `self not in other`
+    pub fn not_in(self, other: Expr) -> Expr {
+        self.binary("not in", other, Precedence::Comparison)
+    }
+
+    /// `self if condition else otherwise`
+    pub fn if_else(self, condition: Expr, otherwise: Expr) -> Expr {
+        let mut declarations = Vec::new();
+        let body = self.operand(Precedence::Or, &mut declarations);
+        let condition = condition.operand(Precedence::Or, &mut declarations);
+        let otherwise = otherwise.operand(Precedence::Conditional, &mut declarations);
+        Expr::new(
+            format!("{} if {} else {}", body, condition, otherwise),
+            Precedence::Conditional,
+            declarations,
+        )
+    }
+
+    /// `lambda parameters: body`, ignoring the parameters' annotations but keeping the declarations their defaults need.
+    pub fn lambda(parameters: &Parameters, body: Expr) -> Expr {
+        let mut declarations = parameters.declarations.clone();
+        let body = body.operand(Precedence::Lambda, &mut declarations);
+        Expr::new(parameters.lambda(&body), Precedence::Lambda, declarations)
+    }
+
+    /// `[items]`
+    pub fn list<I: IntoIterator<Item = Expr>>(items: I) -> Expr {
+        let mut declarations = Vec::new();
+        let items = Expr::operands(items, Precedence::Lambda, &mut declarations);
+        Expr::new(
+            format!("[{}]", items.join(", ")),
+            Precedence::Atom,
+            declarations,
+        )
+    }
+
+    /// `(items)`, with the trailing comma of one-element tuples.
+    pub fn tuple<I: IntoIterator<Item = Expr>>(items: I) -> Expr {
+        let mut declarations = Vec::new();
+        let items = Expr::operands(items, Precedence::Lambda, &mut declarations);
+        let source = match items.len() {
+            1 => format!("({},)", items[0]),
+            _ => format!("({})", items.join(", ")),
+        };
+        Expr::new(source, Precedence::Atom, declarations)
+    }
+
+    /// `{items}`, or `set()` without items.
+    pub fn set<I: IntoIterator<Item = Expr>>(items: I) -> Expr {
+        let mut declarations = Vec::new();
+        let items = Expr::operands(items, Precedence::Lambda, &mut declarations);
+        let source = if items.is_empty() {
+            "set()".to_owned()
+        } else {
+            format!("{{{}}}", items.join(", "))
+        };
+        Expr::new(source, Precedence::Atom, declarations)
+    }
+
+    /// `{key: value, ...}`
+    pub fn dict<I: IntoIterator<Item = (Expr, Expr)>>(items: I) -> Expr {
+        let mut declarations = Vec::new();
+        let items: Vec<_> = items
+            .into_iter()
+            .map(|(key, value)| {
+                let key = key.operand(Precedence::Conditional, &mut declarations);
+                let value = value.operand(Precedence::Lambda, &mut declarations);
+                format!("{}: {}", key, value)
+            })
+            .collect();
+        Expr::new(
+            format!("{{{}}}", items.join(", ")),
+            Precedence::Atom,
+            declarations,
+        )
+    }
+
+    fn comprehension(
+        open: &str,
+        element: String,
+        clauses: &Comprehension,
+        close: &str,
+        mut declarations: Vec<String>,
+    ) -> Expr {
+        declarations.extend(clauses.declarations.iter().cloned());
+        Expr::new(
+            format!("{}{}{}{}", open, element, clauses.clauses, close),
+            Precedence::Atom,
+            declarations,
+        )
+    }
+
+    /// `[element for ...]`
+    pub fn list_comprehension(element: Expr, clauses: &Comprehension) -> Expr {
+        let mut declarations = Vec::new();
+        let element = element.operand(Precedence::Conditional, &mut declarations);
+        Expr::comprehension("[", element, clauses, "]", declarations)
+    }
+
+    /// `{element for ...}`
+    pub fn set_comprehension(element: Expr, clauses: &Comprehension) -> Expr {
+        let mut declarations = Vec::new();
+        let element = element.operand(Precedence::Conditional, &mut declarations);
+        Expr::comprehension("{", element, clauses, "}", declarations)
+    }
+
+    /// `(element for ...)`
+    pub fn generator(element: Expr, clauses: &Comprehension) -> Expr {
+        let mut declarations = Vec::new();
+        let element = element.operand(Precedence::Conditional, &mut declarations);
+        Expr::comprehension("(", element, clauses, ")", declarations)
+    }
+
+    /// `{key: value for ...}`
+    pub fn dict_comprehension(key: Expr, value: Expr, clauses: &Comprehension) -> Expr {
+        let mut declarations = Vec::new();
+        let key = key.operand(Precedence::Conditional, &mut declarations);
+        let value = value.operand(Precedence::Conditional, &mut declarations);
+        let element = format!("{}: {}", key, value);
+        Expr::comprehension("{", element, clauses, "}", declarations)
+    }
+}
+
+/// The `for` and `if` clauses of a comprehension.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comprehension {
+    clauses: String,
+    declarations: Vec<String>,
+}
+
+impl Comprehension {
+    /// Starts with "for `target` in `iter`", where `target` is a name or a tuple of names such as `k, v`.
+    pub fn new(target: &str, iter: Expr) -> Comprehension {
+        let mut comprehension = Comprehension {
+            clauses: String::new(),
+            declarations: Vec::new(),
+        };
+        comprehension.r#for(target, iter);
+        comprehension
+    }
+
+    /// Adds a nested "for `target` in `iter`".
+    pub fn r#for(&mut self, target: &str, iter: Expr) -> &mut Self {
+        let iter = iter.operand(Precedence::Or, &mut self.declarations);
+        self.clauses += &format!(" for {} in {}", target, iter);
+        self
+    }
+
+    /// Adds an "if `condition`" filter.
+    pub fn r#if(&mut self, condition: Expr) -> &mut Self {
+        let condition = condition.operand(Precedence::Or, &mut self.declarations);
+        self.clauses += &format!(" if {}", condition);
+        self
+    }
+}
+
+macro_rules! expr_operator_impl {
+    ($($trait: ident, $method: ident, $operator: expr, $precedence: ident;)+) => {
+        $(
+            impl std::ops::$trait for Expr {
+                type Output = Expr;
+                fn $method(self, other: Expr) -> Expr {
+                    self.binary($operator, other, Precedence::$precedence)
+                }
+            }
+        )+
+    };
+}
+
+expr_operator_impl!(
+    Add, add, "+", Sum;
+    Sub, sub, "-", Sum;
+    Mul, mul, "*", Product;
+    Div, div, "/", Product;
+    Rem, rem, "%", Product;
+    BitAnd, bitand, "&", BitAnd;
+    BitOr, bitor, "|", BitOr;
+    BitXor, bitxor, "^", BitXor;
+    Shl, shl, "<<", Shift;
+    Shr, shr, ">>", Shift;
+);
+
+/// `not self`: use `invert` for `~self`.
+impl std::ops::Not for Expr {
+    type Output = Expr;
+    fn not(self) -> Expr {
+        self.unary("not ", Precedence::Not)
+    }
+}
+
+impl std::ops::Neg for Expr {
+    type Output = Expr;
+    fn neg(self) -> Expr {
+        self.unary("-", Precedence::Unary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comprehension, Expr};
+    use crate::{Parameters, PyValue, PythonProgram};
+
+    fn name(name: &str) -> Expr {
+        Expr::name(name)
+    }
+
+    fn int(i: i64) -> Expr {
+        Expr::literal(&i)
+    }
+
+    #[test]
+    fn precedence() {
+        let (a, b, c) = (name("a"), name("b"), name("c"));
+        assert_eq!(
+            ((a.clone() + b.clone()) * c.clone()).to_string(),
+            "(a + b) * c"
+        );
+        assert_eq!((a.clone() + b.clone() * c.clone()).to_string(), "a + b * c");
+        assert_eq!(
+            (a.clone() - (b.clone() - c.clone())).to_string(),
+            "a - (b - c)"
+        );
+        assert_eq!((a.clone() - b.clone() - c.clone()).to_string(), "a - b - c");
+        assert_eq!(
+            a.clone().pow(b.clone().pow(c.clone())).to_string(),
+            "a ** b ** c"
+        );
+        assert_eq!(
+            a.clone().pow(b.clone()).pow(c.clone()).to_string(),
+            "(a ** b) ** c"
+        );
+        assert_eq!((-a.clone()).pow(int(2)).to_string(), "(-a) ** 2");
+        assert_eq!((-a.clone().pow(int(2))).to_string(), "-a ** 2");
+        assert_eq!(int(-2).pow(int(-1)).to_string(), "(-2) ** -1");
+        assert_eq!(
+            a.clone()
+                .less_than(b.clone())
+                .less_than(c.clone())
+                .to_string(),
+            "(a < b) < c"
+        );
+        assert_eq!(
+            a.clone().or(b.clone()).and(!c.clone()).to_string(),
+            "(a or b) and not c"
+        );
+        assert_eq!(
+            a.clone()
+                .if_else(b.clone(), c.clone().if_else(a.clone(), b.clone()))
+                .to_string(),
+            "a if b else c if a else b"
+        );
+        assert_eq!(int(1).attr("real").to_string(), "(1).real");
+        assert_eq!((a.clone() + b.clone()).attr("T").to_string(), "(a + b).T");
+        assert_eq!(Expr::raw("a + b").attr("T").to_string(), "(a + b).T");
+        assert_eq!(
+            name("x")
+                .index_many(vec![Expr::slice(None, None, Some(int(2))), int(0)])
+                .to_string(),
+            "x[::2, 0]"
+        );
+        assert_eq!(
+            name("f")
+                .call(vec![Expr::lambda(Parameters::new().param("x"), name("x"))])
+                .to_string(),
+            "f(lambda x: x)"
+        );
+        assert_eq!(
+            name("plt")
+                .method("plot", vec![name("x"), name("y")])
+                .to_string(),
+            "plt.plot(x, y)"
+        );
+        assert_eq!(
+            name("plt")
+                .attr("plot")
+                .call_kw(vec![name("x")], vec![("label", Expr::literal("it's"))])
+                .to_string(),
+            "plt.plot(x, label=\"it's\")"
+        );
+    }
+
+    /// Checks that rendered expressions evaluate as the tree says.
+    #[test]
+    fn evaluation() {
+        let mut program = PythonProgram::new();
+        program.define_variable("xs", &vec![3, 1, 2]);
+        program.write_line("import asyncio");
+        let check = |expression: Expr, expected: PyValue| {
+            assert_eq!(
+                program.evaluate::<PyValue>(&expression).unwrap(),
+                expected,
+                "{}",
+                expression
+            );
+        };
+        check((int(1) + int(2)) * int(3), PyValue::Int(9));
+        check(int(2) - (int(3) - int(4)), PyValue::Int(3));
+        check(int(-2).pow(int(2)), PyValue::Int(4));
+        check(-int(2).pow(int(2)), PyValue::Int(-4));
+        check(int(7).floordiv(int(2)) % int(2), PyValue::Int(1));
+        check(
+            (int(1) << int(4) | int(1)) & int(0xff) ^ int(3),
+            PyValue::Int(17 ^ 3),
+        );
+        check(
+            int(1).less_than(int(2)).equals(Expr::literal(&true)),
+            PyValue::Bool(true),
+        );
+        check(
+            name("xs").index(Expr::slice(Some(int(1)), None, None)),
+            PyValue::List(vec![PyValue::Int(1), PyValue::Int(2)]),
+        );
+        check(
+            name("sorted").call_kw(vec![name("xs")], vec![("reverse", Expr::literal(&true))]),
+            PyValue::List(vec![PyValue::Int(3), PyValue::Int(2), PyValue::Int(1)]),
+        );
+        check(
+            Expr::list_comprehension(
+                name("x") * int(10),
+                Comprehension::new("x", name("xs")).r#if(name("x").greater_than(int(1))),
+            ),
+            PyValue::List(vec![PyValue::Int(30), PyValue::Int(20)]),
+        );
+        check(
+            Expr::dict_comprehension(
+                name("k"),
+                name("v"),
+                Comprehension::new("k, v", name("zip").call(vec![name("xs"), name("xs")]))
+                    .r#if(name("k").equals(int(1))),
+            ),
+            PyValue::Dict(vec![(PyValue::Int(1), PyValue::Int(1))]),
+        );
+        check(
+            name("sum").call(vec![Expr::generator(
+                name("x"),
+                &Comprehension::new("x", name("xs")),
+            )]),
+            PyValue::Int(6),
+        );
+        check(
+            Expr::tuple(vec![
+                Expr::tuple(vec![int(1)]),
+                Expr::set(vec![]),
+                Expr::dict(vec![(int(1), Expr::list(vec![]))]),
+            ]),
+            PyValue::Tuple(vec![
+                PyValue::Tuple(vec![PyValue::Int(1)]),
+                PyValue::Set(vec![]),
+                PyValue::Dict(vec![(PyValue::Int(1), PyValue::List(vec![]))]),
+            ]),
+        );
+        check(
+            Expr::lambda(Parameters::new().optional("y", "2"), name("y") * name("y")).call(vec![]),
+            PyValue::Int(4),
+        );
+    }
+
+    #[test]
+    fn in_programs() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: i32,
+        }
+
+        let point = Expr::literal(&Point { x: 2 });
+        let mut program = PythonProgram::new();
+        program
+            .r#for(&name("i").is_in(name("range").call(vec![int(3)])))
+            .r#if(&name("i").equals(point.clone().attr("x")))
+            .write_line(&name("print").call(vec![name("i"), point.clone()]))
+            .end_block()
+            .end_block();
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "2 Point(x=2)\n");
+        assert_eq!(
+            program
+                .evaluate::<i64>(&point.attr("x").pow(int(3)))
+                .unwrap(),
+            8
+        );
+    }
+}
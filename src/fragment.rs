@@ -0,0 +1,340 @@
+//! Composing programs: fragments built on their own, and python files included as they are.
+use crate::snippet::in_strings;
+use crate::{IndentUnit, PycallError, PythonProgram, Section};
+use std::fmt::{Display, Error, Formatter};
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+
+/// A piece of python built on its own, then embedded in programs with `PythonProgram::append`.
+///
+/// Fragments are written with the methods of `PythonProgram`, sections included: a fragment can
+/// import modules, define data and write plotting calls to the footer, each landing in the matching
+/// section of the programs it is appended to.
+#[derive(Clone, Default, PartialEq)]
+pub struct PythonFragment(PythonProgram);
+
+impl PythonFragment {
+    /// Creates an empty fragment.
+    pub fn new() -> PythonFragment {
+        PythonFragment(PythonProgram::new())
+    }
+}
+
+impl Deref for PythonFragment {
+    type Target = PythonProgram;
+
+    fn deref(&self) -> &PythonProgram {
+        &self.0
+    }
+}
+
+impl DerefMut for PythonFragment {
+    fn deref_mut(&mut self) -> &mut PythonProgram {
+        &mut self.0
+    }
+}
+
+impl Display for PythonFragment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        self.0.fmt(f)
+    }
+}
+
+/// Converts the indentation of `line` from `from` to `to`, after `indentation`.
+fn reindent(line: &str, from: IndentUnit, to: IndentUnit, indentation: &str) -> String {
+    let code = line.trim_start();
+    let whitespace = &line[..line.len() - code.len()];
+    let tabs = whitespace.matches('\t').count();
+    let spaces = whitespace.len() - tabs;
+    let (levels, spaces) = match from {
+        IndentUnit::Spaces(width) if width > 0 => (tabs + spaces / width, spaces % width),
+        _ => (tabs, spaces),
+    };
+    format!(
+        "{}{}{}{}",
+        indentation,
+        to.repeat(levels),
+        " ".repeat(spaces),
+        code
+    )
+}
+
+/// The lines of `fragment` holding the declarations that `program` already wrote itself, in `lines` of its `section`.
+fn declared_lines(
+    program: &PythonProgram,
+    fragment: &PythonProgram,
+    section: Section,
+    lines: &[&str],
+) -> Vec<bool> {
+    let mut declared = vec![false; lines.len()];
+    if section > Section::Data {
+        return declared;
+    }
+    for code in &fragment.declarations.declared {
+        if !program.declarations.is_declared(code) {
+            continue;
+        }
+        // Rendered the way the fragment wrote it.
+        let mut rendered = PythonProgram::new();
+        rendered
+            .indent_unit(fragment.indent_unit)
+            .write_snippet(code);
+        let rendered: Vec<&str> = rendered.source.lines().collect();
+        let found = (0..(lines.len() + 1).saturating_sub(rendered.len())).find(|&start| {
+            !declared[start] && lines[start..start + rendered.len()] == rendered[..]
+        });
+        if let Some(start) = found {
+            declared[start..start + rendered.len()].fill(true);
+        }
+    }
+    declared
+}
+
+impl PythonProgram {
+    /// Embeds `fragment` at the current indentation level of the current section.
+    /// The other sections of the fragment are appended to the same sections of this program, and its imports merged;
+    /// the declarations this program already wrote are left out, and the contents of multi-line strings left as they are.
+    /// The lines of the fragment keep pointing to the calls that wrote them.
+    #[track_caller]
+    pub fn append(&mut self, fragment: &PythonFragment) -> &mut Self {
+        let indentation = self.indentation();
+        self.embed(fragment, self.current_section(), &indentation)
+    }
+
+    /// Embeds `fragment`, appending its body to the end of `section` at its top level.
+    /// Use `append` to embed it in a block of the current section instead.
+    #[track_caller]
+    pub fn append_to(&mut self, section: Section, fragment: &PythonFragment) -> &mut Self {
+        self.embed(fragment, section, "")
+    }
+
+    #[track_caller]
+    fn embed(&mut self, fragment: &PythonFragment, body: Section, indentation: &str) -> &mut Self {
+        let fragment = &fragment.0;
+        if self.error.is_some() {
+            return self;
+        }
+        if let Err(e) = fragment.check_blocks() {
+            self.error = Some(e);
+            return self;
+        }
+        self.merge_imports(&fragment.imports);
+        if self.error.is_some() {
+            return self;
+        }
+        self.names.merge(&fragment.names);
+        // The declarations both programs made are only kept once, where this program wrote them.
+        let declared: Vec<Vec<bool>> = Section::ALL
+            .iter()
+            .map(|&section| {
+                let lines: Vec<&str> = fragment
+                    .section_lines(section)
+                    .map(|(line, _)| line)
+                    .collect();
+                declared_lines(self, fragment, section, &lines)
+            })
+            .collect();
+        self.declarations.merge(&fragment.declarations);
+        self.data_files.extend(fragment.data_files.iter().cloned());
+        for (section, declared) in Section::ALL.iter().copied().zip(declared) {
+            let (target, indentation) = match section {
+                Section::Body => (body, indentation),
+                section => (section, ""),
+            };
+            let lines: Vec<(&str, _)> = fragment.section_lines(section).collect();
+            let strings = in_strings(&lines.iter().map(|(line, _)| *line).collect::<Vec<_>>());
+            let mut text = String::new();
+            let mut written_by = Vec::new();
+            for (((line, location), string), declared) in
+                lines.into_iter().zip(strings).zip(declared)
+            {
+                if declared {
+                    continue;
+                }
+                if string {
+                    // The contents of multi-line strings are left as they are.
+                    text += line;
+                } else if !line.trim().is_empty() {
+                    text += &reindent(line, fragment.indent_unit, self.indent_unit, indentation);
+                }
+                text.push('\n');
+                written_by.push(location);
+            }
+            let statements = text.lines().any(|line| {
+                let code = line.trim_start();
+                !code.is_empty() && !code.starts_with('#')
+            });
+            if target == self.current_section() && !indentation.is_empty() && statements {
+                if let Some(block) = self.blocks.last_mut() {
+                    block.statements += 1;
+                }
+            }
+            self.insert_raw(target, &text, written_by);
+        }
+        self
+    }
+
+    /// Writes the python file at `path` at the current indentation level, like `write_snippet`,
+    /// except that the contents of multi-line strings, such as docstrings, are left as they are.
+    /// If it can't be read, the program fails with `PycallError::Include`.
+    #[track_caller]
+    pub fn include_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> &mut Self {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(code) => self.write_code(&code, true),
+            Err(error) => {
+                if self.error.is_none() {
+                    self.error = Some(PycallError::Include {
+                        path: path.to_owned(),
+                        error,
+                        location: Location::caller(),
+                    });
+                }
+                self
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{IndentUnit, PycallError, PythonFragment, PythonProgram, Section};
+    use std::io::Write;
+
+    /// A fragment computing statistics, as another module would write it.
+    fn statistics(values: &[f64]) -> PythonFragment {
+        let mut fragment = PythonFragment::new();
+        fragment.indent_unit(IndentUnit::Spaces(2));
+        fragment.from_import("statistics", &["mean"]);
+        fragment.section(Section::Data);
+        fragment.define_variable("values", values);
+        fragment.section(Section::Body);
+        fragment
+            .r#for("value in values")
+            .write_line("total += value")
+            .end_block();
+        fragment
+            .section(Section::Footer)
+            .write_line("print('mean', mean(values))");
+        fragment
+    }
+
+    #[test]
+    fn fragments() {
+        let fragment = statistics(&[1.0, 2.0, 3.0]);
+        let mut program = PythonProgram::new();
+        program.import("statistics").write_line("total = 0");
+        program.r#if("True");
+        program.append(&fragment).end_block();
+        program.append_to(Section::Footer, &PythonFragment::new());
+        let mut footer = PythonFragment::new();
+        footer.write_line("print('total', total)");
+        program.append_to(Section::Footer, &footer);
+        assert_eq!(
+            program.as_str(),
+            "import statistics\n\
+             from statistics import mean\n\
+             values = [1.0,2.0,3.0,]\n\
+             total = 0\n\
+             if True:\n\
+             \tfor value in values:\n\
+             \t\ttotal += value\n\
+             print('mean', mean(values))\n\
+             print('total', total)\n"
+        );
+        // Lines still point to the fragment's code.
+        assert_eq!(program.written_by(7), fragment.written_by(4));
+        let output = program.run_checked().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "mean 2.0\ntotal 6.0\n"
+        );
+
+        // Fragments that failed fail the programs they're appended to.
+        let mut broken = PythonFragment::new();
+        broken.define_variable("class", &1);
+        program.append(&broken);
+        assert!(matches!(
+            program.run(),
+            Err(PycallError::InvalidName { .. })
+        ));
+    }
+
+    #[test]
+    fn shared_declarations() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: i32,
+        }
+
+        // Classes declared by both are only defined once, and strings keep their contents.
+        let mut fragment = PythonFragment::new();
+        fragment.indent_unit(IndentUnit::Spaces(4));
+        fragment.define_variable("b", &Point { x: 2 });
+        write!(fragment, "TEXT = '''\n  two spaces\n'''\n").unwrap();
+        let mut program = PythonProgram::new();
+        program.define_variable("a", &Point { x: 1 });
+        program.r#if("True");
+        program.append(&fragment).end_block();
+        program.write_line("print(a.x + b.x, repr(TEXT))");
+        let source = program.to_string();
+        assert_eq!(source.matches("class Point").count(), 1, "{}", source);
+        assert_eq!(run(&program), "3 '\\n  two spaces\\n'\n");
+    }
+
+    #[test]
+    fn includes() {
+        let mut helper = tempfile::Builder::new().suffix(".py").tempfile().unwrap();
+        let helper_source = r#"
+def double(x):
+    """Doubles x.
+
+        >>> double(2)
+    """
+    return 2 * x
+TEXT = '''
+  two spaces # """ \'''
+''' # '''
+"#;
+        write!(helper, "{}", helper_source).unwrap();
+        let mut program = PythonProgram::new();
+        program.r#if("True");
+        let included_at = line!() + 1;
+        program.include_file(helper.path()).end_block();
+        program.write_line("print(double(21), double.__doc__.splitlines()[2])");
+        program.write_line("print(repr(TEXT))");
+        // String contents are left as they are.
+        let included = r#"
+	def double(x):
+		"""Doubles x.
+
+        >>> double(2)
+    """
+		return 2 * x
+	TEXT = '''
+  two spaces # """ \'''
+''' # '''
+"#;
+        assert!(program.as_str().contains(&included[1..]), "{}", program);
+        assert_eq!(program.written_by(3).unwrap().line(), included_at);
+        assert_eq!(
+            run(&program),
+            "42         >>> double(2)\n'\\n  two spaces # \"\"\" \\'\\'\\'\\n'\n"
+        );
+
+        let missing = helper.path().with_extension("missing");
+        program.include_file(&missing);
+        match program.run() {
+            Err(PycallError::Include { path, .. }) => assert_eq!(path, missing),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn run(program: &PythonProgram) -> String {
+        let output = program.run_checked().unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}
@@ -0,0 +1,324 @@
+//! The imports of a program: deduplicated, and hoisted to the top of its script.
+use crate::{is_identifier, is_keyword, PythonProgram};
+use std::fmt::{Display, Error, Formatter};
+use std::panic::Location;
+
+/// An import statement.
+#[derive(Clone, Debug, PartialEq)]
+enum Import {
+    /// `import module` or `import module as alias`.
+    Module {
+        module: String,
+        alias: Option<String>,
+    },
+    /// `from module import name, other as alias`.
+    From {
+        module: String,
+        names: Vec<(String, Option<String>)>,
+    },
+}
+
+/// Splits `name as alias`.
+fn parse_item(item: &str) -> (String, Option<String>) {
+    match item.split_once(" as ") {
+        Some((name, alias)) => (name.trim().to_owned(), Some(alias.trim().to_owned())),
+        None => (item.trim().to_owned(), None),
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    is_identifier(name) && !is_keyword(name)
+}
+
+/// Whether `module` is a dotted module path, relative ones starting with dots if `relative`.
+fn is_module(module: &str, relative: bool) -> bool {
+    let path = if relative {
+        module.trim_start_matches('.')
+    } else {
+        module
+    };
+    (path.is_empty() && path.len() < module.len()) || path.split('.').all(is_identifier)
+}
+
+impl Import {
+    fn is_future(&self) -> bool {
+        matches!(self, Import::From { module, .. } if module == "__future__")
+    }
+
+    /// The first name of this import that is invalid, and why.
+    fn invalid_name(&self) -> Option<(&str, &'static str)> {
+        let (module, relative, names) = match self {
+            Import::Module { module, alias } => (module, false, alias.iter().collect()),
+            Import::From { module, names } => (
+                module,
+                true,
+                names
+                    .iter()
+                    .flat_map(|(name, alias)| std::iter::once(name).chain(alias))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        if !is_module(module, relative) {
+            return Some((module, "not a module name"));
+        }
+        names
+            .into_iter()
+            .find(|name| !is_name(name))
+            .map(|name| (name.as_str(), "not a valid import name"))
+    }
+}
+
+fn fmt_item(f: &mut Formatter<'_>, name: &str, alias: &Option<String>) -> Result<(), Error> {
+    match alias {
+        Some(alias) => write!(f, "{} as {}", name, alias),
+        None => f.write_str(name),
+    }
+}
+
+impl Display for Import {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Import::Module { module, alias } => {
+                f.write_str("import ")?;
+                fmt_item(f, module, alias)
+            }
+            Import::From { module, names } => {
+                write!(f, "from {} import ", module)?;
+                for (i, (name, alias)) in names.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    fmt_item(f, name, alias)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The import statements of a program, in the order they were first requested, `__future__` ones first.
+/// Each is attributed to the call that first requested it.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Imports {
+    statements: Vec<(Import, Option<&'static Location<'static>>)>,
+}
+
+impl Imports {
+    /// Adds `import`, unless it is already there.
+    /// Names imported from a module that is already imported from are added to its statement.
+    fn add(&mut self, import: Import, written_by: Option<&'static Location<'static>>) {
+        if let Import::From { module, names } = &import {
+            let existing = self
+                .statements
+                .iter_mut()
+                .find_map(|(other, _)| match other {
+                    Import::From {
+                        module: other,
+                        names,
+                    } if other == module => Some(names),
+                    _ => None,
+                });
+            if let Some(existing) = existing {
+                for name in names {
+                    if !existing.contains(name) {
+                        existing.push(name.clone());
+                    }
+                }
+                return;
+            }
+        }
+        if self.statements.iter().any(|(other, _)| *other == import) {
+            return;
+        }
+        let position = if import.is_future() {
+            self.statements
+                .iter()
+                .take_while(|(other, _)| other.is_future())
+                .count()
+        } else {
+            self.statements.len()
+        };
+        self.statements.insert(position, (import, written_by));
+    }
+
+    /// The calls that requested each import statement.
+    fn locations(&self) -> Vec<Option<&'static Location<'static>>> {
+        self.statements
+            .iter()
+            .map(|(_, written_by)| *written_by)
+            .collect()
+    }
+
+    /// The import statements, one per line.
+    fn header(&self) -> String {
+        self.statements
+            .iter()
+            .map(|(import, _)| format!("{}\n", import))
+            .collect()
+    }
+}
+
+impl PythonProgram {
+    /// Imports the comma-separated `modules`, each of which may be renamed: `"numpy as np, sys"`.
+    /// Imports are written once, at the top of the script, wherever and however many times they are requested.
+    #[track_caller]
+    pub fn import(&mut self, modules: &str) -> &mut Self {
+        for item in modules.split(',') {
+            let (module, alias) = parse_item(item);
+            self.add_import(Import::Module { module, alias });
+        }
+        self
+    }
+
+    /// Imports `module` as `rename`, which must be a valid name.
+    #[track_caller]
+    pub fn import_as(&mut self, module: &str, rename: &str) -> &mut Self {
+        self.add_import(Import::Module {
+            module: module.trim().to_owned(),
+            alias: Some(rename.trim().to_owned()),
+        });
+        self
+    }
+
+    /// Imports `names` from `module`, each of which may be renamed: `&["deque", "OrderedDict as OD"]`.
+    /// Names imported from the same module share a single statement.
+    #[track_caller]
+    pub fn from_import(&mut self, module: &str, names: &[&str]) -> &mut Self {
+        let names = names.iter().map(|name| parse_item(name)).collect();
+        self.add_import(Import::From {
+            module: module.trim().to_owned(),
+            names,
+        });
+        self
+    }
+
+    /// Checks `import` and records it, along with the names it binds.
+    #[track_caller]
+    fn add_import(&mut self, import: Import) {
+        if let Some((name, reason)) = import.invalid_name() {
+            let name = name.to_owned();
+            self.invalid_name(&name, reason);
+            return;
+        }
+        self.bind_import(&import.to_string());
+        self.imports.add(import, Some(Location::caller()));
+        self.replace_imports(&self.imports.header(), self.imports.locations());
+    }
+
+    /// Adds the imports of another program, attributed to the calls that requested them there.
+    pub(crate) fn merge_imports(&mut self, imports: &Imports) {
+        for (import, written_by) in &imports.statements {
+            self.bind_import(&import.to_string());
+            self.imports.add(import.clone(), *written_by);
+        }
+        self.replace_imports(&self.imports.header(), self.imports.locations());
+    }
+
+    /// Hoists `code` with the program's imports if it is a single import statement, returning whether it was.
+    #[track_caller]
+    pub(crate) fn hoist_import(&mut self, code: &str) -> bool {
+        let code = code.trim();
+        if code.contains('\n') {
+            return false;
+        }
+        if let Some(modules) = code.strip_prefix("import ") {
+            self.import(modules);
+        } else if let Some((module, names)) = code
+            .strip_prefix("from ")
+            .and_then(|rest| rest.split_once(" import "))
+        {
+            let names: Vec<&str> = names
+                .trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace())
+                .split(',')
+                .collect();
+            self.from_import(module, &names);
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MatPlotLib, PycallError, PythonProgram};
+
+    #[test]
+    fn imports() {
+        let mut program = PythonProgram::new();
+        let line = line!() + 1;
+        program.write_line("print(sqrt(deque([4]).pop()), np, OD.__name__)");
+        program.r#if("True").import("math, sys").end_block();
+        program
+            .from_import("collections", &["deque"])
+            .from_import("math", &["sqrt"])
+            .import("sys")
+            .from_import("collections", &["OrderedDict as OD", "deque"])
+            .from_import("__future__", &["annotations"]);
+        program.import_as("types", "np").import_pyplot_as_plt();
+        program.import_pyplot_as_plt();
+        assert_eq!(
+            program.to_string(),
+            "from __future__ import annotations\n\
+             import math\n\
+             import sys\n\
+             from collections import deque, OrderedDict as OD\n\
+             from math import sqrt\n\
+             import types as np\n\
+             import matplotlib.pyplot as plt\n\
+             print(sqrt(deque([4]).pop()), np, OD.__name__)\n\
+             if True:\n\
+             \tpass\n"
+        );
+        // Imports are attributed to the calls that first requested them.
+        assert_eq!(program.written_by(4).unwrap().line(), line + 3);
+        assert_eq!(program.written_by(8).unwrap().line(), line);
+
+        let error = |import: &dyn Fn(&mut PythonProgram)| {
+            let mut program = PythonProgram::new();
+            import(&mut program);
+            match program.run() {
+                Err(PycallError::InvalidName { name, reason, .. }) => {
+                    format!("{}: {}", name, reason)
+                }
+                other => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(
+            error(&|program| {
+                program.import("numpy.");
+            }),
+            "numpy.: not a module name"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.from_import("os", &["path as class"]);
+            }),
+            "class: not a valid import name"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.from_import(".", &["*"]);
+            }),
+            "*: not a valid import name"
+        );
+    }
+
+    #[test]
+    fn hoisting() {
+        let mut program = PythonProgram::new();
+        program.r#for("i in range(2)");
+        program.define_ndarray("a", &[1u8, 2], &[2]);
+        program.plot_y(&vec![1.0, 2.0]);
+        program.end_block();
+        program.write_line("print(a.sum(), np.__name__, plt.__name__)");
+        let source = program.to_string();
+        assert!(
+            source.starts_with(
+                "import numpy as np\nimport matplotlib.pyplot as plt\nfor i in range(2):\n"
+            ),
+            "{}",
+            source
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Choosing the python interpreter that runs programs, and the environment it runs in.
+use crate::PycallError;
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Error, Formatter};
+use std::path::{Path, PathBuf};
+
+/// How to start python: which executable, with which flags, environment and working directory.
+///
+/// By default, the executable is discovered from the environment:
+/// `PYCALL_PYTHON` if set, else the python of the active virtualenv (`VIRTUAL_ENV`) or conda env (`CONDA_PREFIX`),
+/// else `python3` from the `PATH`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interpreter {
+    executable: PathBuf,
+    args: Vec<OsString>,
+    env: Vec<(OsString, Option<OsString>)>,
+    clear_env: bool,
+    current_dir: Option<PathBuf>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::discover()
+    }
+}
+
+impl Interpreter {
+    /// Uses `executable`, which is looked up in the `PATH` if it isn't a path.
+    pub fn new<P: AsRef<Path>>(executable: P) -> Interpreter {
+        Interpreter {
+            executable: executable.as_ref().to_owned(),
+            args: Vec::new(),
+            env: Vec::new(),
+            clear_env: false,
+            current_dir: None,
+        }
+    }
+
+    /// Finds the interpreter from `PYCALL_PYTHON`, `VIRTUAL_ENV` or `CONDA_PREFIX`, falling back to `python3`.
+    pub fn discover() -> Interpreter {
+        Interpreter::new(discover_executable(|name| std::env::var_os(name)))
+    }
+
+    /// The executable this interpreter runs.
+    pub fn executable(&self) -> &Path {
+        &self.executable
+    }
+
+    /// Passes `arg` to python, before the script. Typically a flag such as `-O` or `-X dev`.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Passes each of `args` to python, before the script.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Sets the environment variable `key` to `value` for python, such as `MPLBACKEND=Agg`.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+        self.env
+            .push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+        self
+    }
+
+    /// Removes the environment variable `key` from python's environment.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.env.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    /// Whether python inherits this process' environment (the default), or only gets the variables set with `env`.
+    pub fn inherit_env(&mut self, inherit: bool) -> &mut Self {
+        self.clear_env = !inherit;
+        self
+    }
+
+    /// Runs python in `dir` instead of this process' working directory.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// A command starting python with this configuration, without any script.
+    pub fn command(&self) -> std::process::Command {
+        let mut command = std::process::Command::new(&self.executable);
+        if self.clear_env {
+            command.env_clear();
+        }
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        command.args(&self.args);
+        command
+    }
+
+    /// The error for a failure to start this interpreter: `PycallError::InterpreterNotFound` if its executable
+    /// is missing, `PycallError::Io` for anything else, such as a missing working directory or a permission error.
+    pub(crate) fn start_error(&self, error: std::io::Error) -> PycallError {
+        let missing_dir = matches!(&self.current_dir, Some(dir) if !dir.is_dir());
+        if error.kind() == std::io::ErrorKind::NotFound && !missing_dir {
+            PycallError::InterpreterNotFound(error)
+        } else {
+            PycallError::Io(error)
+        }
+    }
+
+    /// Runs the script at `path` to completion.
+    pub(crate) fn run(&self, path: &Path) -> Result<std::process::Output, PycallError> {
+        self.command()
+            .arg(path)
+            .output()
+            .map_err(|e| self.start_error(e))
+    }
+
+    /// Starts the interpreter to ask its version, which also checks that it can be started at all.
+    pub fn version(&self) -> Result<PythonVersion, PycallError> {
+        let output = self
+            .command()
+            .arg("-c")
+            .arg("import sys; print('%d.%d.%d' % sys.version_info[:3])")
+            .output()
+            .map_err(|e| self.start_error(e))?;
+        let output = PycallError::check_output(output)?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        let mut parts = version.trim().split('.').map(str::parse::<u32>);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => Ok(PythonVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(PycallError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected python version: {:?}", version),
+            ))),
+        }
+    }
+}
+
+/// The version of a python interpreter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PythonVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Display for PythonVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+fn discover_executable<F: Fn(&str) -> Option<OsString>>(var: F) -> PathBuf {
+    let set = |name| var(name).filter(|value| !value.is_empty());
+    if let Some(python) = set("PYCALL_PYTHON") {
+        return python.into();
+    }
+    let in_env = |prefix: OsString| {
+        let prefix = PathBuf::from(prefix);
+        if cfg!(windows) {
+            prefix.join("Scripts").join("python.exe")
+        } else {
+            prefix.join("bin").join("python")
+        }
+    };
+    if let Some(venv) = set("VIRTUAL_ENV") {
+        return in_env(venv);
+    }
+    if let Some(conda) = set("CONDA_PREFIX") {
+        return if cfg!(windows) {
+            PathBuf::from(conda).join("python.exe")
+        } else {
+            in_env(conda)
+        };
+    }
+    PathBuf::from("python3")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovery() {
+        let discover = |vars: &[(&str, &str)]| {
+            let vars: Vec<(String, OsString)> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), OsString::from(v)))
+                .collect();
+            discover_executable(|name| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone()))
+        };
+        assert_eq!(discover(&[]), PathBuf::from("python3"));
+        assert_eq!(discover(&[("PYCALL_PYTHON", "")]), PathBuf::from("python3"));
+        if cfg!(unix) {
+            assert_eq!(
+                discover(&[("CONDA_PREFIX", "/opt/conda"), ("VIRTUAL_ENV", "/venv")]),
+                PathBuf::from("/venv/bin/python")
+            );
+            assert_eq!(
+                discover(&[("CONDA_PREFIX", "/opt/conda")]),
+                PathBuf::from("/opt/conda/bin/python")
+            );
+        }
+        assert_eq!(
+            discover(&[("VIRTUAL_ENV", "/venv"), ("PYCALL_PYTHON", "python3.11")]),
+            PathBuf::from("python3.11")
+        );
+    }
+
+    #[test]
+    fn configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut interpreter = Interpreter::new("python3");
+        interpreter
+            .args(["-O", "-X", "utf8"])
+            .env("PYCALL_TEST", "set")
+            .current_dir(dir.path());
+        assert!(
+            interpreter.version().unwrap()
+                >= PythonVersion {
+                    major: 3,
+                    minor: 0,
+                    patch: 0
+                }
+        );
+
+        let mut program = crate::PythonProgram::new();
+        program
+            .interpreter(interpreter.clone())
+            .import("os, sys")
+            .write_line("print(__debug__, sys.flags.utf8_mode)")
+            .write_line("print(os.environ['PYCALL_TEST'])")
+            .write_line("print(os.getcwd())");
+        let output = program.run_checked().unwrap();
+        let expected = format!(
+            "False 1\nset\n{}\n",
+            dir.path().canonicalize().unwrap().display()
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+        interpreter.inherit_env(false).env_remove("PYCALL_TEST");
+        program.interpreter(interpreter);
+        let output = program.run().unwrap();
+        assert!(String::from_utf8_lossy(&output.stderr).contains("KeyError: 'PYCALL_TEST'"));
+
+        let missing = Interpreter::new("/nonexistent/python");
+        assert!(matches!(
+            missing.version(),
+            Err(PycallError::InterpreterNotFound(_))
+        ));
+        assert!(matches!(
+            crate::plots::plot_y_with(&missing, &[1, 2]),
+            Err(PycallError::InterpreterNotFound(_))
+        ));
+        program.interpreter(missing);
+        assert!(matches!(
+            program.run(),
+            Err(PycallError::InterpreterNotFound(_))
+        ));
+
+        // Other failures to start python are plain I/O errors.
+        let mut elsewhere = Interpreter::new("python3");
+        elsewhere.current_dir(dir.path().join("missing"));
+        program.interpreter(elsewhere);
+        assert!(matches!(program.run(), Err(PycallError::Io(_))));
+        assert!(matches!(program.spawn(), Err(PycallError::Io(_))));
+        let not_executable = dir.path().join("python");
+        std::fs::write(&not_executable, "").unwrap();
+        assert!(matches!(
+            Interpreter::new(&not_executable).version(),
+            Err(PycallError::Io(_))
+        ));
+    }
+}
@@ -1,8 +1,123 @@
 use std::fmt::{Display, Error, Formatter};
 use std::io::Write;
+use std::panic::Location;
+
+#[cfg(feature = "derive")]
+pub use pycall_derive::{py, AsPythonLitteral};
+
+mod parse;
+pub use parse::{parse_python_literal, FromPythonLiteral, LiteralError, PyValue};
+
+mod error;
+pub use error::PycallError;
+
+mod blocks;
+pub use blocks::BlockGuard;
+use blocks::BlockKind;
+
+mod statements;
+pub use statements::Parameters;
+
+mod expr;
+pub use expr::{AsPythonSource, Comprehension, Expr};
+
+mod snippet;
+pub use snippet::Snippet;
+
+mod names;
+pub use names::{is_identifier, is_keyword, Var};
+
+mod imports;
+
+mod sections;
+pub use sections::Section;
+
+mod fragment;
+pub use fragment::PythonFragment;
+
+mod data;
+
+mod npy;
+pub use npy::{write_npy, NpyArray, NpyElement, Order};
+
+#[cfg(feature = "nalgebra")]
+mod nalgebra_support;
+#[cfg(feature = "ndarray")]
+mod ndarray_support;
+
+mod interpreter;
+pub use interpreter::{Interpreter, PythonVersion};
+
+mod process;
+pub use process::{OutputLine, RunningProgram, SpawnOptions, Stream};
+
+mod traceback;
+pub use traceback::{Frame, PythonException};
+
+mod session;
+pub use session::{ChunkOutput, PythonSession};
+
+#[cfg(feature = "serde")]
+mod ser;
+#[cfg(feature = "serde")]
+pub use ser::{to_python_literal, SerializeError};
+
+// Lets `#[derive(AsPythonLitteral)]` refer to `::pycall` from within this crate too.
+extern crate self as pycall;
 
 pub trait AsPythonLitteral {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result;
+
+    /// Registers the definitions (imports, classes...) that this type's literals rely on.
+    /// Most types don't need any.
+    fn declare(_declarations: &mut Declarations) {}
+
+    /// Writes the value in python's `marshal` format, which side files use for large values, returning `false`
+    /// if it has none: the value is then stored as source and evaluated, and whatever was written is discarded.
+    /// `hashable` is set for set elements and dict keys, where sequences become tuples and sets frozensets.
+    fn marshal(&self, _out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The Python definitions required by the literals of a program, each of which is only written once.
+#[derive(Clone, Debug, Default)]
+pub struct Declarations {
+    visited: std::collections::HashSet<String>,
+    declared: std::collections::HashSet<String>,
+    pending: Vec<String>,
+}
+
+impl Declarations {
+    /// Returns `true` the first time it is called for `type_name`.
+    /// Lets recursive types stop registering their dependencies.
+    pub fn first_visit(&mut self, type_name: &str) -> bool {
+        self.visited.insert(type_name.to_owned())
+    }
+
+    /// Queues `code` to be written at the top level of the script before the next literal, unless it has already been declared.
+    /// Nested blocks in `code` must be indented with tabs.
+    pub fn declare(&mut self, code: &str) -> &mut Self {
+        if self.declared.insert(code.to_owned()) {
+            self.pending.push(code.to_owned())
+        }
+        self
+    }
+
+    /// Records the definitions written by another program, so that they aren't written again.
+    fn merge(&mut self, other: &Declarations) {
+        self.visited.extend(other.visited.iter().cloned());
+        self.declared.extend(other.declared.iter().cloned());
+    }
+
+    /// Whether `code` has been declared already.
+    fn is_declared(&self, code: &str) -> bool {
+        self.declared.contains(code)
+    }
+
+    fn take_pending(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.pending)
+    }
 }
 
 macro_rules! as_py_lit_impl {
@@ -11,12 +126,103 @@ macro_rules! as_py_lit_impl {
             fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
                 write!(f, $fmt_str, &self)
             }
+
+            fn marshal(
+                &self,
+                out: &mut dyn std::io::Write,
+                _hashable: bool,
+            ) -> std::io::Result<bool> {
+                match <i128 as std::convert::TryFrom<$t>>::try_from(*self) {
+                    Ok(i) => data::write_int(out, i)?,
+                    Err(_) => data::write_uint(out, *self as u128)?,
+                }
+                Ok(true)
+            }
         }
     };
 }
 
-as_py_lit_impl!(str, "\"\"\"{}\"\"\"");
-as_py_lit_impl!(String, "\"\"\"{}\"\"\"");
+/// Writes `s` as a Python string literal, escaped the way `repr` would.
+/// The result round-trips exactly for any valid Unicode input.
+pub(crate) fn write_str_literal(f: &mut Formatter, s: &str) -> Result<(), Error> {
+    use std::fmt::Write;
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    f.write_char(quote)?;
+    for c in s.chars() {
+        match c {
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c == quote => write!(f, "\\{}", c)?,
+            c if (c as u32) < 0x20 || c == '\x7f' => write!(f, "\\x{:02x}", c as u32)?,
+            // Rust's `escape_debug` knows which characters are not printable: escape those too.
+            c if c.escape_debug().nth(1) == Some('u') => match c as u32 {
+                n if n <= 0xff => write!(f, "\\x{:02x}", n)?,
+                n if n <= 0xffff => write!(f, "\\u{:04x}", n)?,
+                n => write!(f, "\\U{:08x}", n)?,
+            },
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char(quote)
+}
+
+/// Writes `bytes` as a Python bytes literal.
+pub(crate) fn write_bytes_literal<W: std::fmt::Write>(
+    out: &mut W,
+    bytes: &[u8],
+) -> std::fmt::Result {
+    out.write_str("b'")?;
+    for &b in bytes {
+        match b {
+            b'\\' => out.write_str("\\\\")?,
+            b'\'' => out.write_str("\\'")?,
+            b'\n' => out.write_str("\\n")?,
+            b'\r' => out.write_str("\\r")?,
+            b'\t' => out.write_str("\\t")?,
+            0x20..=0x7e => out.write_char(b as char)?,
+            _ => write!(out, "\\x{:02x}", b)?,
+        }
+    }
+    out.write_str("'")
+}
+
+impl AsPythonLitteral for str {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write_str_literal(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        data::write_str(out, self).map(|_| true)
+    }
+}
+
+impl AsPythonLitteral for String {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write_str_literal(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        data::write_str(out, self).map(|_| true)
+    }
+}
+
+/// Python has no character type: a `char` becomes a string of length 1.
+impl AsPythonLitteral for char {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write_str_literal(f, self.encode_utf8(&mut [0; 4]))
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        data::write_str(out, self.encode_utf8(&mut [0; 4])).map(|_| true)
+    }
+}
+
 as_py_lit_impl!(u8, "{}");
 as_py_lit_impl!(u16, "{}");
 as_py_lit_impl!(u32, "{}");
@@ -30,61 +236,389 @@ as_py_lit_impl!(i64, "{}");
 as_py_lit_impl!(i128, "{}");
 as_py_lit_impl!(isize, "{}");
 
-impl AsPythonLitteral for f32 {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        if self.is_nan() {
-            write!(f, "float('nan')")
-        } else {
-            write!(f, "{:.6e}", &self)
+/// Floats are written with the shortest representation that round-trips exactly.
+/// If the formatter carries a precision (`{:.3}`), scientific notation with that many decimals is used instead.
+/// `$to_f64` converts them to the python float that their literal reads as.
+macro_rules! as_py_lit_float_impl {
+    ($t: ty, $to_f64: expr) => {
+        impl AsPythonLitteral for $t {
+            fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+                if self.is_nan() {
+                    write!(f, "float('nan')")
+                } else if self.is_infinite() {
+                    if self.is_sign_negative() {
+                        write!(f, "float('-inf')")
+                    } else {
+                        write!(f, "float('inf')")
+                    }
+                } else if let Some(precision) = f.precision() {
+                    write!(f, "{:.*e}", precision, self)
+                } else {
+                    write!(f, "{:?}", self)
+                }
+            }
+
+            fn marshal(
+                &self,
+                out: &mut dyn std::io::Write,
+                _hashable: bool,
+            ) -> std::io::Result<bool> {
+                data::write_float(out, $to_f64(*self)).map(|_| true)
+            }
         }
+    };
+}
+
+// An `f32` reads as the double closest to its shortest representation, not as its exact value.
+as_py_lit_float_impl!(f32, |x: f32| format!("{:?}", x)
+    .parse::<f64>()
+    .expect("floats parse back"));
+as_py_lit_float_impl!(f64, |x: f64| x);
+
+/// Complex numbers are written as `complex(re,im)`, which keeps non-finite parts.
+#[cfg(feature = "num-complex")]
+impl<T: AsPythonLitteral> AsPythonLitteral for num_complex::Complex<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "complex(")?;
+        fmt_unhashable(&self.re, f)?;
+        write!(f, ",")?;
+        fmt_unhashable(&self.im, f)?;
+        write!(f, ")")
     }
 }
 
-impl AsPythonLitteral for f64 {
+impl AsPythonLitteral for bool {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        if self.is_nan() {
-            write!(f, "float('nan')")
-        } else {
-            write!(f, "{:.6e}", &self)
-        }
+        write!(f, "{}", if *self { "True" } else { "False" })
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        data::write_bool(out, *self).map(|_| true)
+    }
+}
+
+/// Formats `x` as an element of a set or a key of a dict, which Python requires to be hashable.
+/// The formatter's alternate flag marks this context: sequences then become tuples and sets frozensets.
+fn fmt_hashable<T: AsPythonLitteral + ?Sized>(x: &T, f: &mut Formatter) -> Result<(), Error> {
+    match f.precision() {
+        Some(precision) => write!(f, "{:#.*}", precision, PythonLiteral(x)),
+        None => write!(f, "{:#}", PythonLiteral(x)),
+    }
+}
+
+/// Formats `x` outside of any hashable context, such as the value of a dict.
+fn fmt_unhashable<T: AsPythonLitteral + ?Sized>(x: &T, f: &mut Formatter) -> Result<(), Error> {
+    match (f.alternate(), f.precision()) {
+        (false, _) => x.fmt(f),
+        (true, Some(precision)) => write!(f, "{:.*}", precision, PythonLiteral(x)),
+        (true, None) => write!(f, "{}", PythonLiteral(x)),
+    }
+}
+
+/// Writes a list, or a tuple when a hashable value is required.
+pub(crate) fn fmt_sequence<T: AsPythonLitteral, I: IntoIterator<Item = T>>(
+    f: &mut Formatter,
+    items: I,
+) -> Result<(), Error> {
+    let (open, close) = if f.alternate() {
+        ("(", ")")
+    } else {
+        ("[", "]")
+    };
+    write!(f, "{}", open)?;
+    for x in items {
+        AsPythonLitteral::fmt(&x, f)?;
+        write!(f, ",")?;
+    }
+    write!(f, "{}", close)
+}
+
+/// Writes a set, or a frozenset when a hashable value is required.
+fn fmt_set<'a, T: AsPythonLitteral + 'a, I: IntoIterator<Item = &'a T>>(
+    f: &mut Formatter,
+    items: I,
+) -> Result<(), Error> {
+    let frozen = f.alternate();
+    let mut items = items.into_iter().peekable();
+    if items.peek().is_none() {
+        return write!(f, "{}", if frozen { "frozenset()" } else { "set()" });
+    }
+    write!(f, "{}", if frozen { "frozenset({" } else { "{" })?;
+    for x in items {
+        fmt_hashable(x, f)?;
+        write!(f, ",")?;
     }
+    write!(f, "{}", if frozen { "})" } else { "}" })
+}
+
+fn fmt_dict<'a, K: AsPythonLitteral + 'a, V: AsPythonLitteral + 'a, I>(
+    f: &mut Formatter,
+    items: I,
+) -> Result<(), Error>
+where
+    I: IntoIterator<Item = (&'a K, &'a V)>,
+{
+    write!(f, "{{")?;
+    for (k, v) in items {
+        fmt_hashable(k, f)?;
+        write!(f, ":")?;
+        fmt_unhashable(v, f)?;
+        write!(f, ",")?;
+    }
+    write!(f, "}}")
 }
 
 impl<T: AsPythonLitteral> AsPythonLitteral for [T] {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "[")?;
-        for x in self.iter() {
-            write!(f, "{},", PythonLiteral(x))?;
-        }
-        write!(f, "]")
+        fmt_sequence(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        data::marshal_sequence(out, self.iter(), hashable)
+    }
+}
+
+impl<T: AsPythonLitteral, const N: usize> AsPythonLitteral for [T; N] {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        fmt_sequence(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        data::marshal_sequence(out, self.iter(), hashable)
     }
 }
 
 impl<T: AsPythonLitteral> AsPythonLitteral for Vec<T> {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "[")?;
-        for x in self.iter() {
-            write!(f, "{},", PythonLiteral(x))?;
-        }
-        write!(f, "]")
+        fmt_sequence(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        data::marshal_sequence(out, self.iter(), hashable)
     }
 }
 
+impl<T: AsPythonLitteral> AsPythonLitteral for std::collections::VecDeque<T> {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        fmt_sequence(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        data::marshal_sequence(out, self.iter(), hashable)
+    }
+}
+
+impl<T: AsPythonLitteral, S> AsPythonLitteral for std::collections::HashSet<T, S> {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        fmt_set(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        data::marshal_set(out, self.iter(), hashable)
+    }
+}
+
+impl<T: AsPythonLitteral> AsPythonLitteral for std::collections::BTreeSet<T> {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        fmt_set(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        data::marshal_set(out, self.iter(), hashable)
+    }
+}
+
+impl<K: AsPythonLitteral, V: AsPythonLitteral, S> AsPythonLitteral
+    for std::collections::HashMap<K, V, S>
+{
+    fn declare(declarations: &mut Declarations) {
+        K::declare(declarations);
+        V::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        fmt_dict(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        data::marshal_dict(out, self.iter())
+    }
+}
+
+/// Python dicts preserve insertion order, so the keys stay sorted.
 impl<K: AsPythonLitteral, V: AsPythonLitteral> AsPythonLitteral
-    for std::collections::HashMap<K, V>
+    for std::collections::BTreeMap<K, V>
 {
+    fn declare(declarations: &mut Declarations) {
+        K::declare(declarations);
+        V::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        fmt_dict(f, self)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        data::marshal_dict(out, self.iter())
+    }
+}
+
+impl<T: AsPythonLitteral> AsPythonLitteral for Option<T> {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Some(x) => x.fmt(f),
+            None => write!(f, "None"),
+        }
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        match self {
+            Some(x) => x.marshal(out, hashable),
+            None => out.write_all(b"N").map(|_| true),
+        }
+    }
+}
+
+impl AsPythonLitteral for () {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{{")?;
-        for (k, v) in self.iter() {
-            write!(f, "{}:{},", PythonLiteral(k), PythonLiteral(v))?;
+        write!(f, "None")
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, _hashable: bool) -> std::io::Result<bool> {
+        out.write_all(b"N").map(|_| true)
+    }
+}
+
+macro_rules! as_py_lit_tuple_impl {
+    ($($t: ident $i: tt),+) => {
+        impl<$($t: AsPythonLitteral),+> AsPythonLitteral for ($($t,)+) {
+            fn declare(declarations: &mut Declarations) {
+                $($t::declare(declarations);)+
+            }
+
+            fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+                write!(f, "(")?;
+                $(
+                    AsPythonLitteral::fmt(&self.$i, f)?;
+                    write!(f, ",")?;
+                )+
+                write!(f, ")")
+            }
+
+            fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+                let len = [$(stringify!($t)),+].len();
+                data::write_sequence_header(out, true, len)?;
+                Ok($(self.$i.marshal(out, hashable)? &&)+ true)
+            }
         }
-        write!(f, "}}")
+    };
+}
+
+as_py_lit_tuple_impl!(A 0);
+as_py_lit_tuple_impl!(A 0, B 1);
+as_py_lit_tuple_impl!(A 0, B 1, C 2);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10);
+as_py_lit_tuple_impl!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7, I 8, J 9, K 10, L 11);
+
+macro_rules! as_py_lit_deref_impl {
+    ($($t: ty),+) => {
+        $(
+            impl<T: AsPythonLitteral + ?Sized> AsPythonLitteral for $t {
+                fn declare(declarations: &mut Declarations) {
+                    T::declare(declarations)
+                }
+
+                fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+                    (**self).fmt(f)
+                }
+
+                fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+                    (**self).marshal(out, hashable)
+                }
+            }
+        )+
+    };
+}
+
+as_py_lit_deref_impl!(&T, Box<T>, std::rc::Rc<T>, std::sync::Arc<T>);
+
+impl<'a, T: AsPythonLitteral + ToOwned + ?Sized> AsPythonLitteral for std::borrow::Cow<'a, T> {
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        (**self).fmt(f)
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        (**self).marshal(out, hashable)
     }
 }
 
+/// A number of tab indentations.
+#[deprecated(
+    note = "always writes tabs: use `IndentUnit::repeat`, which writes the program's indentation unit"
+)]
 #[derive(Copy, Clone, Debug)]
 pub struct Indents(pub isize);
 
+/// The whitespace making up one level of indentation in a program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum IndentUnit {
+    /// One tab per level.
+    #[default]
+    Tab,
+    /// This many spaces per level, usually 4 (as in PEP 8) or 2.
+    Spaces(usize),
+}
+
+impl IndentUnit {
+    /// The whitespace for `level` levels of indentation.
+    pub fn repeat(self, level: usize) -> String {
+        match self {
+            IndentUnit::Tab => "\t".repeat(level),
+            IndentUnit::Spaces(n) => " ".repeat(n * level),
+        }
+    }
+}
+
+#[allow(deprecated)]
 impl std::fmt::Display for Indents {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         for _ in 0..self.0 {
@@ -101,17 +635,39 @@ impl<'l, T: AsPythonLitteral + ?Sized> Display for PythonLiteral<'l, T> {
     }
 }
 
+/// A literal formatted with its program's float precision, if any.
+struct ProgramLiteral<'l, T: AsPythonLitteral + ?Sized>(&'l T, Option<usize>);
+impl<'l, T: AsPythonLitteral + ?Sized> Display for ProgramLiteral<'l, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self.1 {
+            Some(precision) => write!(f, "{:.*}", precision, PythonLiteral(self.0)),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+/// A thread that is joined when the guard is dropped, unless it was detached.
+///
+/// Dropping the guard blocks until the thread finishes, forever if it never does: a guard around
+/// `PythonProgram::background_run` hangs on a script that never ends. Use `PythonProgram::spawn`
+/// for scripts that may have to be killed.
 pub struct JoinGuard<T>(Option<std::thread::JoinHandle<T>>);
 
+impl<T> Default for JoinGuard<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> JoinGuard<T> {
     pub fn new() -> Self {
         JoinGuard(None)
     }
 
-    pub fn spawn<F: FnOnce() -> T>(f: F) -> Self
+    pub fn spawn<F>(f: F) -> Self
     where
         T: Send + 'static,
-        F: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
     {
         JoinGuard(Some(std::thread::spawn(f)))
     }
@@ -131,281 +687,931 @@ impl<T> JoinGuard<T> {
 impl<T> Drop for JoinGuard<T> {
     fn drop(&mut self) {
         if let Some(handle) = self.0.take() {
-            handle.join();
+            let _ = handle.join();
         }
     }
 }
 
+/// Evaluates an expression at the end of a script, and writes `ok` or `error` to a result file,
+/// followed by the `repr` of its value or its traceback.
+/// Values with a `tolist` method (numpy arrays and scalars) are converted first.
+const EVALUATE_EPILOGUE: &str = "
+def __pycall_evaluate(expression, path):
+\timport traceback
+\twith open(path, 'w', encoding='utf-8') as result:
+\t\ttry:
+\t\t\tvalue = eval(expression, globals())
+\t\t\tif hasattr(value, 'tolist'):
+\t\t\t\tvalue = value.tolist()
+\t\t\tresult.write('ok\\n' + repr(value))
+\t\texcept BaseException:
+\t\t\tresult.write('error\\n' + traceback.format_exc())
+";
+
 /// An instance of code generation unit.
-/// It really is just a file with dedicated APIs to write Python into it.
+/// It really is just a string with dedicated APIs to write Python into it.
 /// Most importantly: it manages indentation for you.
+///
+/// The script is kept in memory, and only written to a temporary file when it is run:
+/// see `as_str`, `save_as` and `write_to` to get it otherwise.
+///
+/// Writing never panics: the first error is kept, further writes are skipped,
+/// and the error is returned by `run`, `save_as` and `evaluate`.
+///
+/// Each line remembers which call wrote it, so that python exceptions can point back to the rust code.
 pub struct PythonProgram {
-    file: tempfile::NamedTempFile,
-    indents: Indents,
+    source: String,
+    layout: sections::Layout,
+    error: Option<PycallError>,
+    written_by: Vec<Option<&'static Location<'static>>>,
+    interpreter: Interpreter,
+    data_threshold: Option<usize>,
+    data_files: Vec<std::sync::Arc<tempfile::TempPath>>,
+    indents: isize,
+    indent_unit: IndentUnit,
+    /// The start of a character split across raw writes.
+    partial: Vec<u8>,
+    blocks: Vec<blocks::Block>,
+    /// The block closed last and its indentation level, until anything else is written: what an `elif` or `else` may follow.
+    closed: Option<(isize, BlockKind)>,
+    /// The last call to `indent`, which left the indentation unbalanced if it doesn't match the open blocks.
+    indented_by: Option<&'static Location<'static>>,
+    float_precision: Option<usize>,
+    declarations: Declarations,
+    names: names::Names,
+    imports: imports::Imports,
+}
+impl Default for PythonProgram {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl PythonProgram {
-    /// Creates a named temp file to store the generated python program
+    /// Creates an empty program.
     pub fn new() -> PythonProgram {
         PythonProgram {
-            file: tempfile::NamedTempFile::new().unwrap(),
-            indents: Indents(0),
+            source: String::new(),
+            layout: sections::Layout::default(),
+            error: None,
+            written_by: Vec::new(),
+            interpreter: Interpreter::default(),
+            data_threshold: None,
+            data_files: Vec::new(),
+            indents: 0,
+            indent_unit: IndentUnit::Tab,
+            partial: Vec::new(),
+            blocks: Vec::new(),
+            closed: None,
+            indented_by: None,
+            float_precision: None,
+            declarations: Declarations::default(),
+            names: names::Names::default(),
+            imports: imports::Imports::default(),
+        }
+    }
+
+    /// Creates an empty program. Programs no longer touch the disk until they are run, so this never fails.
+    pub fn try_new() -> Result<PythonProgram, PycallError> {
+        Ok(PythonProgram::new())
+    }
+
+    /// The first error met while writing this program, if any.
+    pub fn error(&self) -> Option<&PycallError> {
+        self.error.as_ref()
+    }
+
+    /// Limits floats written by this program to `precision` decimals in scientific notation.
+    /// By default (`None`), floats are written losslessly; a precision trades exactness for smaller scripts.
+    pub fn float_precision(&mut self, precision: Option<usize>) -> &mut Self {
+        self.float_precision = precision;
+        self
+    }
+
+    /// Sets the whitespace written for each indentation level, tabs by default.
+    /// Declarations are reindented to match. Set it before writing any indented code, as python rejects mixed indentation.
+    pub fn indent_unit(&mut self, unit: IndentUnit) -> &mut Self {
+        self.indent_unit = unit;
+        self
+    }
+
+    /// The current indentation level.
+    pub fn indent_level(&self) -> usize {
+        self.indents as usize
+    }
+
+    /// The whitespace starting lines at the current indentation level.
+    pub(crate) fn indentation(&self) -> String {
+        self.indent_unit.repeat(self.indent_level())
+    }
+
+    /// Sets the interpreter that runs this program, `Interpreter::discover()` by default.
+    pub fn interpreter(&mut self, interpreter: Interpreter) -> &mut Self {
+        self.interpreter = interpreter;
+        self
+    }
+
+    /// Values whose literals are longer than `threshold` bytes are written to side files that the script loads,
+    /// instead of being inlined in its source. Side files are deleted along with the program,
+    /// so scripts copied with `save_as` or `write_to` only work while it lives.
+    /// `None`, the default, always inlines values, which keeps saved scripts self-contained.
+    pub fn data_threshold(&mut self, threshold: Option<usize>) -> &mut Self {
+        self.data_threshold = threshold;
+        self
+    }
+
+    fn literal<'l, T: AsPythonLitteral + ?Sized>(&self, value: &'l T) -> ProgramLiteral<'l, T> {
+        ProgramLiteral(value, self.float_precision)
+    }
+
+    /// The script written so far, imports included, even if an error interrupted it.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// The script, or the error that interrupted it.
+    pub(crate) fn script(&self) -> Result<&str, PycallError> {
+        match &self.error {
+            Some(e) => Err(e.duplicate()),
+            None => Ok(&self.source),
         }
     }
 
-    pub fn save_as<P: AsRef<std::path::Path>>(&self, path: P) -> Result<u64, std::io::Error> {
-        std::fs::copy(self.file.path(), path)
+    /// Appends `text` to the current section, unless an error occurred earlier.
+    /// The lines it completes are attributed to `written_by`.
+    fn write_raw(
+        &mut self,
+        text: &str,
+        written_by: Option<&'static Location<'static>>,
+    ) -> &mut Self {
+        if self.error.is_none() {
+            self.insert_raw(self.layout.cursor, text, std::iter::repeat(written_by));
+        }
+        self
+    }
+
+    /// The location of the rust call that wrote the 1-based `line` of the script.
+    /// `None` for lines written through `std::io::Write`, or past the end of the script.
+    pub fn written_by(&self, line: usize) -> Option<&'static Location<'static>> {
+        let index = line.checked_sub(1)?;
+        self.written_by.get(index).copied().flatten()
+    }
+
+    /// Points the frames of python exceptions raised by `script` back to the calls that wrote them.
+    fn locate(&self, script: &std::path::Path, error: PycallError) -> PycallError {
+        match error {
+            PycallError::PythonException {
+                mut exception,
+                output,
+            } => {
+                exception.locate(script, |line| self.written_by(line));
+                PycallError::PythonException { exception, output }
+            }
+            PycallError::ExpressionFailed {
+                mut exception,
+                output,
+            } => {
+                exception
+                    .traceback
+                    .retain(|frame| frame.function != "__pycall_evaluate");
+                exception.locate(script, |line| self.written_by(line));
+                PycallError::ExpressionFailed { exception, output }
+            }
+            error => error,
+        }
+    }
+
+    pub fn save_as<P: AsRef<std::path::Path>>(&self, path: P) -> Result<u64, PycallError> {
+        self.check_blocks()?;
+        let script = self.script()?;
+        std::fs::write(path, script).map_err(PycallError::Write)?;
+        Ok(script.len() as u64)
+    }
+
+    /// Writes the script to `writer`.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), PycallError> {
+        self.check_blocks()?;
+        writer
+            .write_all(self.script()?.as_bytes())
+            .and_then(|_| writer.flush())
+            .map_err(PycallError::Write)
     }
 
-    /// Runs the program using python3
-    pub fn run(&self) -> Result<std::process::Output, std::io::Error> {
-        std::process::Command::new("python3")
-            .arg(self.file.path())
-            .output()
+    /// Runs the program using its interpreter.
+    /// A script that fails still returns its `Output`: see `run_checked` to turn that into an error.
+    pub fn run(&self) -> Result<std::process::Output, PycallError> {
+        self.check_blocks()?;
+        self.interpreter.run(self.snapshot()?.path())
     }
 
-    /// Spawns a thread to run the program using python3.
-    /// The returned JoinGuard ensures that the program will be ran to completion.
-    pub fn background_run(self) -> JoinGuard<Result<std::process::Output, std::io::Error>> {
+    /// Runs the program using its interpreter, failing if the script does.
+    /// Python exceptions are parsed, and their frames mapped back to the calls that wrote the script.
+    pub fn run_checked(&self) -> Result<std::process::Output, PycallError> {
+        self.check_blocks()?;
+        let script = self.snapshot()?;
+        let path = script.path();
+        PycallError::check_output(self.interpreter.run(path)?).map_err(|e| self.locate(path, e))
+    }
+
+    /// Runs the program using its interpreter, killing it if it takes longer than `timeout`.
+    /// A script that fails still returns its `Output`, but one that times out returns `PycallError::Timeout`.
+    /// Subprocesses that keep its output open count towards the timeout, and are killed along with it.
+    pub fn run_with_timeout(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<std::process::Output, PycallError> {
+        self.spawn()?.wait_with_output_timeout(timeout)
+    }
+
+    /// Starts the program in the background, returning a handle to wait for it or kill it.
+    /// The handle runs a copy of the script, so the program can keep being written meanwhile.
+    pub fn spawn(&self) -> Result<RunningProgram, PycallError> {
+        self.spawn_with(&SpawnOptions::default())
+    }
+
+    /// Starts the program in the background, following its output as specified by `options`.
+    pub fn spawn_with(&self, options: &SpawnOptions) -> Result<RunningProgram, PycallError> {
+        self.check_blocks()?;
+        RunningProgram::spawn(
+            &self.interpreter,
+            self.snapshot()?,
+            self.data_files.clone(),
+            options,
+        )
+    }
+
+    /// Runs the program using its interpreter, calling `f` on each line of output as soon as it is written.
+    /// The full output is still returned once the program finishes.
+    pub fn run_streaming<F: FnMut(&OutputLine)>(
+        &self,
+        f: F,
+    ) -> Result<std::process::Output, PycallError> {
+        self.spawn_with(SpawnOptions::new().lines(true))?
+            .for_each_line(f)
+    }
+
+    /// Writes the script to a new temporary file.
+    fn snapshot(&self) -> Result<tempfile::NamedTempFile, PycallError> {
+        let source = self.script()?;
+        let mut script = tempfile::NamedTempFile::new().map_err(PycallError::TempFile)?;
+        script
+            .write_all(source.as_bytes())
+            .and_then(|_| script.flush())
+            .map_err(PycallError::Write)?;
+        Ok(script)
+    }
+
+    /// Runs the program, then evaluates `expression` and decodes its value into `T`.
+    /// The value is passed back through a separate file, so whatever the script prints doesn't interfere.
+    /// The program itself is left untouched: the declarations `expression` needs are only added to the evaluated copy.
+    pub fn evaluate<T: FromPythonLiteral>(
+        &self,
+        expression: &(impl AsPythonSource + ?Sized),
+    ) -> Result<T, PycallError> {
+        self.check_blocks()?;
+        // The declarations land where the program would write them, in a copy of it.
+        let mut program = self.clone();
+        let expression = program.source(expression);
+        let mut script = program.snapshot()?;
+        let result = tempfile::NamedTempFile::new().map_err(PycallError::TempFile)?;
+        script
+            .write_all(EVALUATE_EPILOGUE.as_bytes())
+            .and_then(|_| {
+                writeln!(
+                    script,
+                    "__pycall_evaluate({}, {})",
+                    PythonLiteral(&*expression),
+                    PythonLiteral(&*result.path().to_string_lossy())
+                )
+            })
+            .and_then(|_| script.flush())
+            .map_err(PycallError::Write)?;
+        let output = self.interpreter.run(script.path())?;
+        let report = std::fs::read_to_string(result.path()).map_err(PycallError::Io)?;
+        let error = if let Some(value) = report.strip_prefix("ok\n") {
+            return Ok(T::from_python_literal(value)?);
+        } else if let Some(traceback) = report.strip_prefix("error\n") {
+            PycallError::ExpressionFailed {
+                exception: Box::new(
+                    PythonException::parse(traceback)
+                        .unwrap_or_else(|| PythonException::unparsed(traceback)),
+                ),
+                output,
+            }
+        } else {
+            match PycallError::check_output(output) {
+                Err(e) => e,
+                // The script exited successfully, but before reaching the expression.
+                Ok(output) => PycallError::NonZeroExit(output),
+            }
+        };
+        Err(program.locate(script.path(), error))
+    }
+
+    /// Spawns a thread to run the program using its interpreter.
+    /// The returned JoinGuard ensures that the program will be ran to completion, dropping it blocking until then:
+    /// use `spawn` or `run_with_timeout` for programs that may never finish.
+    pub fn background_run(self) -> JoinGuard<Result<std::process::Output, PycallError>> {
         JoinGuard::spawn(move || self.run())
     }
 
-    /// Ensures that the internal file has been flushed. Typically not necessary.
+    /// Does nothing: programs are kept in memory, and written out whole when they are used.
     pub fn flush(&mut self) -> &mut Self {
-        self.file.flush().unwrap();
         self
     }
 
-    /// Moves the indentation level by `n`. However, I recommend using the dedicated functions when possible/
+    /// Moves the indentation level by `n`. However, I recommend using the dedicated functions when possible.
+    /// Dedenting past the first column stops there, and fails the program with `PycallError::Dedent`.
+    #[track_caller]
     pub fn indent(&mut self, n: isize) -> &mut Self {
-        if n >= 0 {
-            self.indents.0 += n as isize
-        } else {
-            self.indents.0 -= n as isize
+        self.indents += n;
+        self.indented_by = Some(Location::caller());
+        if self.indents < 0 {
+            self.indents = 0;
+            if self.error.is_none() {
+                self.error = Some(PycallError::Dedent(Location::caller()));
+            }
         }
         self
     }
 
-    /// Removes one indentation level from the cursor.
-    /// You should call this whenever you're done with a scope.
+    /// Closes the innermost block opened by `r#if`, `r#for`... writing `pass` in it if it is empty.
+    /// Without such a block, removes one indentation level from the cursor.
+    /// You should call this whenever you're done with a scope, or use the closure and guard forms such as `if_`.
+    #[track_caller]
     pub fn end_block(&mut self) -> &mut Self {
-        self.indent(-1)
+        if !self.close_block() {
+            self.indent(-1);
+        }
+        self
     }
 
-    /// Writes a line assigning `value` formatted as a python literal to `name`
-    pub fn define_variable<T: AsPythonLitteral + ?Sized>(
+    /// Writes a line assigning `value` formatted as a python literal to `name`, and returns the variable,
+    /// to refer to it in later calls and `Expr`s.
+    /// `name` may be a `Var`, to reassign it. Keywords, invalid identifiers and imported names fail the program
+    /// with `PycallError::InvalidName`: see `fresh_name` to get a name that is free.
+    #[track_caller]
+    pub fn define_variable<T: AsPythonLitteral + ?Sized, N: AsRef<str> + ?Sized>(
         &mut self,
-        name: &str,
+        name: &N,
         value: &T,
+    ) -> Var {
+        let var = self.bind_variable(name.as_ref());
+        self.declare::<T>();
+        self.define_value(&var, value, false);
+        var
+    }
+
+    /// Writes a line assigning `value` to `name`, loading it from a side file regardless of its size,
+    /// and returns the variable like `define_variable`.
+    #[track_caller]
+    pub fn define_data<T: AsPythonLitteral + ?Sized, N: AsRef<str> + ?Sized>(
+        &mut self,
+        name: &N,
+        value: &T,
+    ) -> Var {
+        let var = self.bind_variable(name.as_ref());
+        self.declare::<T>();
+        self.define_value(&var, value, true);
+        var
+    }
+
+    /// Assigns `value` to `name`, from a side file if `side_file` or if its literal is over the data threshold.
+    #[track_caller]
+    fn define_value<T: AsPythonLitteral + ?Sized>(
+        &mut self,
+        name: &Var,
+        value: &T,
+        side_file: bool,
+    ) -> &mut Self {
+        let literal = if side_file {
+            None
+        } else {
+            let literal = self.literal(value).to_string();
+            if !matches!(self.data_threshold, Some(threshold) if literal.len() > threshold) {
+                return self.write_line(&format!("{} = {}", name, literal));
+            }
+            Some(literal)
+        };
+        // Values are marshalled straight from rust, unless their floats must be rounded.
+        if self.float_precision.is_none() {
+            if let Some(file) = self.data_file(".pycall", |file| value.marshal(file, false)) {
+                return self.load_data(name, "__pycall_load", data::MARSHAL_LOADER, &file);
+            }
+            if self.error.is_some() {
+                return self;
+            }
+        }
+        let literal = literal.unwrap_or_else(|| self.literal(value).to_string());
+        self.define_literal(name, literal, true)
+    }
+
+    #[track_caller]
+    fn define_literal(&mut self, name: &Var, literal: String, side_file: bool) -> &mut Self {
+        let side_file = side_file
+            || matches!(self.data_threshold, Some(threshold) if literal.len() > threshold);
+        if !side_file {
+            return self.write_line(&format!("{} = {}", name, literal));
+        }
+        // Plain literals are marshalled, others are evaluated from source.
+        let (loader, code, file) = match parse_python_literal(&literal) {
+            Ok(value) => (
+                "__pycall_load",
+                data::MARSHAL_LOADER,
+                self.data_file(".pycall", |file| value.marshal(file, false)),
+            ),
+            Err(_) => (
+                "__pycall_eval",
+                data::SOURCE_LOADER,
+                self.data_file(".pycall", |file| {
+                    file.write_all(literal.as_bytes()).map(|_| true)
+                }),
+            ),
+        };
+        match file {
+            Some(file) => self.load_data(name, loader, code, &file),
+            None => self,
+        }
+    }
+
+    /// Assigns to `name` the value that `loader`, defined by `code`, reads from `file`.
+    #[track_caller]
+    fn load_data(
+        &mut self,
+        name: &Var,
+        loader: &str,
+        code: &str,
+        file: &std::path::Path,
     ) -> &mut Self {
-        writeln!(
-            &mut self.file,
-            "{}{} = {}",
-            self.indents,
+        self.declarations.declare(code);
+        self.write_pending();
+        self.write_line(&format!(
+            "{} = {}({})",
             name,
-            PythonLiteral(value)
-        )
-        .unwrap();
-        self
+            loader,
+            PythonLiteral(&*file.to_string_lossy())
+        ))
     }
 
-    /// Writes an import statement for your `dependency`
-    pub fn import(&mut self, dependency: &str) -> &mut Self {
-        writeln!(&mut self.file, "{}import {}", self.indents, dependency).unwrap();
-        self
+    /// Writes a line assigning a numpy array to `name`, loaded from a `.npy` side file which keeps `T`'s exact dtype.
+    /// `data` holds the elements in row-major order, and must fit `shape`. Returns the variable like `define_variable`.
+    #[track_caller]
+    pub fn define_ndarray<T: NpyElement, N: AsRef<str> + ?Sized>(
+        &mut self,
+        name: &N,
+        data: &[T],
+        shape: &[usize],
+    ) -> Var {
+        self.define_ndarray_ordered(name, data, shape, Order::C)
     }
 
-    /// Writes an import statement for your `dependency` as `rename`
-    pub fn import_as(&mut self, dependency: &str, rename: &str) -> &mut Self {
-        writeln!(
-            &mut self.file,
-            "{}import {} as {}",
-            self.indents, dependency, rename
-        )
-        .unwrap();
+    /// Writes a line assigning a numpy array to `name`, whose elements are laid out in `data` following `order`.
+    #[track_caller]
+    pub fn define_ndarray_ordered<T: NpyElement, N: AsRef<str> + ?Sized>(
+        &mut self,
+        name: &N,
+        data: &[T],
+        shape: &[usize],
+        order: Order,
+    ) -> Var {
+        self.define_npy(name.as_ref(), |file| write_npy(file, data, shape, order))
+    }
+
+    /// Writes a line assigning a numpy array to `name`, with the shape, dtype and memory order of `array`.
+    #[track_caller]
+    pub fn define_array<A: NpyArray + ?Sized, N: AsRef<str> + ?Sized>(
+        &mut self,
+        name: &N,
+        array: &A,
+    ) -> Var {
+        self.define_npy(name.as_ref(), |file| array.write_npy(file))
+    }
+
+    #[track_caller]
+    fn define_npy<F>(&mut self, name: &str, write: F) -> Var
+    where
+        F: FnOnce(&mut std::io::BufWriter<std::fs::File>) -> std::io::Result<()>,
+    {
+        let var = self.bind_variable(name);
+        let file = match self.data_file(".npy", |file| write(file).map(|_| true)) {
+            Some(file) => file,
+            None => return var,
+        };
+        self.declarations.declare("import numpy as np");
+        self.write_pending();
+        self.write_line(&format!(
+            "{} = np.load({})",
+            var,
+            PythonLiteral(&*file.to_string_lossy())
+        ));
+        var
+    }
+
+    /// Creates a side file filled by `write`, which lives as long as the program and the scripts it spawns.
+    /// Returns its path, or `None` if an error was recorded or `write` returned `false` to discard the file.
+    fn data_file<F>(&mut self, suffix: &str, write: F) -> Option<std::path::PathBuf>
+    where
+        F: FnOnce(&mut std::io::BufWriter<std::fs::File>) -> std::io::Result<bool>,
+    {
+        if self.error.is_some() {
+            return None;
+        }
+        let file = match tempfile::Builder::new().suffix(suffix).tempfile() {
+            Ok(file) => file,
+            Err(e) => {
+                self.error = Some(PycallError::TempFile(e));
+                return None;
+            }
+        };
+        let (file, path) = file.into_parts();
+        let mut file = std::io::BufWriter::new(file);
+        match write(&mut file).and_then(|keep| file.flush().map(|_| keep)) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                self.error = Some(PycallError::Write(e));
+                return None;
+            }
+        }
+        let path_buf = path.to_path_buf();
+        self.data_files.push(std::sync::Arc::new(path));
+        Some(path_buf)
+    }
+
+    /// Writes a line assigning `value`, serialized as a python literal, to `name`, and returns the variable like `define_variable`.
+    #[cfg(feature = "serde")]
+    #[track_caller]
+    pub fn define_serialized<T: serde::Serialize + ?Sized, N: AsRef<str> + ?Sized>(
+        &mut self,
+        name: &N,
+        value: &T,
+    ) -> Result<Var, SerializeError> {
+        let literal = ser::to_python_literal_with_precision(value, self.float_precision)?;
+        let var = self.bind_variable(name.as_ref());
+        self.define_literal(&var, literal, false);
+        Ok(var)
+    }
+
+    /// Writes the definitions that `T`'s literals rely on, unless this program already has them.
+    /// Methods taking literals already do this for you.
+    #[track_caller]
+    pub fn declare<T: AsPythonLitteral + ?Sized>(&mut self) -> &mut Self {
+        T::declare(&mut self.declarations);
+        self.write_pending()
+    }
+
+    /// Writes the pending declarations at the top level, so that they are visible from the whole script:
+    /// imports are hoisted, and other definitions go to the end of `Section::Data`,
+    /// or of `Section::Imports` while an earlier section is written.
+    #[track_caller]
+    fn write_pending(&mut self) -> &mut Self {
+        for code in self.declarations.take_pending() {
+            if self.hoist_import(&code) {
+                continue;
+            }
+            self.names.bind_statements(&code);
+            let section = if self.current_section() <= Section::Data {
+                Section::Imports
+            } else {
+                Section::Data
+            };
+            let cursor = self.current_section();
+            let indents = std::mem::replace(&mut self.indents, 0);
+            let blocks = std::mem::take(&mut self.blocks);
+            self.section(section).write_snippet(&code).section(cursor);
+            self.indents = indents;
+            self.blocks = blocks;
+        }
         self
     }
 
+    /// The source of `code`, after writing the declarations it needs.
+    #[track_caller]
+    fn source<C: AsPythonSource + ?Sized>(&mut self, code: &C) -> String {
+        code.declare(&mut self.declarations);
+        self.write_pending();
+        code.source().into_owned()
+    }
+
     /// Writes whatever line you passed it, indented at the proper level.
-    pub fn write_line(&mut self, line: &str) -> &mut Self {
-        writeln!(&mut self.file, "{}{}", self.indents, line).unwrap();
-        self
+    #[track_caller]
+    pub fn write_line<C: AsPythonSource + ?Sized>(&mut self, line: &C) -> &mut Self {
+        let line = self.source(line);
+        if let Some(block) = self.blocks.last_mut() {
+            let code = line.trim_start();
+            if !code.is_empty() && !code.starts_with('#') {
+                block.statements += 1;
+            }
+        }
+        let line = format!("{}{}\n", self.indentation(), line);
+        self.write_raw(&line, Some(Location::caller()))
     }
 
     /// Writes an if, using your condition as a test, and increments indentation.
-    pub fn r#if(&mut self, condition: &str) -> &mut Self {
-        writeln!(&mut self.file, "{}if {}:", self.indents, condition).unwrap();
-        self.indent(1)
+    #[track_caller]
+    pub fn r#if<C: AsPythonSource + ?Sized>(&mut self, condition: &C) -> &mut Self {
+        let condition = self.source(condition);
+        self.open_block(BlockKind::If, &format!("if {}:", condition))
     }
-    /// Decrements indentation, writes an elif, using your condition as a test, and increments indentation.
-    pub fn elif(&mut self, condition: &str) -> &mut Self {
-        self.indent(-1);
-        writeln!(&mut self.file, "{}elif {}:", self.indents, condition).unwrap();
-        self.indent(1)
+    /// Closes the current block, writes an elif, using your condition as a test, and increments indentation.
+    /// The closed block must be an `r#if` or `elif`, or the program fails with `PycallError::MisplacedBlock`.
+    #[track_caller]
+    pub fn elif<C: AsPythonSource + ?Sized>(&mut self, condition: &C) -> &mut Self {
+        let condition = self.source(condition);
+        self.end_block().check_follows(BlockKind::Elif);
+        self.open_block(BlockKind::Elif, &format!("elif {}:", condition))
     }
-    /// Decrements indentation, writes an else, using your condition as a test, and increments indentation.
+    /// Closes the current block, writes an else, using your condition as a test, and increments indentation.
+    /// The closed block must be an `r#if`, `elif`, `r#for`, `r#while` or `except`, or the program fails with `PycallError::MisplacedBlock`.
+    #[track_caller]
     pub fn r#else(&mut self) -> &mut Self {
-        self.indent(-1).write_line("else:").indent(1)
+        self.end_block().check_follows(BlockKind::Else);
+        self.open_block(BlockKind::Else, "else:")
     }
 
     /// Writes "for `range`:", and increments indentation.
-    pub fn r#for(&mut self, range: &str) -> &mut Self {
-        writeln!(&mut self.file, "{}for {}:", self.indents, range).unwrap();
-        self.indent(1)
+    #[track_caller]
+    pub fn r#for<C: AsPythonSource + ?Sized>(&mut self, range: &C) -> &mut Self {
+        let range = self.source(range);
+        self.open_block(BlockKind::For, &format!("for {}:", range))
     }
 
     /// Writes a while, using your condition as a test, and increments indentation.
-    pub fn r#while(&mut self, condition: &str) -> &mut Self {
-        writeln!(&mut self.file, "{}while {}:", self.indents, condition).unwrap();
-        self.indent(1)
+    #[track_caller]
+    pub fn r#while<C: AsPythonSource + ?Sized>(&mut self, condition: &C) -> &mut Self {
+        let condition = self.source(condition);
+        self.open_block(BlockKind::While, &format!("while {}:", condition))
     }
 }
 
+/// Appends raw code to the current section, which must be UTF-8.
+/// A character split across writes is taken along with its end.
 impl Write for PythonProgram {
+    /// Writes raw code at the end of the current section. Characters may be split across writes.
+    /// Writes are ignored once the program has failed, like those of its other writers.
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        self.file.write(buf)
+        if self.error.is_some() {
+            return Ok(buf.len());
+        }
+        let pending = self.partial.len();
+        let mut bytes = std::mem::take(&mut self.partial);
+        bytes.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&bytes) {
+            Ok(_) => bytes.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                bytes.truncate(pending);
+                self.partial = bytes;
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+            }
+        };
+        self.partial = bytes.split_off(valid);
+        let text = std::str::from_utf8(&bytes).expect("checked by from_utf8");
+        self.insert_raw(self.layout.cursor, text, std::iter::repeat(None));
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> Result<(), std::io::Error> {
-        self.file.flush()
+        Ok(())
     }
 }
 
+/// The script written so far, followed by a comment giving the error that makes it unusable, if any:
+/// the error met while writing it, a block left open or unbalanced indentation.
 impl Display for PythonProgram {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        use std::io::BufRead;
-        let read_file = std::fs::File::open(self.file.path()).unwrap();
-        let reader = std::io::BufReader::new(read_file);
-        for line in reader.lines() {
-            writeln!(f, "{}", line.unwrap())?
+        f.write_str(&self.source)?;
+        match self.check_blocks() {
+            Ok(()) => Ok(()),
+            Err(e) => writeln!(f, "# error: {}", e.to_string().replace('\n', "\n# ")),
+        }
+    }
+}
+
+/// Copies the script and the state needed to keep writing it. Side files are shared, not copied.
+impl Clone for PythonProgram {
+    fn clone(&self) -> Self {
+        PythonProgram {
+            source: self.source.clone(),
+            layout: self.layout.clone(),
+            error: self.error.as_ref().map(PycallError::duplicate),
+            written_by: self.written_by.clone(),
+            interpreter: self.interpreter.clone(),
+            data_threshold: self.data_threshold,
+            data_files: self.data_files.clone(),
+            indents: self.indents,
+            indent_unit: self.indent_unit,
+            partial: self.partial.clone(),
+            blocks: self.blocks.clone(),
+            closed: self.closed,
+            indented_by: self.indented_by,
+            float_precision: self.float_precision,
+            declarations: self.declarations.clone(),
+            names: self.names.clone(),
+            imports: self.imports.clone(),
         }
-        Ok(())
     }
 }
 
+/// Programs are equal when they write the same script.
+impl PartialEq for PythonProgram {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+/// Plotting with `matplotlib.pyplot`, imported as `plt` by the first plotting call.
 pub trait MatPlotLib {
     fn import_pyplot_as_plt(&mut self) -> &mut Self;
     fn plot_y<Y: AsPythonLitteral>(&mut self, y: &Y) -> &mut Self;
     fn plot_xy<X: AsPythonLitteral, Y: AsPythonLitteral>(&mut self, x: &X, y: &Y) -> &mut Self;
-    fn plot_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral>(
+    fn plot_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral, A: AsPythonSource + ?Sized>(
         &mut self,
         x: &X,
         y: &Y,
-        args: &str,
+        args: &A,
     ) -> &mut Self;
     fn semilogy_y<Y: AsPythonLitteral>(&mut self, y: &Y) -> &mut Self;
     fn semilogy_xy<X: AsPythonLitteral, Y: AsPythonLitteral>(&mut self, x: &X, y: &Y) -> &mut Self;
-    fn semilogy_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral>(
+    fn semilogy_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral, A: AsPythonSource + ?Sized>(
         &mut self,
         x: &X,
         y: &Y,
-        args: &str,
+        args: &A,
     ) -> &mut Self;
     fn show(&mut self) -> &mut Self;
 }
 
 impl MatPlotLib for PythonProgram {
+    #[track_caller]
     fn import_pyplot_as_plt(&mut self) -> &mut Self {
         self.import_as("matplotlib.pyplot", "plt")
     }
 
+    #[track_caller]
     fn plot_y<Y: AsPythonLitteral>(&mut self, y: &Y) -> &mut Self {
-        self.write_line(&format!("plt.plot({})", PythonLiteral(y)))
+        self.import_pyplot_as_plt();
+        self.declare::<Y>();
+        self.write_line(&format!("plt.plot({})", self.literal(y)))
     }
 
+    #[track_caller]
     fn plot_xy<X: AsPythonLitteral, Y: AsPythonLitteral>(&mut self, x: &X, y: &Y) -> &mut Self {
+        self.import_pyplot_as_plt();
+        self.declare::<X>().declare::<Y>();
         self.write_line(&format!(
             "plt.plot({},{})",
-            PythonLiteral(x),
-            PythonLiteral(y)
+            self.literal(x),
+            self.literal(y)
         ))
     }
 
-    fn plot_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral>(
+    #[track_caller]
+    fn plot_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral, A: AsPythonSource + ?Sized>(
         &mut self,
         x: &X,
         y: &Y,
-        args: &str,
+        args: &A,
     ) -> &mut Self {
+        self.import_pyplot_as_plt();
+        self.declare::<X>().declare::<Y>();
+        let args = self.source(args);
         self.write_line(&format!(
             "plt.plot({},{},{})",
-            PythonLiteral(x),
-            PythonLiteral(y),
+            self.literal(x),
+            self.literal(y),
             args
         ))
     }
 
+    #[track_caller]
     fn semilogy_y<Y: AsPythonLitteral>(&mut self, y: &Y) -> &mut Self {
-        self.write_line(&format!("plt.semilogy({})", PythonLiteral(y)))
+        self.import_pyplot_as_plt();
+        self.declare::<Y>();
+        self.write_line(&format!("plt.semilogy({})", self.literal(y)))
     }
 
+    #[track_caller]
     fn semilogy_xy<X: AsPythonLitteral, Y: AsPythonLitteral>(&mut self, x: &X, y: &Y) -> &mut Self {
+        self.import_pyplot_as_plt();
+        self.declare::<X>().declare::<Y>();
         self.write_line(&format!(
             "plt.semilogy({},{})",
-            PythonLiteral(x),
-            PythonLiteral(y)
+            self.literal(x),
+            self.literal(y)
         ))
     }
 
-    fn semilogy_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral>(
+    #[track_caller]
+    fn semilogy_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral, A: AsPythonSource + ?Sized>(
         &mut self,
         x: &X,
         y: &Y,
-        args: &str,
+        args: &A,
     ) -> &mut Self {
+        self.import_pyplot_as_plt();
+        self.declare::<X>().declare::<Y>();
+        let args = self.source(args);
         self.write_line(&format!(
             "plt.semilogy({},{},{})",
-            PythonLiteral(x),
-            PythonLiteral(y),
+            self.literal(x),
+            self.literal(y),
             args
         ))
     }
 
+    #[track_caller]
     fn show(&mut self) -> &mut Self {
+        self.import_pyplot_as_plt();
         self.write_line("plt.show()")
     }
 }
 
+/// One-shot plots, each in its own python process started with `Interpreter::discover()`.
 pub mod plots {
-    use crate::{AsPythonLitteral, PythonLiteral, PythonProgram};
-    use std::io::Write;
+    use crate::{AsPythonLitteral, Interpreter, PycallError, PythonLiteral, PythonProgram};
 
+    #[track_caller]
     pub fn plot_xyargs<X: AsPythonLitteral, Y: AsPythonLitteral>(
         x: &X,
         y: &Y,
         args: &str,
-    ) -> Result<std::process::Output, std::io::Error> {
-        let mut program = PythonProgram::new();
-        program.import_as("matplotlib.pyplot", "plt");
-        writeln!(
-            &program.file,
-            "plt.plot({}, {}, {})",
-            PythonLiteral(x),
-            PythonLiteral(y),
-            PythonLiteral(args)
-        );
-        program.write_line("plt.show()").run()
+    ) -> Result<std::process::Output, PycallError> {
+        plot_xyargs_with(&Interpreter::discover(), x, y, args)
     }
 
+    /// Like `plot_xyargs`, run by `interpreter`.
+    #[track_caller]
+    pub fn plot_xyargs_with<X: AsPythonLitteral, Y: AsPythonLitteral>(
+        interpreter: &Interpreter,
+        x: &X,
+        y: &Y,
+        args: &str,
+    ) -> Result<std::process::Output, PycallError> {
+        let mut program = PythonProgram::try_new()?;
+        program
+            .interpreter(interpreter.clone())
+            .import_as("matplotlib.pyplot", "plt")
+            .declare::<X>()
+            .declare::<Y>()
+            .write_line(&format!(
+                "plt.plot({}, {}, {})",
+                PythonLiteral(x),
+                PythonLiteral(y),
+                PythonLiteral(args)
+            ))
+            .write_line("plt.show()")
+            .run()
+    }
+
+    #[track_caller]
     pub fn plot_xy<X: AsPythonLitteral, Y: AsPythonLitteral>(
         x: &X,
         y: &Y,
-    ) -> Result<std::process::Output, std::io::Error> {
-        let mut program = PythonProgram::new();
-        program.import_as("matplotlib.pyplot", "plt");
-        writeln!(
-            &program.file,
-            "plt.plot({}, {})",
-            PythonLiteral(x),
-            PythonLiteral(y),
-        );
-        program.write_line("plt.show()").run()
+    ) -> Result<std::process::Output, PycallError> {
+        plot_xy_with(&Interpreter::discover(), x, y)
     }
 
-    pub fn plot_y<Y: AsPythonLitteral>(y: &Y) -> Result<std::process::Output, std::io::Error> {
-        let mut program = PythonProgram::new();
-        program.import_as("matplotlib.pyplot", "plt");
-        writeln!(&program.file, "plt.plot({})", PythonLiteral(y));
-        program.write_line("plt.show()").run()
+    /// Like `plot_xy`, run by `interpreter`.
+    #[track_caller]
+    pub fn plot_xy_with<X: AsPythonLitteral, Y: AsPythonLitteral>(
+        interpreter: &Interpreter,
+        x: &X,
+        y: &Y,
+    ) -> Result<std::process::Output, PycallError> {
+        let mut program = PythonProgram::try_new()?;
+        program
+            .interpreter(interpreter.clone())
+            .import_as("matplotlib.pyplot", "plt")
+            .declare::<X>()
+            .declare::<Y>()
+            .write_line(&format!(
+                "plt.plot({}, {})",
+                PythonLiteral(x),
+                PythonLiteral(y)
+            ))
+            .write_line("plt.show()")
+            .run()
+    }
+
+    #[track_caller]
+    pub fn plot_y<Y: AsPythonLitteral>(y: &Y) -> Result<std::process::Output, PycallError> {
+        plot_y_with(&Interpreter::discover(), y)
+    }
+
+    /// Like `plot_y`, run by `interpreter`.
+    #[track_caller]
+    pub fn plot_y_with<Y: AsPythonLitteral>(
+        interpreter: &Interpreter,
+        y: &Y,
+    ) -> Result<std::process::Output, PycallError> {
+        let mut program = PythonProgram::try_new()?;
+        program
+            .interpreter(interpreter.clone())
+            .import_as("matplotlib.pyplot", "plt")
+            .declare::<Y>()
+            .write_line(&format!("plt.plot({})", PythonLiteral(y)))
+            .write_line("plt.show()")
+            .run()
     }
 }
 
@@ -424,18 +1630,628 @@ macro_rules! plot {
 
 #[test]
 fn run() {
-    let join = std::thread::spawn(|| quick_plot(&(-50..50).map(|x| (-x * x)).collect::<Vec<_>>()));
+    let join = std::thread::spawn(|| plots::plot_y(&(-50..50).map(|x| -x * x).collect::<Vec<_>>()));
     let mut program = PythonProgram::new();
     program
         .write_line("import matplotlib.pyplot as plt")
         .define_variable(
             "hello",
             &(-50..50).map(|x| (x * x) as f64).collect::<Vec<_>>(),
-        )
+        );
+    program
         .write_line("print(hello)")
         .write_line("plt.plot(hello)")
         .write_line("plt.show()");
-    println!("program: {}\r\n{}", program.file.path().display(), &program);
-    let output = program.run().unwrap();
-    join.join();
+    println!("program:\r\n{}", &program);
+    program.run().unwrap();
+    join.join().unwrap().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Defines `value` in a fresh program and returns the raw UTF-8 bytes Python holds for it.
+    fn python_echo<T: AsPythonLitteral + ?Sized>(value: &T) -> Vec<u8> {
+        let mut program = PythonProgram::new();
+        program.import("sys").define_variable("value", value);
+        program
+            .write_line("assert type(value) is str")
+            .write_line("sys.stdout.buffer.write(value.encode('utf-8'))");
+        let output = program.run().unwrap();
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            program,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output.stdout
+    }
+
+    #[test]
+    fn str_literals_round_trip() {
+        let adversarial = [
+            "",
+            "plain",
+            "\"\"\"",
+            "ends with a quote\"",
+            "'",
+            "'\"",
+            "'''\"\"\"'''",
+            "\\",
+            "trailing backslash\\",
+            "\\n is not a newline",
+            "nul\0byte\0",
+            "\x0001",
+            "\r\n\t\x0b\x0c\x1b\x7f",
+            "\u{85}\u{a0}\u{ad}\u{2028}\u{2029}\u{feff}\u{200b}",
+            "\u{301}combining first",
+            "héllo wörld, 日本語, 🐍🦀",
+            "\u{10ffff}\u{e000}\u{fffd}",
+            "\"); import os; os.system(\"echo pwned\"); (\"",
+            "{}{{}}%s%r",
+        ];
+        for s in adversarial.iter() {
+            assert_eq!(python_echo(*s), s.as_bytes(), "{:?}", s);
+            assert_eq!(python_echo(&s.to_string()), s.as_bytes(), "{:?}", s);
+        }
+        let cow: std::borrow::Cow<str> = "cow'\"".into();
+        assert_eq!(python_echo(&cow), cow.as_bytes());
+    }
+
+    #[test]
+    fn char_literals_round_trip() {
+        for c in [
+            'a', '\'', '"', '\\', '\0', '\n', '\u{7f}', '\u{2028}', 'é', '🐍',
+        ]
+        .iter()
+        {
+            assert_eq!(python_echo(c), c.to_string().as_bytes(), "{:?}", c);
+        }
+    }
+
+    /// Runs `program` and returns its stdout, failing the test if Python did.
+    fn run_stdout(program: &PythonProgram) -> String {
+        let output = program.run().unwrap();
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            program,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn float_literals_round_trip() {
+        let values = vec![
+            0.0,
+            -0.0,
+            0.1,
+            1.0 / 3.0,
+            -2.5e-300,
+            std::f64::consts::PI,
+            1e16,
+            1e22,
+            123456789.12345679,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            5e-324,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        let mut program = PythonProgram::new();
+        program.import("struct").define_variable("values", &values);
+        program.write_line("print(' '.join(struct.pack('<d', v).hex() for v in values))");
+        let stdout = run_stdout(&program);
+        let expected = values
+            .iter()
+            .map(|v| {
+                v.to_le_bytes()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(stdout.trim(), expected);
+
+        let values = vec![0.1f32, -0.0, 1.0 / 3.0, f32::MAX, 1e-45, f32::NEG_INFINITY];
+        let mut program = PythonProgram::new();
+        program.import("struct").define_variable("values", &values);
+        program.define_variable("nan", &f64::NAN);
+        program
+            .write_line("assert nan != nan")
+            .write_line("print(' '.join(struct.pack('<f', v).hex() for v in values))");
+        let stdout = run_stdout(&program);
+        let expected = values
+            .iter()
+            .map(|v| {
+                v.to_le_bytes()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(stdout.trim(), expected);
+    }
+
+    #[test]
+    fn float_precision() {
+        let mut program = PythonProgram::new();
+        program
+            .float_precision(Some(2))
+            .define_variable("values", &vec![std::f64::consts::PI, -0.0, f64::INFINITY]);
+        assert_eq!(
+            program.to_string(),
+            "values = [3.14e0,-0.00e0,float('inf'),]\n"
+        );
+        program.write_line("print(values)");
+        assert_eq!(run_stdout(&program).trim(), "[3.14, -0.0, inf]");
+    }
+
+    #[test]
+    fn std_literals() {
+        use std::collections::*;
+        let mut program = PythonProgram::new();
+        let set: BTreeSet<_> = vec![(1, 'a'), (2, 'b')].into_iter().collect();
+        let nested: HashSet<BTreeSet<u8>> = vec![BTreeSet::new(), vec![3].into_iter().collect()]
+            .into_iter()
+            .collect();
+        let lists_as_keys: BTreeMap<Vec<u8>, Vec<u8>> =
+            vec![(vec![1, 2], vec![3, 4])].into_iter().collect();
+        let mut deque = VecDeque::new();
+        deque.push_front(1);
+        deque.push_back(2);
+        program.define_variable("t", &true);
+        program.define_variable("f", &false);
+        program.define_variable("none", &None::<u8>);
+        program.define_variable("some", &Some(3));
+        program.define_variable("unit", &());
+        program.define_variable("single", &(1,));
+        program.define_variable("pair", &("a", 2.5));
+        program.define_variable(
+            "twelve",
+            &(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, std::rc::Rc::new("12")),
+        );
+        program.define_variable("array", &[1u8, 2, 3]);
+        program.define_variable("deque", &deque);
+        program.define_variable("pairs", &set);
+        program.define_variable("empty_set", &HashSet::<u8>::new());
+        program.define_variable("nested", &nested);
+        program.define_variable("lists_as_keys", &lists_as_keys);
+        program.define_variable(
+            "ordered",
+            &vec![(3, 'c'), (1, 'a')]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>(),
+        );
+        program.define_variable("boxed", &Box::new(std::sync::Arc::new(vec![Some(1), None])));
+        program.define_variable("cow", &std::borrow::Cow::<[u8]>::Borrowed(&[1, 2]));
+        program
+            .write_line("assert t is True and f is False")
+            .write_line("assert none is None and unit is None and some == 3")
+            .write_line("assert single == (1,) and pair == ('a', 2.5)")
+            .write_line("assert twelve == tuple(range(1, 12)) + ('12',)")
+            .write_line("assert array == [1, 2, 3] and deque == [1, 2]")
+            .write_line("assert pairs == {(1, 'a'), (2, 'b')} and type(pairs) is set")
+            .write_line("assert empty_set == set() and type(empty_set) is set")
+            .write_line("assert nested == {frozenset(), frozenset({3})}")
+            .write_line("assert lists_as_keys == {(1, 2): [3, 4]}")
+            .write_line("assert list(ordered.items()) == [(1, 'a'), (3, 'c')]")
+            .write_line("assert boxed == [1, None] and cow == [1, 2]")
+            .write_line("print('ok')");
+        assert_eq!(run_stdout(&program).trim(), "ok");
+    }
+
+    #[test]
+    fn derived_literals() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        struct Plain {
+            x: f64,
+            #[python(rename = "label")]
+            name: String,
+            #[python(skip)]
+            _cache: Vec<u8>,
+        }
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass, rename_all = "camelCase")]
+        struct Point<T> {
+            x_pos: T,
+            y_pos: T,
+        }
+
+        #[derive(AsPythonLitteral)]
+        #[python(namedtuple, rename = "Span")]
+        struct Range(u32, u32);
+
+        #[derive(AsPythonLitteral)]
+        #[python(namespace)]
+        struct Config {
+            points: Vec<Point<i32>>,
+            spans: Vec<Range>,
+            color: Color,
+            shape: Shape,
+        }
+
+        #[derive(AsPythonLitteral)]
+        #[python(enum)]
+        enum Color {
+            Red,
+            #[python(rename = "GREEN")]
+            Green,
+        }
+
+        #[derive(AsPythonLitteral)]
+        #[python(rename_all = "snake_case")]
+        enum Shape {
+            Empty,
+            Circle(f64),
+            Segment(i32, i32),
+            BoundingBox { width: u8, height: u8 },
+        }
+
+        #[derive(AsPythonLitteral)]
+        struct Newtype(i64);
+
+        // Fields may be named like the formatter.
+        #[derive(AsPythonLitteral)]
+        enum Tagged {
+            V { f: u8, x: u8 },
+        }
+
+        #[derive(AsPythonLitteral, PartialEq, Eq, PartialOrd, Ord)]
+        struct Cell {
+            row: u8,
+            col: u8,
+        }
+
+        let mut program = PythonProgram::new();
+        program.define_variable(
+            "plain",
+            &Plain {
+                x: 0.5,
+                name: "a'b".into(),
+                _cache: vec![],
+            },
+        );
+        program.define_variable(
+            "config",
+            &Config {
+                points: vec![Point { x_pos: 1, y_pos: 2 }],
+                spans: vec![Range(3, 4)],
+                color: Color::Green,
+                shape: Shape::Circle(1.5),
+            },
+        );
+        program.define_variable(
+            "other_point",
+            &Point {
+                x_pos: 0.5,
+                y_pos: 0.25,
+            },
+        );
+        program.define_variable("colors", &[Color::Red, Color::Green]);
+        program.define_variable(
+            "shapes",
+            &vec![
+                Shape::Empty,
+                Shape::Segment(-1, 1),
+                Shape::BoundingBox {
+                    width: 2,
+                    height: 3,
+                },
+            ],
+        );
+        program.define_variable("newtype", &Newtype(7));
+        program.define_variable(
+            "cells",
+            &std::collections::BTreeSet::from([Cell { row: 0, col: 1 }]),
+        );
+        program
+            .write_line("assert plain == {'x': 0.5, 'label': \"a'b\"}")
+            .write_line("assert config.points == [Point(xPos=1, yPos=2)]")
+            .write_line("assert config.spans[0] == Span(3, 4) and config.spans[0]._1 == 4")
+            .write_line("assert config.color is Color.GREEN and config.shape == {'circle': 1.5}")
+            .write_line("assert other_point.yPos == 0.25 and colors == [Color.Red, Color.GREEN]")
+            .write_line("assert shapes == ['empty', {'segment': (-1, 1)}, {'bounding_box': {'width': 2, 'height': 3}}]")
+            .write_line("assert newtype == 7")
+            .define_variable("tagged", &Tagged::V { f: 1, x: 2 });
+        program
+            .write_line("assert cells == {(('row', 0), ('col', 1))}")
+            .write_line("assert tagged == {'V': {'f': 1, 'x': 2}}")
+            .write_line("print('ok')");
+        // Each class is only declared once.
+        assert_eq!(program.to_string().matches("class Point").count(), 1);
+        assert_eq!(run_stdout(&program).trim(), "ok");
+    }
+
+    #[test]
+    fn nested_declarations() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: i32,
+        }
+
+        // Classes first needed in a block are still defined at the top level.
+        let mut program = PythonProgram::new();
+        program.r#if("False").define_variable("a", &Point { x: 1 });
+        program.end_block().define_variable("b", &Point { x: 2 });
+        program.write_line("print(b.x)");
+        assert!(program
+            .to_string()
+            .starts_with("import dataclasses\n@dataclasses.dataclass"));
+        assert_eq!(run_stdout(&program), "2\n");
+    }
+
+    #[test]
+    fn evaluate() {
+        let mut program = PythonProgram::new();
+        program.define_variable("xs", &vec![1, 2, 3]);
+        program
+            .write_line("print('(noise)')")
+            .r#if("True")
+            .write_line("ys = [x * 2 for x in xs]")
+            .end_block();
+        assert_eq!(program.evaluate::<i64>("sum(ys)").unwrap(), 12);
+        assert_eq!(
+            program
+                .evaluate::<(String, Vec<f64>)>("'ok', [x / 2 for x in xs]")
+                .unwrap(),
+            ("ok".to_owned(), vec![0.5, 1.0, 1.5])
+        );
+        match program.evaluate::<i64>("missing") {
+            Err(PycallError::ExpressionFailed { exception, output }) => {
+                assert_eq!(exception.type_name, "NameError");
+                assert_eq!(exception.message, "name 'missing' is not defined");
+                assert_eq!(exception.traceback.last().unwrap().file, "<string>");
+                assert_eq!(String::from_utf8_lossy(&output.stdout), "(noise)\n");
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            program.evaluate::<i64>("object()"),
+            Err(PycallError::Decode(_))
+        ));
+        assert!(matches!(
+            program.evaluate::<String>("sum(ys)"),
+            Err(PycallError::Decode(LiteralError::Mismatch { .. }))
+        ));
+        let line = line!() + 1;
+        program.write_line("raise RuntimeError('boom')");
+        match program.evaluate::<i64>("1") {
+            Err(PycallError::PythonException { exception, output }) => {
+                assert_eq!(exception.type_name, "RuntimeError");
+                assert_eq!(exception.message, "boom");
+                let frame = exception.program_frame().unwrap();
+                assert_eq!(frame.line, 5);
+                assert_eq!(frame.written_by.unwrap().line(), line);
+                assert!(!output.status.success());
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn evaluate_declarations() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Inner {
+            x: u8,
+        }
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Outer {
+            inner: Inner,
+        }
+
+        // The declarations the expression needs come one per line, before it, even after an unfinished line.
+        let mut program = PythonProgram::new();
+        write!(program, "y = 4").unwrap();
+        let outer = Expr::literal(&Outer {
+            inner: Inner { x: 3 },
+        });
+        assert_eq!(
+            program
+                .evaluate::<u8>(&(outer.attr("inner").attr("x") + Expr::name("y")))
+                .unwrap(),
+            7
+        );
+        assert_eq!(program.as_str(), "y = 4\n");
+    }
+
+    #[test]
+    fn buffer() {
+        let mut program = PythonProgram::new();
+        program.define_variable("x", &1);
+        let mut copy = program.clone();
+        assert!(copy == program);
+        copy.import("sys").write_line("print(x, file=sys.stdout)");
+        program.write_line("print(x)");
+        assert!(copy != program);
+        assert_eq!(program.as_str(), "x = 1\nprint(x)\n");
+        assert_eq!(
+            copy.as_str(),
+            "import sys\nx = 1\nprint(x, file=sys.stdout)\n"
+        );
+        assert_eq!(copy.written_by(1).unwrap().line(), line!() - 8);
+        assert_eq!(copy.written_by(3).unwrap().line(), line!() - 9);
+        assert_eq!(run_stdout(&copy), "1\n");
+
+        // Raw writes must be UTF-8, but may split characters.
+        let bytes = "print('é')\n".as_bytes();
+        assert_eq!(copy.write(&bytes[..7]).unwrap(), 7);
+        assert_eq!(copy.write(&bytes[7..8]).unwrap(), 1);
+        copy.write_all(&bytes[8..]).unwrap();
+        assert!(copy.write(b"\xff").is_err());
+        assert_eq!(copy.written_by(4), None);
+        let mut written = Vec::new();
+        copy.write_to(&mut written).unwrap();
+        assert_eq!(written, copy.as_str().as_bytes());
+        assert_eq!(run_stdout(&copy), "1\né\n");
+
+        // Once the program failed, raw writes are ignored.
+        copy.define_variable("class", &1);
+        copy.write_all(b"print('ignored')\n").unwrap();
+        assert!(!copy.as_str().contains("ignored"));
+        assert!(matches!(copy.run(), Err(PycallError::InvalidName { .. })));
+    }
+
+    #[test]
+    fn indentation() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: i32,
+        }
+
+        for unit in [
+            IndentUnit::Tab,
+            IndentUnit::Spaces(4),
+            IndentUnit::Spaces(2),
+        ] {
+            let mut program = PythonProgram::new();
+            program
+                .indent_unit(unit)
+                .define_variable("point", &Point { x: 3 });
+            program
+                .r#for("i in range(point.x)")
+                .r#if("i == 0")
+                .write_line("print('zero')")
+                .elif("i == 1")
+                .r#while("False")
+                .end_block()
+                .r#else()
+                .write_line("print('else')")
+                .end_block()
+                .r#if("True")
+                .indent(1)
+                .indent(-1)
+                .write_line("print('after', i)")
+                .end_block()
+                .end_block()
+                .write_line("print('done')");
+            assert_eq!(program.indent_level(), 0);
+            assert_eq!(
+                run_stdout(&program),
+                "zero\nafter 0\nafter 1\nelse\nafter 2\ndone\n"
+            );
+            let source = program.to_string();
+            let indented = format!("\n{}while False:\n", unit.repeat(2));
+            assert!(source.contains(&indented), "{}", source);
+            if unit != IndentUnit::Tab {
+                assert!(!source.contains('\t'), "{}", source);
+            }
+        }
+
+        let mut program = PythonProgram::new();
+        let dedented_at = line!() + 3;
+        program
+            .indent(1)
+            .indent(-2)
+            .write_line("print('never written')");
+        assert_eq!(program.indent_level(), 0);
+        match program.run() {
+            Err(PycallError::Dedent(location)) => assert_eq!(location.line(), dedented_at),
+            other => panic!("{:?}", other),
+        }
+        let mut program = PythonProgram::new();
+        program.end_block();
+        assert!(matches!(program.error(), Some(PycallError::Dedent(_))));
+    }
+
+    #[test]
+    fn errors() {
+        let mut program = PythonProgram::new();
+        program.write_line("print('hi')");
+        assert_eq!(run_stdout(&program), "hi\n");
+        assert!(program.run_checked().is_ok());
+
+        program.write_line("raise ValueError('nope')");
+        assert!(program.run().is_ok());
+        match program.run_checked() {
+            Err(PycallError::PythonException { exception, output }) => {
+                assert_eq!(exception.type_name, "ValueError");
+                assert!(exception.raw.contains("ValueError: nope"));
+                assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n");
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let mut program = PythonProgram::new();
+        program.write_line("import sys").write_line("sys.exit(3)");
+        match program.run_checked() {
+            Err(PycallError::NonZeroExit(output)) => assert_eq!(output.status.code(), Some(3)),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            program.evaluate::<i64>("1"),
+            Err(PycallError::NonZeroExit(_))
+        ));
+
+        // Exceptions point back to the rust calls that wrote the failing lines.
+        let mut program = PythonProgram::new();
+        let defined_at = line!() + 1;
+        program.define_variable("xs", &vec![1, 2]);
+        program.define_variable("ys", &vec![(); 0]);
+        program
+            .write_all(b"def first(xs):\n\treturn xs[0]\n")
+            .unwrap();
+        let called_at = line!() + 1;
+        program.write_line("first(xs)").write_line("first(ys)");
+        assert_eq!(program.written_by(1).unwrap().line(), defined_at);
+        assert_eq!(program.written_by(3), None);
+        assert_eq!(program.written_by(0), None);
+        assert_eq!(program.written_by(7), None);
+        match program.run_checked() {
+            Err(error @ PycallError::PythonException { .. }) => {
+                let message = error.to_string();
+                let exception = match error {
+                    PycallError::PythonException { exception, .. } => exception,
+                    _ => unreachable!(),
+                };
+                assert_eq!(exception.type_name, "IndexError");
+                let lines: Vec<_> = exception.traceback.iter().map(|f| f.line).collect();
+                assert_eq!(lines, vec![6, 4]);
+                assert_eq!(exception.traceback[0].written_by.unwrap().line(), called_at);
+                assert_eq!(exception.traceback[1].written_by, None);
+                assert_eq!(exception.traceback[1].function, "first");
+                assert_eq!(exception.program_frame().unwrap().line, 4);
+                let written_by = format!(
+                    "line 6 of the generated script, written by {}:{}:",
+                    file!(),
+                    called_at
+                );
+                assert!(message.contains(&written_by), "{}", message);
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // Write errors are kept, and reported whenever the program is used.
+        let mut program = PythonProgram::new();
+        program.error = Some(PycallError::Write(std::io::Error::other("disk full")));
+        program.write_line("print('never written')").flush();
+        assert!(matches!(program.error(), Some(PycallError::Write(_))));
+        assert_eq!(program.as_str(), "");
+        assert!(matches!(program.run(), Err(PycallError::Write(_))));
+        assert!(matches!(
+            program.evaluate::<i64>("1"),
+            Err(PycallError::Write(_))
+        ));
+        assert!(matches!(
+            program.save_as("/nonexistent/script.py"),
+            Err(PycallError::Write(_))
+        ));
+    }
 }
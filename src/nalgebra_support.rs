@@ -0,0 +1,108 @@
+//! `nalgebra` matrices as nested python lists, or as numpy arrays through `PythonProgram::define_array`.
+use crate::data::write_sequence_header;
+use crate::npy::{write_npy_iter, NpyArray, NpyElement, Order};
+use crate::{AsPythonLitteral, Declarations};
+use nalgebra::{Dim, Matrix, RawStorage, Scalar};
+use std::fmt::{Error, Formatter};
+use std::io::Write;
+
+/// Matrices, vectors included, are written as lists of rows.
+impl<T, R, C, S> AsPythonLitteral for Matrix<T, R, C, S>
+where
+    T: Scalar + AsPythonLitteral,
+    R: Dim,
+    C: Dim,
+    S: RawStorage<T, R, C>,
+{
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let (open, close) = if f.alternate() {
+            ("(", ")")
+        } else {
+            ("[", "]")
+        };
+        f.write_str(open)?;
+        for i in 0..self.nrows() {
+            f.write_str(open)?;
+            for j in 0..self.ncols() {
+                AsPythonLitteral::fmt(&self[(i, j)], f)?;
+                f.write_str(",")?;
+            }
+            f.write_str(close)?;
+            f.write_str(",")?;
+        }
+        f.write_str(close)
+    }
+
+    fn declare(declarations: &mut Declarations) {
+        T::declare(declarations)
+    }
+
+    fn marshal(&self, out: &mut dyn Write, hashable: bool) -> std::io::Result<bool> {
+        write_sequence_header(out, hashable, self.nrows())?;
+        for i in 0..self.nrows() {
+            write_sequence_header(out, hashable, self.ncols())?;
+            for j in 0..self.ncols() {
+                if !self[(i, j)].marshal(out, hashable)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Matrices are written in column-major order, as nalgebra stores them.
+impl<T, R, C, S> NpyArray for Matrix<T, R, C, S>
+where
+    T: Scalar + NpyElement,
+    R: Dim,
+    C: Dim,
+    S: RawStorage<T, R, C>,
+{
+    fn write_npy<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let shape = [self.nrows(), self.ncols()];
+        write_npy_iter(out, self.len(), self.iter(), &shape, Order::Fortran)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NpyArray, PythonLiteral, PythonProgram};
+    use nalgebra::{DMatrix, DVector, Matrix2x3};
+
+    #[test]
+    fn nalgebra_literals() {
+        let matrix = Matrix2x3::new(1, 2, 3, 4, 5, 6);
+        assert_eq!(PythonLiteral(&matrix).to_string(), "[[1,2,3,],[4,5,6,],]");
+        assert_eq!(
+            format!("{:#}", PythonLiteral(&matrix.row(1))),
+            "((4,5,6,),)"
+        );
+        let vector = DVector::from_vec(vec![0.5, 1.5]);
+        assert_eq!(PythonLiteral(&vector).to_string(), "[[0.5,],[1.5,],]");
+
+        let mut program = PythonProgram::new();
+        program.define_variable("m", &DMatrix::from_row_slice(2, 2, &[1u8, 2, 3, 4]));
+        program.write_line("print(m[0][1] + m[1][0])");
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+    }
+
+    #[test]
+    fn nalgebra_npy() {
+        let matrix = DMatrix::from_row_slice(2, 3, &[1u8, 2, 3, 4, 5, 6]);
+        let mut npy = Vec::new();
+        matrix.write_npy(&mut npy).unwrap();
+        let len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        let header = std::str::from_utf8(&npy[10..10 + len]).unwrap();
+        assert_eq!(
+            header.trim_end(),
+            "{'descr': '|u1', 'fortran_order': True, 'shape': (2, 3), }"
+        );
+        assert_eq!(&npy[10 + len..], &[1, 4, 2, 5, 3, 6]);
+
+        let mut npy = Vec::new();
+        matrix.row(1).write_npy(&mut npy).unwrap();
+        assert!(npy.ends_with(&[4, 5, 6]));
+    }
+}
@@ -0,0 +1,335 @@
+//! Python names: checking them, keeping track of those a program binds, and generating fresh ones.
+use crate::{AsPythonSource, Expr, PycallError, PythonProgram};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::{Display, Error, Formatter};
+use std::panic::Location;
+
+/// Python's keywords, which can't be used as names. pycall-derive keeps a copy of both lists, to check the names of types.
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+/// Python's soft keywords, which are only keywords in some contexts, but make confusing names.
+const SOFT_KEYWORDS: &[&str] = &["_", "case", "match", "type"];
+
+/// Whether `name` is a python identifier, following the rules of `str.isidentifier`.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => {
+            (first == '_' || unicode_ident::is_xid_start(first))
+                && chars.all(unicode_ident::is_xid_continue)
+        }
+        None => false,
+    }
+}
+
+/// Whether `name` is a keyword or a soft keyword of python.
+pub fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(&name) || SOFT_KEYWORDS.contains(&name)
+}
+
+/// Why `name` can't be used as a variable name, if it can't.
+fn invalid_name(name: &str) -> Option<&'static str> {
+    if !is_identifier(name) {
+        Some("not a python identifier")
+    } else if KEYWORDS.contains(&name) {
+        Some("a python keyword")
+    } else if SOFT_KEYWORDS.contains(&name) {
+        Some("a python soft keyword")
+    } else {
+        None
+    }
+}
+
+/// The names bound by a top-level statement: imports, classes and functions.
+fn bound_names(line: &str) -> Vec<String> {
+    let alias = |item: &str| {
+        let item = item.trim();
+        match item.split_once(" as ") {
+            Some((_, alias)) => alias.trim().to_owned(),
+            // `import a.b` binds `a`.
+            None => item.split('.').next().unwrap_or(item).trim().to_owned(),
+        }
+    };
+    let definition = |rest: &str| {
+        rest.split(['(', ':'])
+            .next()
+            .map(|name| vec![name.trim().to_owned()])
+            .unwrap_or_default()
+    };
+    let names = if let Some(modules) = line.strip_prefix("import ") {
+        modules.split(',').map(alias).collect()
+    } else if let Some((_, items)) = line
+        .strip_prefix("from ")
+        .and_then(|rest| rest.split_once(" import "))
+    {
+        items
+            .trim_matches(|c: char| c == '(' || c == ')' || c.is_whitespace())
+            .split(',')
+            .map(alias)
+            .collect()
+    } else if let Some(rest) = line.strip_prefix("class ") {
+        definition(rest)
+    } else if let Some(rest) = line.strip_prefix("def ") {
+        definition(rest)
+    } else {
+        Vec::new()
+    };
+    names
+        .into_iter()
+        .filter(|name: &String| is_identifier(name))
+        .collect()
+}
+
+/// The names a program has bound so far.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Names {
+    /// Modules, classes and functions bound by imports and declarations.
+    imported: HashSet<String>,
+    /// Variables, including names reserved by `fresh_name`.
+    variables: HashSet<String>,
+}
+
+impl Names {
+    /// Records the names bound by `code`, if it is a top-level import or definition.
+    pub(crate) fn bind_statements(&mut self, code: &str) {
+        for line in code.lines() {
+            for name in bound_names(line) {
+                self.imported.insert(name);
+            }
+        }
+    }
+
+    /// Records the names bound by another program.
+    pub(crate) fn merge(&mut self, other: &Names) {
+        self.imported.extend(other.imported.iter().cloned());
+        self.variables.extend(other.variables.iter().cloned());
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.imported.contains(name) || self.variables.contains(name)
+    }
+}
+
+/// A variable of a python program, whose name has been checked.
+/// Accepted wherever a name or source is, so that it can be used in later calls and in `Expr`s.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Var(String);
+
+impl Var {
+    /// The variable's name.
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// The variable, as an expression.
+    pub fn expr(&self) -> Expr {
+        Expr::name(&self.0)
+    }
+}
+
+impl Display for Var {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for Var {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsPythonSource for Var {
+    fn source(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.0)
+    }
+}
+
+impl From<Var> for Expr {
+    fn from(var: Var) -> Expr {
+        var.expr()
+    }
+}
+
+impl From<&Var> for Expr {
+    fn from(var: &Var) -> Expr {
+        var.expr()
+    }
+}
+
+impl PythonProgram {
+    /// Fails the program with `PycallError::InvalidName`, unless it already failed.
+    #[track_caller]
+    pub(crate) fn invalid_name(&mut self, name: &str, reason: &'static str) {
+        if self.error.is_none() {
+            self.error = Some(PycallError::InvalidName {
+                name: name.to_owned(),
+                reason,
+                location: Location::caller(),
+            });
+        }
+    }
+
+    /// Checks that `name` can be assigned, and records it as a variable.
+    /// Variables can be reassigned, but imports and declarations can't be clobbered.
+    #[track_caller]
+    pub(crate) fn bind_variable(&mut self, name: &str) -> Var {
+        match invalid_name(name) {
+            Some(reason) => self.invalid_name(name, reason),
+            None if self.names.imported.contains(name) => {
+                self.invalid_name(name, "already bound by an import or a declaration")
+            }
+            None => {
+                self.names.variables.insert(name.to_owned());
+            }
+        }
+        Var(name.to_owned())
+    }
+
+    /// Checks that the names bound by the import `statement` don't clobber variables, and records them.
+    #[track_caller]
+    pub(crate) fn bind_import(&mut self, statement: &str) {
+        for name in bound_names(statement) {
+            if self.names.variables.contains(&name) {
+                self.invalid_name(&name, "already bound by a variable");
+            }
+            self.names.imported.insert(name);
+        }
+    }
+
+    /// A variable name based on `base` that this program doesn't use yet: `base`, else `base_1`, `base_2`...
+    /// The name is reserved, so that the next calls return other names.
+    /// Characters that can't be in names are replaced by `_`.
+    pub fn fresh_name(&mut self, base: &str) -> Var {
+        let mut base: String = base
+            .chars()
+            .map(|c| {
+                if unicode_ident::is_xid_continue(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if invalid_name(&base).is_some() {
+            base.insert(0, '_');
+        }
+        let mut name = base.clone();
+        let mut i = 0;
+        while self.names.contains(&name) || is_keyword(&name) {
+            i += 1;
+            name = format!("{}_{}", base, i);
+        }
+        self.names.variables.insert(name.clone());
+        Var(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers() {
+        for name in ["x", "_private", "série", "名前", "x1", "__pycall_load"] {
+            assert!(is_identifier(name) && !is_keyword(name), "{}", name);
+        }
+        for name in ["", "1x", "my-var", "a b", "a.b", "x!"] {
+            assert!(!is_identifier(name), "{}", name);
+        }
+        for name in ["class", "None", "await", "match", "_"] {
+            assert!(is_keyword(name), "{}", name);
+        }
+        assert_eq!(bound_names("import matplotlib.pyplot as plt"), ["plt"]);
+        assert_eq!(bound_names("import os.path, sys"), ["os", "sys"]);
+        assert_eq!(
+            bound_names("from collections import (namedtuple, OrderedDict as OD)"),
+            ["namedtuple", "OD"]
+        );
+        assert_eq!(bound_names("class Point(Base):"), ["Point"]);
+        assert_eq!(bound_names("\tdef method(self):"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn variables() {
+        let mut program = PythonProgram::new();
+        program.import_as("math", "m");
+        let x = program.define_variable("x", &2);
+        let series = program.fresh_name("series");
+        let series_1 = program.fresh_name("series");
+        assert_eq!((series.name(), series_1.name()), ("series", "series_1"));
+        assert_eq!(program.fresh_name("x").name(), "x_1");
+        assert_eq!(program.fresh_name("m").name(), "m_1");
+        assert_eq!(program.fresh_name("class").name(), "_class");
+        assert_eq!(program.fresh_name("my-var").name(), "my_var");
+        assert_eq!(program.fresh_name("2d").name(), "_2d");
+        program.define_variable(&series, &vec![1, 2]);
+        program.define_variable(&x, &3);
+        program.write_line(&format!(
+            "print({}, m.sqrt({}), {})",
+            x,
+            series.expr().index(Expr::literal(&1)),
+            series
+        ));
+        let output = program.run_checked().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "3 1.4142135623730951 [1, 2]\n"
+        );
+
+        let error = |define: &dyn Fn(&mut PythonProgram)| {
+            let mut program = PythonProgram::new();
+            program.import("matplotlib.pyplot as plt");
+            define(&mut program);
+            match program.run() {
+                Err(PycallError::InvalidName { name, reason, .. }) => {
+                    format!("{}: {}", name, reason)
+                }
+                other => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(
+            error(&|program| {
+                program.define_variable("plt", &1);
+            }),
+            "plt: already bound by an import or a declaration"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.define_variable("class", &1);
+            }),
+            "class: a python keyword"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.define_variable("match", &1);
+            }),
+            "match: a python soft keyword"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.define_data("my-var", &1);
+            }),
+            "my-var: not a python identifier"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.define_variable("x", &1);
+                program.import_as("numpy", "x");
+            }),
+            "x: already bound by a variable"
+        );
+        assert_eq!(
+            error(&|program| {
+                program.import_as("numpy", "np.linalg");
+            }),
+            "np.linalg: not a valid import name"
+        );
+    }
+}
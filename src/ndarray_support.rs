@@ -0,0 +1,125 @@
+//! `ndarray` arrays as nested python lists, or as numpy arrays through `PythonProgram::define_array`.
+use crate::data::marshal_sequence;
+use crate::npy::{write_npy, write_npy_iter, NpyArray, NpyElement, Order};
+use crate::{fmt_sequence, AsPythonLitteral, Declarations};
+use ndarray::{ArrayBase, Data, Dimension};
+use std::fmt::{Error, Formatter};
+use std::io::Write;
+
+/// Arrays are written as nested lists following their shape; 0-dimensional arrays as their only element.
+impl<S, D> AsPythonLitteral for ArrayBase<S, D>
+where
+    S: Data,
+    S::Elem: AsPythonLitteral,
+    D: Dimension,
+{
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let view = self.view().into_dyn();
+        match (view.ndim(), view.first()) {
+            (0, Some(x)) => AsPythonLitteral::fmt(x, f),
+            _ => fmt_sequence(f, view.outer_iter()),
+        }
+    }
+
+    fn declare(declarations: &mut Declarations) {
+        S::Elem::declare(declarations)
+    }
+
+    fn marshal(&self, out: &mut dyn Write, hashable: bool) -> std::io::Result<bool> {
+        let view = self.view().into_dyn();
+        match (view.ndim(), view.first()) {
+            (0, Some(x)) => x.marshal(out, hashable),
+            _ => marshal_sequence(out, view.outer_iter(), hashable),
+        }
+    }
+}
+
+/// Contiguous arrays are written as they are laid out in memory, others in row-major order.
+impl<S, D> NpyArray for ArrayBase<S, D>
+where
+    S: Data,
+    S::Elem: NpyElement,
+    D: Dimension,
+{
+    fn write_npy<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        if let Some(data) = self.as_slice() {
+            write_npy(out, data, self.shape(), Order::C)
+        } else if let Some(data) = self.t().as_slice() {
+            write_npy(out, data, self.shape(), Order::Fortran)
+        } else {
+            write_npy_iter(out, self.len(), self.iter(), self.shape(), Order::C)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{NpyArray, PythonLiteral};
+    use ndarray::{arr0, arr2, Array2, Array3, ShapeBuilder};
+
+    fn npy_header_and_data<A: NpyArray>(array: &A) -> (String, Vec<u8>) {
+        let mut npy = Vec::new();
+        array.write_npy(&mut npy).unwrap();
+        let len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        let header = String::from_utf8(npy[10..10 + len].to_vec()).unwrap();
+        (header.trim_end().to_owned(), npy[10 + len..].to_vec())
+    }
+
+    #[test]
+    fn ndarray_literals() {
+        let matrix = arr2(&[[1, 2, 3], [4, 5, 6]]);
+        assert_eq!(PythonLiteral(&matrix).to_string(), "[[1,2,3,],[4,5,6,],]");
+        assert_eq!(
+            format!("{:#}", PythonLiteral(&matrix.t())),
+            "((1,4,),(2,5,),(3,6,),)"
+        );
+        assert_eq!(PythonLiteral(&arr0(1.5)).to_string(), "1.5");
+        let empty = Array3::<u8>::zeros((2, 0, 1));
+        assert_eq!(PythonLiteral(&empty).to_string(), "[[],[],]");
+        assert_eq!(
+            PythonLiteral(&matrix.into_dyn()).to_string(),
+            "[[1,2,3,],[4,5,6,],]"
+        );
+    }
+
+    #[test]
+    fn ndarray_npy() {
+        let matrix = Array2::from_shape_vec((2, 2), vec![1u8, 2, 3, 4]).unwrap();
+        let (header, data) = npy_header_and_data(&matrix);
+        assert_eq!(
+            header,
+            "{'descr': '|u1', 'fortran_order': False, 'shape': (2, 2), }"
+        );
+        assert_eq!(data, vec![1, 2, 3, 4]);
+
+        let fortran = Array2::from_shape_vec((2, 2).f(), vec![1i16, 2, 3, 4]).unwrap();
+        let (header, data) = npy_header_and_data(&fortran);
+        assert_eq!(
+            header,
+            "{'descr': '<i2', 'fortran_order': True, 'shape': (2, 2), }"
+        );
+        assert_eq!(data, vec![1, 0, 2, 0, 3, 0, 4, 0]);
+
+        let mut program = crate::PythonProgram::new();
+        program.define_array("fortran", &fortran);
+        assert!(program.to_string().contains("fortran = np.load("));
+
+        // Strided views are written in logical order.
+        let column = matrix.column(1);
+        let (header, data) = npy_header_and_data(&column);
+        assert_eq!(
+            header,
+            "{'descr': '|u1', 'fortran_order': False, 'shape': (2,), }"
+        );
+        assert_eq!(data, vec![2, 4]);
+
+        let complex = ndarray::arr1(&[num_complex::Complex::new(1f32, -1f32)]);
+        assert_eq!(
+            format!("{:.1}", PythonLiteral(&complex)),
+            "[complex(1.0e0,-1.0e0),]"
+        );
+        let (header, data) = npy_header_and_data(&complex);
+        assert!(header.contains("'<c8'"));
+        assert_eq!(data.len(), 8);
+    }
+}
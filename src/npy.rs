@@ -0,0 +1,267 @@
+//! Writing numeric arrays in NumPy's `.npy` format, which keeps their exact dtype and loads without parsing.
+use std::io::Write;
+
+/// The layout of a multidimensional array's elements in memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Order {
+    /// Row-major: the last index varies fastest.
+    #[default]
+    C,
+    /// Column-major: the first index varies fastest.
+    Fortran,
+}
+
+/// A type that NumPy stores natively.
+pub trait NpyElement {
+    /// The array-protocol type string of the dtype, such as `<f8`.
+    const DESCR: &'static str;
+    /// Writes the element's little-endian representation.
+    fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()>;
+}
+
+macro_rules! npy_element_impl {
+    ($t: ty, $descr: expr) => {
+        impl NpyElement for $t {
+            const DESCR: &'static str = $descr;
+            fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+                out.write_all(&self.to_le_bytes())
+            }
+        }
+    };
+}
+npy_element_impl!(i8, "|i1");
+npy_element_impl!(i16, "<i2");
+npy_element_impl!(i32, "<i4");
+npy_element_impl!(i64, "<i8");
+npy_element_impl!(u8, "|u1");
+npy_element_impl!(u16, "<u2");
+npy_element_impl!(u32, "<u4");
+npy_element_impl!(u64, "<u8");
+#[cfg(target_pointer_width = "32")]
+npy_element_impl!(isize, "<i4");
+#[cfg(target_pointer_width = "32")]
+npy_element_impl!(usize, "<u4");
+#[cfg(target_pointer_width = "64")]
+npy_element_impl!(isize, "<i8");
+#[cfg(target_pointer_width = "64")]
+npy_element_impl!(usize, "<u8");
+npy_element_impl!(f32, "<f4");
+npy_element_impl!(f64, "<f8");
+
+impl NpyElement for bool {
+    const DESCR: &'static str = "|b1";
+    fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        out.write_all(&[*self as u8])
+    }
+}
+
+/// Complex numbers, as `(real, imaginary)` pairs.
+impl NpyElement for (f32, f32) {
+    const DESCR: &'static str = "<c8";
+    fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        self.0.write_le(out)?;
+        self.1.write_le(out)
+    }
+}
+
+/// Complex numbers, as `(real, imaginary)` pairs.
+impl NpyElement for (f64, f64) {
+    const DESCR: &'static str = "<c16";
+    fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        self.0.write_le(out)?;
+        self.1.write_le(out)
+    }
+}
+
+/// Complex numbers.
+#[cfg(feature = "num-complex")]
+impl NpyElement for num_complex::Complex<f32> {
+    const DESCR: &'static str = "<c8";
+    fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        (self.re, self.im).write_le(out)
+    }
+}
+
+/// Complex numbers.
+#[cfg(feature = "num-complex")]
+impl NpyElement for num_complex::Complex<f64> {
+    const DESCR: &'static str = "<c16";
+    fn write_le<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        (self.re, self.im).write_le(out)
+    }
+}
+
+/// An array that knows its shape and memory order, and can be written as a `.npy` file.
+pub trait NpyArray {
+    fn write_npy<W: Write>(&self, out: &mut W) -> std::io::Result<()>;
+}
+
+impl<T: NpyElement> NpyArray for [T] {
+    fn write_npy<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write_npy(out, self, &[self.len()], Order::C)
+    }
+}
+
+impl<T: NpyElement> NpyArray for Vec<T> {
+    fn write_npy<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        self.as_slice().write_npy(out)
+    }
+}
+
+/// Writes `data`, laid out in `order`, as a `.npy` array of the given `shape`.
+/// `data` must hold exactly as many elements as `shape` describes.
+pub fn write_npy<W: Write, T: NpyElement>(
+    out: &mut W,
+    data: &[T],
+    shape: &[usize],
+    order: Order,
+) -> std::io::Result<()> {
+    write_npy_iter(out, data.len(), data, shape, order)
+}
+
+/// Writes the `len` elements of `data`, laid out in `order`, as a `.npy` array of the given `shape`.
+pub(crate) fn write_npy_iter<'a, W, T, I>(
+    out: &mut W,
+    len: usize,
+    data: I,
+    shape: &[usize],
+    order: Order,
+) -> std::io::Result<()>
+where
+    W: Write,
+    T: NpyElement + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    if shape.iter().product::<usize>() != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} elements don't fit the shape {:?}", len, shape),
+        ));
+    }
+    let shape = match shape {
+        [len] => format!("({},)", len),
+        shape => format!(
+            "({})",
+            shape
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': {}, 'shape': {}, }}",
+        T::DESCR,
+        if order == Order::Fortran {
+            "True"
+        } else {
+            "False"
+        },
+        shape
+    );
+    // The data starts on a 64 bytes boundary, after the magic string, the version and the header's length.
+    let (version, prefix): (&[u8], usize) = if header.len() + 64 <= 0xffff {
+        (&[1, 0], 10)
+    } else {
+        (&[2, 0], 12)
+    };
+    let padding = (64 - (prefix + header.len() + 1) % 64) % 64;
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+    out.write_all(b"\x93NUMPY")?;
+    out.write_all(version)?;
+    if prefix == 10 {
+        out.write_all(&(header.len() as u16).to_le_bytes())?;
+    } else {
+        out.write_all(&(header.len() as u32).to_le_bytes())?;
+    }
+    out.write_all(header.as_bytes())?;
+    data.into_iter().try_for_each(|x| x.write_le(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PythonProgram;
+
+    #[test]
+    fn npy_header() {
+        let mut npy = Vec::new();
+        write_npy(&mut npy, &[1u16, 2, 3, 4, 5, 6], &[2, 3], Order::Fortran).unwrap();
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert_eq!(
+            header.trim_end(),
+            "{'descr': '<u2', 'fortran_order': True, 'shape': (2, 3), }"
+        );
+        assert!(header.ends_with('\n'));
+        assert_eq!(
+            &npy[10 + header_len..],
+            &[1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0]
+        );
+
+        let mut npy = Vec::new();
+        write_npy(&mut npy, &[true], &[], Order::C).unwrap();
+        assert!(std::str::from_utf8(&npy[10..])
+            .unwrap()
+            .contains("'shape': ()"));
+        assert!(write_npy(&mut npy, &[1u8, 2], &[3], Order::C).is_err());
+    }
+
+    /// Decodes the side files with a reader written against the format's specification, as numpy may not be installed.
+    #[test]
+    fn define_ndarray() {
+        let mut program = PythonProgram::new();
+        program.define_ndarray("bytes", &[0u8, 1, 255], &[3]);
+        program.define_ndarray("ints", &[-1i64, 2, -3, 4, -5, 6], &[3, 2]);
+        program.define_ndarray_ordered("floats", &[0.1f32, 1e30], &[1, 2, 1], Order::Fortran);
+        program.define_ndarray("flags", &[true, false], &[2]);
+        program.define_ndarray("complex", &[(1.5f64, -2.0f64)], &[1]);
+        let source = program.to_string();
+        assert!(source.starts_with("import numpy as np\n"), "{}", source);
+        assert!(source.contains("bytes = np.load("), "{}", source);
+
+        let mut reader = PythonProgram::new();
+        reader.import("ast, struct").define_variable(
+            "paths",
+            &program
+                .data_files
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>(),
+        );
+        reader
+            .r#for("path in paths")
+            .write_line("data = open(path, 'rb').read()")
+            .write_line("assert data[:8] == b'\\x93NUMPY\\x01\\x00'")
+            .write_line("length, = struct.unpack('<H', data[8:10])")
+            .write_line("header = ast.literal_eval(data[10:10 + length].decode('latin1'))")
+            .write_line("codes = {'|u1': 'B', '<i8': 'q', '<f4': 'f', '|b1': '?', '<c16': 'd'}")
+            .write_line("code = codes[header['descr']]")
+            .write_line("count = len(data[10 + length:]) // struct.calcsize(code)")
+            .write_line("values = struct.unpack('<%d%s' % (count, code), data[10 + length:])")
+            .write_line(
+                "print(header['descr'], header['fortran_order'], header['shape'], list(values))",
+            )
+            .end_block();
+        let output = reader.run_checked().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "|u1 False (3,) [0, 1, 255]
+<i8 False (3, 2) [-1, 2, -3, 4, -5, 6]
+<f4 True (1, 2, 1) [0.10000000149011612, 1.0000000150474662e+30]
+|b1 False (2,) [True, False]
+<c16 False (1,) [1.5, -2.0]
+"
+        );
+
+        let mut program = PythonProgram::new();
+        program.define_ndarray("mismatched", &[1u8, 2], &[3]);
+        assert!(matches!(
+            program.error(),
+            Some(crate::PycallError::Write(_))
+        ));
+    }
+}
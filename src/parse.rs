@@ -0,0 +1,1165 @@
+//! Parsing Python literals back into Rust values.
+//!
+//! [`parse_python_literal`] accepts the subset of Python understood by `ast.literal_eval`,
+//! plus the few calls this crate itself emits (`float('inf')`, `set()`, `frozenset({...})`).
+//! [`FromPythonLiteral`] then converts the resulting [`PyValue`] tree into Rust types,
+//! mirroring the `AsPythonLitteral` impls so that values round-trip.
+use crate::AsPythonLitteral;
+use std::convert::TryInto;
+use std::fmt::{Error, Formatter};
+
+/// A parsed Python literal.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PyValue {
+    None,
+    Bool(bool),
+    Int(i128),
+    /// An int too large for `i128`, as its decimal representation.
+    BigInt(String),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<PyValue>),
+    List(Vec<PyValue>),
+    /// Dict items, in order.
+    Dict(Vec<(PyValue, PyValue)>),
+    Set(Vec<PyValue>),
+    FrozenSet(Vec<PyValue>),
+}
+
+impl PyValue {
+    /// The name of this value's Python type, as used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PyValue::None => "NoneType",
+            PyValue::Bool(_) => "bool",
+            PyValue::Int(_) | PyValue::BigInt(_) => "int",
+            PyValue::Float(_) => "float",
+            PyValue::Str(_) => "str",
+            PyValue::Bytes(_) => "bytes",
+            PyValue::Tuple(_) => "tuple",
+            PyValue::List(_) => "list",
+            PyValue::Dict(_) => "dict",
+            PyValue::Set(_) => "set",
+            PyValue::FrozenSet(_) => "frozenset",
+        }
+    }
+
+    fn mismatch<T>(&self, expected: &'static str) -> Result<T, LiteralError> {
+        Err(LiteralError::Mismatch {
+            expected,
+            found: self.type_name(),
+        })
+    }
+
+    /// The items of a list, tuple, set or frozenset.
+    fn into_items(self, expected: &'static str) -> Result<Vec<PyValue>, LiteralError> {
+        match self {
+            PyValue::List(items)
+            | PyValue::Tuple(items)
+            | PyValue::Set(items)
+            | PyValue::FrozenSet(items) => Ok(items),
+            other => other.mismatch(expected),
+        }
+    }
+}
+
+impl AsPythonLitteral for PyValue {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            PyValue::None => ().fmt(f),
+            PyValue::Bool(b) => b.fmt(f),
+            PyValue::Int(i) => i.fmt(f),
+            PyValue::BigInt(digits) => f.write_str(digits),
+            PyValue::Float(x) => x.fmt(f),
+            PyValue::Str(s) => s.fmt(f),
+            PyValue::Bytes(bytes) => crate::write_bytes_literal(f, bytes),
+            PyValue::Tuple(items) => {
+                write!(f, "(")?;
+                for x in items {
+                    x.fmt(f)?;
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            PyValue::List(items) => crate::fmt_sequence(f, items),
+            PyValue::Dict(items) => crate::fmt_dict(f, items.iter().map(|(k, v)| (k, v))),
+            PyValue::Set(items) => crate::fmt_set(f, items),
+            PyValue::FrozenSet(items) => match f.precision() {
+                Some(precision) => write!(f, "{:#.*}", precision, FrozenSet(items)),
+                None => write!(f, "{:#}", FrozenSet(items)),
+            },
+        }
+    }
+
+    fn marshal(&self, out: &mut dyn std::io::Write, hashable: bool) -> std::io::Result<bool> {
+        match self {
+            PyValue::Tuple(items) => {
+                crate::data::write_sequence_header(out, true, items.len())?;
+                for x in items {
+                    x.marshal(out, hashable)?;
+                }
+                Ok(true)
+            }
+            PyValue::List(items) => crate::data::marshal_sequence(out, items.iter(), hashable),
+            PyValue::Dict(items) => {
+                crate::data::marshal_dict(out, items.iter().map(|(k, v)| (k, v)))
+            }
+            PyValue::Set(items) => crate::data::marshal_set(out, items.iter(), hashable),
+            PyValue::FrozenSet(items) => crate::data::marshal_set(out, items.iter(), true),
+            value => crate::data::write_marshal(out, value).map(|_| true),
+        }
+    }
+}
+
+/// Forces `fmt_set` into its frozen form.
+struct FrozenSet<'a>(&'a [PyValue]);
+impl<'a> std::fmt::Display for FrozenSet<'a> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        crate::fmt_set(f, self.0)
+    }
+}
+
+/// The error returned when a Python literal can't be parsed or converted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LiteralError {
+    /// The source is not a valid literal; `offset` is in bytes.
+    Syntax { offset: usize, message: String },
+    /// The literal has the wrong Python type for the requested Rust type.
+    Mismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The literal has the right type, but its value doesn't fit the requested Rust type.
+    Invalid(String),
+}
+
+impl std::fmt::Display for LiteralError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            LiteralError::Syntax { offset, message } => {
+                write!(f, "invalid python literal at byte {}: {}", offset, message)
+            }
+            LiteralError::Mismatch { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            LiteralError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for LiteralError {}
+
+/// Parses `source`, a Python literal such as `repr` would print, into a [`PyValue`].
+pub fn parse_python_literal(source: &str) -> Result<PyValue, LiteralError> {
+    let mut parser = Parser {
+        source,
+        position: 0,
+        depth: 0,
+    };
+    parser.skip_blanks();
+    let first = parser.value()?;
+    parser.skip_blanks();
+    // Like `ast.literal_eval`, accept a top-level tuple without parentheses.
+    let value = if parser.eat(',') {
+        let mut items = vec![first];
+        parser.items(None, &mut items)?;
+        PyValue::Tuple(items)
+    } else {
+        first
+    };
+    parser.skip_blanks();
+    if parser.position < source.len() {
+        return parser.error("unexpected trailing characters");
+    }
+    Ok(value)
+}
+
+/// Nesting deeper than this is rejected instead of overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, LiteralError> {
+        Err(LiteralError::Syntax {
+            offset: self.position,
+            message: message.to_owned(),
+        })
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), LiteralError> {
+        self.skip_blanks();
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.error(&format!("expected `{}`", c))
+        }
+    }
+
+    /// Skips whitespace, comments and line continuations.
+    fn skip_blanks(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\n') | Some('\r') | Some('\x0c') => {
+                    self.bump();
+                }
+                Some('\\') if self.rest()[1..].starts_with('\n') => self.position += 2,
+                Some('\\') if self.rest()[1..].starts_with("\r\n") => self.position += 3,
+                Some('#') => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<PyValue, LiteralError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return self.error("literal is nested too deeply");
+        }
+        self.skip_blanks();
+        let value = match self.peek() {
+            None => self.error("unexpected end of literal"),
+            Some('-') | Some('+') => {
+                let negative = self.bump() == Some('-');
+                match self.value()? {
+                    PyValue::Int(i) if negative => Ok(match i.checked_neg() {
+                        Some(i) => PyValue::Int(i),
+                        None => PyValue::BigInt(negate(&i.to_string())),
+                    }),
+                    PyValue::BigInt(digits) if negative => Ok(PyValue::BigInt(negate(&digits))),
+                    PyValue::Float(x) if negative => Ok(PyValue::Float(-x)),
+                    value @ PyValue::Int(_)
+                    | value @ PyValue::BigInt(_)
+                    | value @ PyValue::Float(_) => Ok(value),
+                    _ => self.error("unary operators only apply to numbers"),
+                }
+            }
+            Some(c)
+                if c.is_ascii_digit()
+                    || (c == '.' && self.rest()[1..].starts_with(|c: char| c.is_ascii_digit())) =>
+            {
+                self.number()
+            }
+            Some('\'') | Some('"') => self.strings(),
+            Some('(') => {
+                self.bump();
+                self.skip_blanks();
+                if self.eat(')') {
+                    return self.nested(Ok(PyValue::Tuple(Vec::new())));
+                }
+                let first = self.value()?;
+                self.skip_blanks();
+                if self.eat(')') {
+                    return self.nested(Ok(first));
+                }
+                self.expect(',')?;
+                let mut items = vec![first];
+                self.items(Some(')'), &mut items)?;
+                Ok(PyValue::Tuple(items))
+            }
+            Some('[') => {
+                self.bump();
+                let mut items = Vec::new();
+                self.items(Some(']'), &mut items)?;
+                Ok(PyValue::List(items))
+            }
+            Some('{') => self.braces(),
+            Some(c) if c.is_alphabetic() || c == '_' => self.name(),
+            Some(_) => self.error("unexpected character"),
+        };
+        self.nested(value)
+    }
+
+    fn nested(&mut self, value: Result<PyValue, LiteralError>) -> Result<PyValue, LiteralError> {
+        self.depth -= 1;
+        value
+    }
+
+    /// Parses comma separated values until `close` (or the end of input if `None`), accepting a trailing comma.
+    fn items(&mut self, close: Option<char>, items: &mut Vec<PyValue>) -> Result<(), LiteralError> {
+        loop {
+            self.skip_blanks();
+            match close {
+                Some(close) if self.eat(close) => return Ok(()),
+                None if self.peek().is_none() => return Ok(()),
+                _ => {}
+            }
+            items.push(self.value()?);
+            self.skip_blanks();
+            if !self.eat(',') {
+                return match close {
+                    Some(close) => self.expect(close),
+                    None => Ok(()),
+                };
+            }
+        }
+    }
+
+    fn braces(&mut self) -> Result<PyValue, LiteralError> {
+        self.bump();
+        self.skip_blanks();
+        if self.eat('}') {
+            return Ok(PyValue::Dict(Vec::new()));
+        }
+        let first = self.value()?;
+        self.skip_blanks();
+        if !self.eat(':') {
+            let mut items = vec![first];
+            if self.eat(',') {
+                self.items(Some('}'), &mut items)?;
+            } else {
+                self.expect('}')?;
+            }
+            return Ok(PyValue::Set(items));
+        }
+        let mut items = vec![(first, self.value()?)];
+        loop {
+            self.skip_blanks();
+            if self.eat('}') {
+                return Ok(PyValue::Dict(items));
+            }
+            self.expect(',')?;
+            self.skip_blanks();
+            if self.eat('}') {
+                return Ok(PyValue::Dict(items));
+            }
+            let key = self.value()?;
+            self.expect(':')?;
+            items.push((key, self.value()?));
+        }
+    }
+
+    fn identifier(&mut self) -> &'a str {
+        let start = self.position;
+        while matches!(self.peek(), Some(c) if c.is_alphanumeric() || c == '_') {
+            self.bump();
+        }
+        &self.source[start..self.position]
+    }
+
+    /// Parses keywords, string prefixes and the calls this crate emits.
+    fn name(&mut self) -> Result<PyValue, LiteralError> {
+        let start = self.position;
+        let name = self.identifier();
+        if matches!(self.peek(), Some('\'') | Some('"')) && is_string_prefix(name) {
+            self.position = start;
+            return self.strings();
+        }
+        match name {
+            "None" => Ok(PyValue::None),
+            "True" => Ok(PyValue::Bool(true)),
+            "False" => Ok(PyValue::Bool(false)),
+            "inf" => Ok(PyValue::Float(f64::INFINITY)),
+            "nan" => Ok(PyValue::Float(f64::NAN)),
+            "float" => {
+                self.expect('(')?;
+                self.skip_blanks();
+                let value = match self.value()? {
+                    PyValue::Str(s) => match s.trim().to_lowercase().as_str() {
+                        "inf" | "+inf" | "infinity" | "+infinity" => PyValue::Float(f64::INFINITY),
+                        "-inf" | "-infinity" => PyValue::Float(f64::NEG_INFINITY),
+                        "nan" | "+nan" | "-nan" => PyValue::Float(f64::NAN),
+                        other => match other.replace('_', "").parse() {
+                            Ok(x) => PyValue::Float(x),
+                            Err(_) => return self.error("invalid float"),
+                        },
+                    },
+                    PyValue::Int(i) => PyValue::Float(i as f64),
+                    value @ PyValue::Float(_) => value,
+                    _ => return self.error("unsupported float() argument"),
+                };
+                self.expect(')')?;
+                Ok(value)
+            }
+            "set" | "frozenset" => {
+                self.expect('(')?;
+                self.skip_blanks();
+                let items = if self.peek() == Some(')') {
+                    Vec::new()
+                } else {
+                    match self.value()? {
+                        PyValue::Dict(items) => items.into_iter().map(|(k, _)| k).collect(),
+                        PyValue::Str(_) | PyValue::Bytes(_) => {
+                            return self.error("unsupported set() argument")
+                        }
+                        value => value.into_items("iterable")?,
+                    }
+                };
+                self.expect(')')?;
+                Ok(if name == "set" {
+                    PyValue::Set(items)
+                } else {
+                    PyValue::FrozenSet(items)
+                })
+            }
+            _ => {
+                self.position = start;
+                self.error(&format!("unsupported name `{}`", name))
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<PyValue, LiteralError> {
+        let start = self.position;
+        let radix = match self.rest().get(..2).map(str::to_ascii_lowercase).as_deref() {
+            Some("0x") => 16,
+            Some("0o") => 8,
+            Some("0b") => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.position += 2;
+            let digits_start = self.position;
+            while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_') {
+                self.bump();
+            }
+            let digits = self.source[digits_start..self.position].replace('_', "");
+            if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+                self.position = start;
+                return self.error("invalid integer");
+            }
+            return Ok(int_from_digits(&digits, radix));
+        }
+        let mut is_float = false;
+        while let Some(c) = self.peek() {
+            match c {
+                '0'..='9' | '_' => {}
+                '.' => is_float = true,
+                'e' | 'E' => {
+                    is_float = true;
+                    if matches!(self.rest()[1..].chars().next(), Some('+') | Some('-')) {
+                        self.bump();
+                    }
+                }
+                _ => break,
+            }
+            self.bump();
+        }
+        if matches!(self.peek(), Some('j') | Some('J')) {
+            return self.error("complex numbers are not supported");
+        }
+        let text = self.source[start..self.position].replace('_', "");
+        if is_float {
+            match text.parse() {
+                Ok(x) => Ok(PyValue::Float(x)),
+                Err(_) => {
+                    self.position = start;
+                    self.error("invalid float")
+                }
+            }
+        } else if text.len() > 1 && text.starts_with('0') && text.chars().any(|c| c != '0') {
+            self.position = start;
+            self.error("leading zeros are not allowed in decimal integers")
+        } else {
+            Ok(int_from_digits(&text, 10))
+        }
+    }
+
+    /// Parses one or more adjacent string (or bytes) literals, which Python concatenates.
+    fn strings(&mut self) -> Result<PyValue, LiteralError> {
+        let mut result: Option<PyValue> = None;
+        loop {
+            let start = self.position;
+            let prefix = self.identifier().to_lowercase();
+            if !matches!(self.peek(), Some('\'') | Some('"')) || !is_string_prefix(&prefix) {
+                self.position = start;
+                break;
+            }
+            let raw = prefix.contains('r');
+            let bytes = prefix.contains('b');
+            let next = self.string_body(raw, bytes)?;
+            result = Some(match (result, next) {
+                (None, next) => next,
+                (Some(PyValue::Str(mut a)), PyValue::Str(b)) => {
+                    a.push_str(&b);
+                    PyValue::Str(a)
+                }
+                (Some(PyValue::Bytes(mut a)), PyValue::Bytes(b)) => {
+                    a.extend(b);
+                    PyValue::Bytes(a)
+                }
+                _ => {
+                    self.position = start;
+                    return self.error("cannot mix bytes and nonbytes literals");
+                }
+            });
+            let before_blanks = self.position;
+            self.skip_blanks();
+            if !matches!(self.peek(), Some(c) if c == '\'' || c == '"' || c.is_alphabetic()) {
+                self.position = before_blanks;
+                break;
+            }
+        }
+        match result {
+            Some(value) => Ok(value),
+            None => self.error("expected a string"),
+        }
+    }
+
+    fn string_body(&mut self, raw: bool, bytes: bool) -> Result<PyValue, LiteralError> {
+        let quote = self.bump().unwrap();
+        let triple = self.rest().starts_with(&format!("{}{}", quote, quote)[..]);
+        if triple {
+            self.position += 2;
+        }
+        let mut out = String::new();
+        let mut out_bytes = Vec::new();
+        loop {
+            let c = match self.bump() {
+                None => return self.error("unterminated string literal"),
+                Some(c) => c,
+            };
+            if c == quote {
+                if !triple {
+                    break;
+                }
+                if self.rest().starts_with(&format!("{}{}", quote, quote)[..]) {
+                    self.position += 2;
+                    break;
+                }
+            }
+            if (c == '\n' || c == '\r') && !triple {
+                return self.error("unterminated string literal");
+            }
+            if bytes && !c.is_ascii() {
+                return self.error("bytes can only contain ASCII literal characters");
+            }
+            if c != '\\' || raw {
+                if raw && c == '\\' {
+                    // A backslash still prevents the next quote from closing a raw string.
+                    if let Some(next) = self.peek() {
+                        self.bump();
+                        push(&mut out, &mut out_bytes, bytes, '\\' as u32);
+                        push(&mut out, &mut out_bytes, bytes, next as u32);
+                        continue;
+                    }
+                }
+                push(&mut out, &mut out_bytes, bytes, c as u32);
+                continue;
+            }
+            let escape_start = self.position - 1;
+            let escaped = match self.bump() {
+                None => return self.error("unterminated string literal"),
+                Some(c) => c,
+            };
+            let code = match escaped {
+                '\n' => continue,
+                '\r' => {
+                    self.eat('\n');
+                    continue;
+                }
+                '\\' | '\'' | '"' => escaped as u32,
+                'a' => 0x07,
+                'b' => 0x08,
+                'f' => 0x0c,
+                'n' => 0x0a,
+                'r' => 0x0d,
+                't' => 0x09,
+                'v' => 0x0b,
+                '0'..='7' => {
+                    let mut code = escaped.to_digit(8).unwrap();
+                    for _ in 0..2 {
+                        match self.peek().and_then(|c| c.to_digit(8)) {
+                            Some(digit) => {
+                                self.bump();
+                                code = code * 8 + digit;
+                            }
+                            None => break,
+                        }
+                    }
+                    if bytes && code > 0xff {
+                        self.position = escape_start;
+                        return self.error("octal escape out of range for bytes");
+                    }
+                    code
+                }
+                'x' => self.hex_escape(2, escape_start)?,
+                'u' if !bytes => self.hex_escape(4, escape_start)?,
+                'U' if !bytes => self.hex_escape(8, escape_start)?,
+                'N' if !bytes => {
+                    self.position = escape_start;
+                    return self.error("\\N{...} escapes are not supported");
+                }
+                // Python keeps unknown escapes as they are.
+                other => {
+                    push(&mut out, &mut out_bytes, bytes, '\\' as u32);
+                    if bytes && !other.is_ascii() {
+                        return self.error("bytes can only contain ASCII literal characters");
+                    }
+                    other as u32
+                }
+            };
+            if !bytes && std::char::from_u32(code).is_none() {
+                self.position = escape_start;
+                return self.error("escape is not a valid unicode scalar value");
+            }
+            push(&mut out, &mut out_bytes, bytes, code);
+        }
+        Ok(if bytes {
+            PyValue::Bytes(out_bytes)
+        } else {
+            PyValue::Str(out)
+        })
+    }
+
+    fn hex_escape(&mut self, len: usize, escape_start: usize) -> Result<u32, LiteralError> {
+        let digits = self.rest().get(..len).unwrap_or("");
+        if digits.len() != len || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            self.position = escape_start;
+            return self.error("truncated hex escape");
+        }
+        self.position += len;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+}
+
+fn push(out: &mut String, out_bytes: &mut Vec<u8>, bytes: bool, code: u32) {
+    if bytes {
+        out_bytes.push(code as u8)
+    } else {
+        out.push(std::char::from_u32(code).unwrap())
+    }
+}
+
+fn is_string_prefix(prefix: &str) -> bool {
+    matches!(
+        prefix.to_lowercase().as_str(),
+        "" | "r" | "u" | "b" | "br" | "rb"
+    )
+}
+
+/// Builds an int from valid `digits` in `radix`, falling back to a decimal `BigInt` if it overflows.
+fn int_from_digits(digits: &str, radix: u32) -> PyValue {
+    if let Ok(i) = i128::from_str_radix(digits, radix) {
+        return PyValue::Int(i);
+    }
+    // Schoolbook base conversion, least significant decimal digit first.
+    let mut decimal: Vec<u32> = vec![0];
+    for digit in digits.chars().map(|c| c.to_digit(radix).unwrap()) {
+        let mut carry = digit;
+        for d in decimal.iter_mut() {
+            let v = *d * radix + carry;
+            *d = v % 10;
+            carry = v / 10;
+        }
+        while carry > 0 {
+            decimal.push(carry % 10);
+            carry /= 10;
+        }
+    }
+    while decimal.len() > 1 && decimal.last() == Some(&0) {
+        decimal.pop();
+    }
+    PyValue::BigInt(
+        decimal
+            .iter()
+            .rev()
+            .map(|d| std::char::from_digit(*d, 10).unwrap())
+            .collect(),
+    )
+}
+
+fn negate(digits: &str) -> String {
+    match digits.strip_prefix('-') {
+        Some(positive) => positive.to_owned(),
+        None => format!("-{}", digits),
+    }
+}
+
+/// Types that can be built from a Python literal, typically one printed by a script.
+pub trait FromPythonLiteral: Sized {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError>;
+
+    /// Parses `source` and converts it.
+    fn from_python_literal(source: &str) -> Result<Self, LiteralError> {
+        Self::from_py_value(parse_python_literal(source)?)
+    }
+}
+
+impl FromPythonLiteral for PyValue {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        Ok(value)
+    }
+}
+
+macro_rules! from_py_int_impl {
+    ($($t: ty),+) => {
+        $(
+            impl FromPythonLiteral for $t {
+                fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+                    let out_of_range = |value: &dyn std::fmt::Display| {
+                        LiteralError::Invalid(format!(
+                            "{} is out of range for {}",
+                            value,
+                            stringify!($t)
+                        ))
+                    };
+                    match value {
+                        PyValue::Int(i) => <$t as std::convert::TryFrom<i128>>::try_from(i)
+                            .map_err(|_| out_of_range(&i)),
+                        PyValue::BigInt(digits) => digits.parse().map_err(|_| out_of_range(&digits)),
+                        PyValue::Bool(b) => Ok(b as $t),
+                        other => other.mismatch("int"),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+from_py_int_impl!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+macro_rules! from_py_float_impl {
+    ($($t: ty),+) => {
+        $(
+            impl FromPythonLiteral for $t {
+                fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+                    match value {
+                        PyValue::Float(x) => Ok(x as $t),
+                        PyValue::Int(i) => Ok(i as $t),
+                        PyValue::BigInt(digits) => Ok(digits.parse().unwrap_or(<$t>::NAN)),
+                        other => other.mismatch("float"),
+                    }
+                }
+            }
+        )+
+    };
+}
+
+from_py_float_impl!(f32, f64);
+
+impl FromPythonLiteral for bool {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        match value {
+            PyValue::Bool(b) => Ok(b),
+            other => other.mismatch("bool"),
+        }
+    }
+}
+
+impl FromPythonLiteral for String {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        match value {
+            PyValue::Str(s) => Ok(s),
+            other => other.mismatch("str"),
+        }
+    }
+}
+
+impl FromPythonLiteral for char {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        let s = String::from_py_value(value)?;
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(LiteralError::Invalid(format!(
+                "expected a string of length 1, found {} characters",
+                s.chars().count()
+            ))),
+        }
+    }
+}
+
+impl FromPythonLiteral for () {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        match value {
+            PyValue::None => Ok(()),
+            other => other.mismatch("None"),
+        }
+    }
+}
+
+impl<T: FromPythonLiteral> FromPythonLiteral for Option<T> {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        match value {
+            PyValue::None => Ok(None),
+            value => T::from_py_value(value).map(Some),
+        }
+    }
+}
+
+fn collect_items<T: FromPythonLiteral, C: std::iter::FromIterator<T>>(
+    value: PyValue,
+    expected: &'static str,
+) -> Result<C, LiteralError> {
+    value
+        .into_items(expected)?
+        .into_iter()
+        .map(T::from_py_value)
+        .collect()
+}
+
+fn collect_dict<K: FromPythonLiteral, V: FromPythonLiteral, C: std::iter::FromIterator<(K, V)>>(
+    value: PyValue,
+) -> Result<C, LiteralError> {
+    match value {
+        PyValue::Dict(items) => items
+            .into_iter()
+            .map(|(k, v)| Ok((K::from_py_value(k)?, V::from_py_value(v)?)))
+            .collect(),
+        other => other.mismatch("dict"),
+    }
+}
+
+impl<T: FromPythonLiteral> FromPythonLiteral for Vec<T> {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        collect_items(value, "list")
+    }
+}
+
+impl<T: FromPythonLiteral> FromPythonLiteral for std::collections::VecDeque<T> {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        collect_items(value, "list")
+    }
+}
+
+impl<T: FromPythonLiteral, const N: usize> FromPythonLiteral for [T; N] {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        let items: Vec<T> = collect_items(value, "list")?;
+        let len = items.len();
+        items
+            .try_into()
+            .map_err(|_| LiteralError::Invalid(format!("expected {} items, found {}", N, len)))
+    }
+}
+
+impl<T: FromPythonLiteral + Eq + std::hash::Hash, S: std::hash::BuildHasher + Default>
+    FromPythonLiteral for std::collections::HashSet<T, S>
+{
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        collect_items(value, "set")
+    }
+}
+
+impl<T: FromPythonLiteral + Ord> FromPythonLiteral for std::collections::BTreeSet<T> {
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        collect_items(value, "set")
+    }
+}
+
+impl<K, V, S> FromPythonLiteral for std::collections::HashMap<K, V, S>
+where
+    K: FromPythonLiteral + Eq + std::hash::Hash,
+    V: FromPythonLiteral,
+    S: std::hash::BuildHasher + Default,
+{
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        collect_dict(value)
+    }
+}
+
+impl<K: FromPythonLiteral + Ord, V: FromPythonLiteral> FromPythonLiteral
+    for std::collections::BTreeMap<K, V>
+{
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        collect_dict(value)
+    }
+}
+
+macro_rules! from_py_tuple_impl {
+    ($len: expr, $($t: ident),+) => {
+        impl<$($t: FromPythonLiteral),+> FromPythonLiteral for ($($t,)+) {
+            fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+                let items = match value {
+                    PyValue::Tuple(items) | PyValue::List(items) => items,
+                    other => return other.mismatch("tuple"),
+                };
+                if items.len() != $len {
+                    return Err(LiteralError::Invalid(format!(
+                        "expected a tuple of {} items, found {}",
+                        $len,
+                        items.len()
+                    )));
+                }
+                let mut items = items.into_iter();
+                Ok(($($t::from_py_value(items.next().unwrap())?,)+))
+            }
+        }
+    };
+}
+
+from_py_tuple_impl!(1, A);
+from_py_tuple_impl!(2, A, B);
+from_py_tuple_impl!(3, A, B, C);
+from_py_tuple_impl!(4, A, B, C, D);
+from_py_tuple_impl!(5, A, B, C, D, E);
+from_py_tuple_impl!(6, A, B, C, D, E, F);
+from_py_tuple_impl!(7, A, B, C, D, E, F, G);
+from_py_tuple_impl!(8, A, B, C, D, E, F, G, H);
+from_py_tuple_impl!(9, A, B, C, D, E, F, G, H, I);
+from_py_tuple_impl!(10, A, B, C, D, E, F, G, H, I, J);
+from_py_tuple_impl!(11, A, B, C, D, E, F, G, H, I, J, K);
+from_py_tuple_impl!(12, A, B, C, D, E, F, G, H, I, J, K, L);
+
+macro_rules! from_py_wrapper_impl {
+    ($($t: ty),+) => {
+        $(
+            impl<T: FromPythonLiteral> FromPythonLiteral for $t {
+                fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+                    T::from_py_value(value).map(Into::into)
+                }
+            }
+        )+
+    };
+}
+
+from_py_wrapper_impl!(Box<T>, std::rc::Rc<T>, std::sync::Arc<T>);
+
+impl<'a, T: ToOwned + ?Sized> FromPythonLiteral for std::borrow::Cow<'a, T>
+where
+    T::Owned: FromPythonLiteral,
+{
+    fn from_py_value(value: PyValue) -> Result<Self, LiteralError> {
+        T::Owned::from_py_value(value).map(std::borrow::Cow::Owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PythonLiteral, PythonProgram};
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+    fn parse(source: &str) -> PyValue {
+        parse_python_literal(source).unwrap_or_else(|e| panic!("{:?}: {}", source, e))
+    }
+
+    #[test]
+    fn parses_literal_eval_subset() {
+        assert_eq!(parse(" None "), PyValue::None);
+        assert_eq!(parse("True"), PyValue::Bool(true));
+        assert_eq!(parse("-0x_ff"), PyValue::Int(-255));
+        assert_eq!(parse("0o17 # comment"), PyValue::Int(15));
+        assert_eq!(parse("0b101"), PyValue::Int(5));
+        assert_eq!(parse("1_000"), PyValue::Int(1000));
+        assert_eq!(
+            parse("-340282366920938463463374607431768211456"),
+            PyValue::BigInt("-340282366920938463463374607431768211456".to_owned())
+        );
+        assert_eq!(
+            parse("0x100000000000000000000000000000000"),
+            PyValue::BigInt("340282366920938463463374607431768211456".to_owned())
+        );
+        assert_eq!(parse("1.5e-3"), PyValue::Float(1.5e-3));
+        assert_eq!(parse(".5"), PyValue::Float(0.5));
+        assert_eq!(parse("1."), PyValue::Float(1.0));
+        assert_eq!(parse("-inf"), PyValue::Float(f64::NEG_INFINITY));
+        assert_eq!(parse("float('-inf')"), PyValue::Float(f64::NEG_INFINITY));
+        assert!(matches!(parse("nan"), PyValue::Float(x) if x.is_nan()));
+        assert_eq!(
+            parse(
+                r#"'a\'b' "c\"d" r'\n' u'\x41\101é\U0001F40D' '\
+'"#
+            ),
+            PyValue::Str("a'bc\"d\\nAAé🐍".to_owned())
+        );
+        assert_eq!(
+            parse(r#"'''tri"ple''' """quo'''te""" '\a\b\f\v\q'"#),
+            PyValue::Str("tri\"plequo'''te\x07\x08\x0c\x0b\\q".to_owned())
+        );
+        assert_eq!(parse(r"r'\''"), PyValue::Str("\\'".to_owned()));
+        assert_eq!(
+            parse(r"b'\x00\xff' Rb'\x'"),
+            PyValue::Bytes(vec![0, 0xff, b'\\', b'x'])
+        );
+        assert_eq!(
+            parse("(1,), (), (2), [3,], {}, {4}, {5: 6,}, set(), frozenset({7})"),
+            PyValue::Tuple(vec![
+                PyValue::Tuple(vec![PyValue::Int(1)]),
+                PyValue::Tuple(vec![]),
+                PyValue::Int(2),
+                PyValue::List(vec![PyValue::Int(3)]),
+                PyValue::Dict(vec![]),
+                PyValue::Set(vec![PyValue::Int(4)]),
+                PyValue::Dict(vec![(PyValue::Int(5), PyValue::Int(6))]),
+                PyValue::Set(vec![]),
+                PyValue::FrozenSet(vec![PyValue::Int(7)]),
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_literals() {
+        for source in [
+            "",
+            "[1, 2",
+            "'unterminated",
+            "'new\nline'",
+            "b'é'",
+            "'a' b'b'",
+            "01",
+            "1j",
+            "os.system('ls')",
+            "__import__('os')",
+            "'\\ud800'",
+            "'\\N{BULLET}'",
+            "{1: 2, 3}",
+            "1 2",
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(
+                    parse_python_literal(source),
+                    Err(LiteralError::Syntax { .. })
+                ),
+                "{:?}",
+                source
+            );
+        }
+        let deep = "[".repeat(MAX_DEPTH + 1) + &"]".repeat(MAX_DEPTH + 1);
+        assert!(parse_python_literal(&deep).is_err());
+        assert_eq!(
+            u8::from_python_literal("256"),
+            Err(LiteralError::Invalid(
+                "256 is out of range for u8".to_owned()
+            ))
+        );
+        assert_eq!(
+            String::from_python_literal("1"),
+            Err(LiteralError::Mismatch {
+                expected: "str",
+                found: "int"
+            })
+        );
+        assert!(<(u8, u8)>::from_python_literal("(1, 2, 3)").is_err());
+        assert!(char::from_python_literal("'ab'").is_err());
+    }
+
+    /// Checks that `value` survives both `AsPythonLitteral` -> parse and a trip through Python's `repr`.
+    fn round_trip<T>(program: &mut PythonProgram, name: &str, value: &T)
+    where
+        T: AsPythonLitteral + FromPythonLiteral + PartialEq + std::fmt::Debug,
+    {
+        let literal = PythonLiteral(value).to_string();
+        assert_eq!(
+            &T::from_python_literal(&literal).unwrap(),
+            value,
+            "{}",
+            literal
+        );
+        program.define_variable(name, value);
+        program.write_line(&format!("print(repr({}))", name));
+    }
+
+    #[test]
+    fn values_round_trip_through_python() {
+        let mut program = PythonProgram::new();
+        round_trip(&mut program, "a", &u128::MAX);
+        round_trip(&mut program, "b", &i128::MIN);
+        round_trip(&mut program, "c", &vec![0.1, -0.0, f64::INFINITY, 1e300]);
+        round_trip(&mut program, "d", &"it's \"all\"\n\0 🐍\u{2028}".to_owned());
+        round_trip(&mut program, "e", &(true, 'x', (), Some(3u8), None::<i8>));
+        round_trip(&mut program, "f", &[1u16, 2, 3]);
+        round_trip(&mut program, "g", &VecDeque::from(vec![-1i64, 1]));
+        round_trip(
+            &mut program,
+            "h",
+            &vec![(1u8, "one".to_owned())]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>(),
+        );
+        round_trip(
+            &mut program,
+            "i",
+            &vec![vec![1u8], vec![]].into_iter().collect::<HashSet<_>>(),
+        );
+        round_trip(
+            &mut program,
+            "j",
+            &vec![vec![2u8].into_iter().collect::<BTreeSet<_>>()]
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+        );
+        round_trip(
+            &mut program,
+            "k",
+            &vec![((1i32, 2i32), Box::new(0.5f32))]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+        );
+        let output = program.run().unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        let mut next = || lines.next().unwrap();
+        assert_eq!(u128::from_python_literal(next()), Ok(u128::MAX));
+        assert_eq!(i128::from_python_literal(next()), Ok(i128::MIN));
+        let c = Vec::<f64>::from_python_literal(next()).unwrap();
+        assert_eq!(c, vec![0.1, -0.0, f64::INFINITY, 1e300]);
+        assert!(c[1].is_sign_negative());
+        assert_eq!(
+            String::from_python_literal(next()).unwrap(),
+            "it's \"all\"\n\0 🐍\u{2028}"
+        );
+        assert_eq!(
+            <(bool, char, (), Option<u8>, Option<i8>)>::from_python_literal(next()),
+            Ok((true, 'x', (), Some(3), None))
+        );
+        assert_eq!(<[u16; 3]>::from_python_literal(next()), Ok([1, 2, 3]));
+        assert_eq!(
+            VecDeque::<i64>::from_python_literal(next()),
+            Ok(VecDeque::from(vec![-1, 1]))
+        );
+        assert_eq!(
+            BTreeMap::<u8, String>::from_python_literal(next()).unwrap()[&1],
+            "one"
+        );
+        assert_eq!(
+            HashSet::<Vec<u8>>::from_python_literal(next())
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            BTreeSet::<BTreeSet<u8>>::from_python_literal(next())
+                .unwrap()
+                .into_iter()
+                .next()
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(
+            *HashMap::<(i32, i32), Box<f32>>::from_python_literal(next()).unwrap()[&(1, 2)],
+            0.5
+        );
+    }
+}
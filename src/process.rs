@@ -0,0 +1,473 @@
+//! Handles on scripts running in the background, which can be waited on with a timeout or killed,
+//! and whose output can be followed line by line.
+use crate::{Interpreter, PycallError};
+use std::io::{BufRead, Write};
+use std::process::{Child, ExitStatus, Output, Stdio};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// The output stream a line was written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A line written by a running script, without its line terminator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub line: String,
+}
+
+/// How a program's output is followed while it runs. By default, it is only collected for the final `Output`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpawnOptions {
+    lines: bool,
+    tee: bool,
+}
+
+impl SpawnOptions {
+    pub fn new() -> SpawnOptions {
+        SpawnOptions::default()
+    }
+
+    /// Makes each line available through `RunningProgram::lines` as soon as it is written.
+    pub fn lines(&mut self, lines: bool) -> &mut Self {
+        self.lines = lines;
+        self
+    }
+
+    /// Copies the script's stdout and stderr to this process' own as they are written.
+    pub fn tee(&mut self, tee: bool) -> &mut Self {
+        self.tee = tee;
+        self
+    }
+}
+
+/// A python script running in its own process group.
+///
+/// Killing it kills the whole group, so that subprocesses started by the script don't outlive it.
+/// Dropping a program that is still running kills it.
+pub struct RunningProgram {
+    child: Child,
+    status: Option<ExitStatus>,
+    stdout: Option<std::thread::JoinHandle<std::io::Result<Vec<u8>>>>,
+    stderr: Option<std::thread::JoinHandle<std::io::Result<Vec<u8>>>>,
+    lines: Option<Receiver<OutputLine>>,
+    // The script and its side files must exist until python has read them.
+    _script: tempfile::NamedTempFile,
+    _data_files: Vec<std::sync::Arc<tempfile::TempPath>>,
+}
+
+impl RunningProgram {
+    /// Starts `interpreter` on `script`, collecting its output in the background.
+    pub(crate) fn spawn(
+        interpreter: &Interpreter,
+        script: tempfile::NamedTempFile,
+        data_files: Vec<std::sync::Arc<tempfile::TempPath>>,
+        options: &SpawnOptions,
+    ) -> Result<RunningProgram, PycallError> {
+        let mut command = interpreter.command();
+        if options.lines || options.tee {
+            // Python buffers its output when it isn't a terminal, which would delay the lines.
+            command.env("PYTHONUNBUFFERED", "1");
+        }
+        command
+            .arg(script.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn().map_err(|e| interpreter.start_error(e))?;
+        let (sender, lines) = match options.lines {
+            true => {
+                let (sender, receiver) = std::sync::mpsc::channel();
+                (Some(sender), Some(receiver))
+            }
+            false => (None, None),
+        };
+        let stdout = child.stdout.take().map(|pipe| {
+            let tee = options
+                .tee
+                .then(|| Box::new(std::io::stdout()) as Box<dyn Write + Send>);
+            collect(pipe, Stream::Stdout, tee, sender.clone())
+        });
+        let stderr = child.stderr.take().map(|pipe| {
+            let tee = options
+                .tee
+                .then(|| Box::new(std::io::stderr()) as Box<dyn Write + Send>);
+            collect(pipe, Stream::Stderr, tee, sender)
+        });
+        Ok(RunningProgram {
+            child,
+            status: None,
+            stdout,
+            stderr,
+            lines,
+            _script: script,
+            _data_files: data_files,
+        })
+    }
+
+    /// The OS identifier of the python process, which is also the identifier of its process group on unix.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// The lines written by the script, in the order they were read, until it closes both its stdout and stderr.
+    /// Blocks while waiting for the next line. Empty unless the program was spawned with `SpawnOptions::lines`.
+    pub fn lines(&mut self) -> impl Iterator<Item = OutputLine> + '_ {
+        self.lines.iter().flat_map(|lines| lines.iter())
+    }
+
+    /// Calls `f` on each line written by the script, then waits for it to finish and collects its output.
+    pub fn for_each_line<F: FnMut(&OutputLine)>(mut self, mut f: F) -> Result<Output, PycallError> {
+        for line in self.lines() {
+            f(&line);
+        }
+        self.wait_with_output()
+    }
+
+    /// Returns the exit status if the script has finished, without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, PycallError> {
+        if self.status.is_none() {
+            self.status = self.child.try_wait().map_err(PycallError::Io)?;
+        }
+        Ok(self.status)
+    }
+
+    /// Waits up to `timeout` for the script to finish, returning `None` if it is still running.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>, PycallError> {
+        let deadline = Instant::now() + timeout;
+        let mut pause = Duration::from_millis(1);
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            std::thread::sleep(pause.min(deadline - now));
+            pause = (pause * 2).min(Duration::from_millis(20));
+        }
+    }
+
+    /// Kills the script and every process in its group, then reaps it.
+    /// Killing a script that already finished still kills the subprocesses it left running.
+    pub fn kill(&mut self) -> Result<(), PycallError> {
+        self.kill_group();
+        if self.status.is_none() {
+            self.child.kill().map_err(PycallError::Io)?;
+            self.status = Some(self.child.wait().map_err(PycallError::Io)?);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn kill_group(&self) {
+        // The group's id is the python process' id. It can't be reused while python isn't reaped,
+        // nor afterwards while any process of the group lives.
+        unsafe {
+            libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn kill_group(&self) {}
+
+    /// Waits for the script to finish and collects its output.
+    pub fn wait_with_output(self) -> Result<Output, PycallError> {
+        self.output_until(None)
+    }
+
+    /// Waits up to `timeout` for the script to finish and collects its output.
+    /// The output is complete once every process holding it open, subprocesses included, has closed it:
+    /// if that takes longer than `timeout`, the script's process group is killed and `PycallError::Timeout` returned.
+    pub fn wait_with_output_timeout(self, timeout: Duration) -> Result<Output, PycallError> {
+        self.output_until(Some(Instant::now() + timeout))
+    }
+
+    fn output_until(mut self, deadline: Option<Instant>) -> Result<Output, PycallError> {
+        let status = match (self.status, deadline) {
+            (Some(status), _) => status,
+            (None, None) => self.child.wait().map_err(PycallError::Io)?,
+            (None, Some(deadline)) => {
+                match self.wait_timeout(deadline.saturating_duration_since(Instant::now()))? {
+                    Some(status) => status,
+                    None => {
+                        self.kill()?;
+                        return Err(PycallError::Timeout);
+                    }
+                }
+            }
+        };
+        self.status = Some(status);
+        if let Some(deadline) = deadline {
+            let mut pause = Duration::from_millis(1);
+            while !self
+                .stdout
+                .iter()
+                .chain(&self.stderr)
+                .all(|handle| handle.is_finished())
+            {
+                let now = Instant::now();
+                if now >= deadline {
+                    self.kill()?;
+                    return Err(PycallError::Timeout);
+                }
+                std::thread::sleep(pause.min(deadline - now));
+                pause = (pause * 2).min(Duration::from_millis(20));
+            }
+        }
+        let join = |handle: Option<std::thread::JoinHandle<std::io::Result<Vec<u8>>>>| match handle
+            .map(|handle| handle.join())
+        {
+            Some(Ok(result)) => result.map_err(PycallError::Io),
+            Some(Err(_)) => Err(PycallError::Io(std::io::Error::other(
+                "the output collector panicked",
+            ))),
+            None => Ok(Vec::new()),
+        };
+        let stdout = join(self.stdout.take())?;
+        let stderr = join(self.stderr.take())?;
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl Drop for RunningProgram {
+    fn drop(&mut self) {
+        if self.status.is_none() {
+            let _ = self.kill();
+        }
+    }
+}
+
+/// Reads `pipe` to its end, copying it to `tee` and sending its lines to `lines` on the way.
+fn collect<R: std::io::Read + Send + 'static>(
+    pipe: R,
+    stream: Stream,
+    mut tee: Option<Box<dyn Write + Send>>,
+    lines: Option<Sender<OutputLine>>,
+) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+    std::thread::spawn(move || {
+        let mut pipe = std::io::BufReader::new(pipe);
+        let mut buffer = Vec::new();
+        loop {
+            let start = buffer.len();
+            if pipe.read_until(b'\n', &mut buffer)? == 0 {
+                return Ok(buffer);
+            }
+            let line = &buffer[start..];
+            if let Some(tee) = &mut tee {
+                // Failing to show the output must not lose it.
+                let _ = tee.write_all(line).and_then(|_| tee.flush());
+            }
+            if let Some(lines) = &lines {
+                let line = line.strip_suffix(b"\n").unwrap_or(line);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                // The receiver may be gone, the output is still collected.
+                let _ = lines.send(OutputLine {
+                    stream,
+                    line: String::from_utf8_lossy(line).into_owned(),
+                });
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputLine, SpawnOptions, Stream};
+    use crate::{PycallError, PythonProgram};
+    use std::time::{Duration, Instant};
+
+    fn busy_loop() -> PythonProgram {
+        let mut program = PythonProgram::new();
+        program.r#while("True").write_line("pass").end_block();
+        program
+    }
+
+    #[test]
+    fn run_with_timeout() {
+        let start = Instant::now();
+        assert!(matches!(
+            busy_loop().run_with_timeout(Duration::from_millis(200)),
+            Err(PycallError::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let mut program = PythonProgram::new();
+        program.write_line("print('done')");
+        let output = program.run_with_timeout(Duration::from_secs(60)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+    }
+
+    #[test]
+    fn running_program() {
+        let mut running = busy_loop().spawn().unwrap();
+        assert_eq!(running.try_wait().unwrap(), None);
+        assert_eq!(
+            running.wait_timeout(Duration::from_millis(100)).unwrap(),
+            None
+        );
+        running.kill().unwrap();
+        let status = running.try_wait().unwrap().unwrap();
+        assert!(!status.success());
+        // Killing twice is fine.
+        running.kill().unwrap();
+        assert_eq!(running.wait_with_output().unwrap().status, status);
+
+        let mut program = PythonProgram::new();
+        program.write_line("import sys").write_line("sys.exit(2)");
+        let mut running = program.spawn().unwrap();
+        let status = running.wait_timeout(Duration::from_secs(60)).unwrap();
+        assert_eq!(status.unwrap().code(), Some(2));
+    }
+
+    /// Starts a script that runs `subprocess` in the background, and waits for the subprocess' pid.
+    #[cfg(target_os = "linux")]
+    fn spawn_subprocess(subprocess: &str, then: &str) -> (super::RunningProgram, u32) {
+        let pid_file = tempfile::NamedTempFile::new().unwrap();
+        let mut program = PythonProgram::new();
+        program
+            .import("subprocess, sys")
+            .define_variable("path", &*pid_file.path().to_string_lossy());
+        program
+            .write_line(&format!("child = subprocess.Popen({})", subprocess))
+            .write_line("open(path, 'w').write(str(child.pid))")
+            .write_line(then);
+        let running = program.spawn().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let pid = loop {
+            let pid = std::fs::read_to_string(pid_file.path()).unwrap();
+            if let Ok(pid) = pid.trim().parse::<u32>() {
+                break pid;
+            }
+            assert!(Instant::now() < deadline, "the subprocess never started");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        (running, pid)
+    }
+
+    /// Checks that the process `pid` stops running soon.
+    #[cfg(target_os = "linux")]
+    fn assert_stops(pid: u32) {
+        // The orphaned subprocess may linger as a zombie until it is reaped, but it must not run.
+        let stat = format!("/proc/{}/stat", pid);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while let Ok(stat) = std::fs::read_to_string(&stat) {
+            let state = stat.rsplit(") ").next().unwrap().chars().next();
+            if state == Some('Z') {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "the subprocess survived: {}",
+                stat
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kill_reaches_subprocesses() {
+        let (mut running, pid) = spawn_subprocess(
+            "[sys.executable, '-c', 'while True: pass']",
+            "while True: pass",
+        );
+        running.kill().unwrap();
+        assert_stops(pid);
+
+        // Subprocesses are killed even once the script has finished.
+        let (mut running, pid) = spawn_subprocess(
+            "['sleep', '60'], stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL",
+            "pass",
+        );
+        let status = running.wait_timeout(Duration::from_secs(30)).unwrap();
+        assert!(status.unwrap().success());
+        running.kill().unwrap();
+        assert_stops(pid);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn timeouts_cover_subprocesses() {
+        // The subprocess keeps the script's output open after it exits.
+        let (running, pid) = spawn_subprocess("['sleep', '60']", "print('done')");
+        let start = Instant::now();
+        assert!(matches!(
+            running.wait_with_output_timeout(Duration::from_secs(1)),
+            Err(PycallError::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_stops(pid);
+
+        let mut program = PythonProgram::new();
+        program
+            .import("subprocess")
+            .write_line("subprocess.Popen(['sleep', '60'])");
+        let start = Instant::now();
+        assert!(matches!(
+            program.run_with_timeout(Duration::from_secs(1)),
+            Err(PycallError::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn streaming() {
+        // The script only finishes once the test has seen its first line.
+        let dir = tempfile::tempdir().unwrap();
+        let seen = dir.path().join("seen");
+        let mut program = PythonProgram::new();
+        program
+            .import("os, sys, time")
+            .define_variable("seen", &*seen.to_string_lossy());
+        program
+            .write_line("print('first')")
+            .write_line("while not os.path.exists(seen): time.sleep(0.01)")
+            .write_line("print('oops', file=sys.stderr)")
+            .write_line("print('last\\r', end='')");
+        let mut running = program
+            .spawn_with(SpawnOptions::new().lines(true).tee(true))
+            .unwrap();
+        let mut lines = running.lines();
+        assert_eq!(
+            lines.next(),
+            Some(OutputLine {
+                stream: Stream::Stdout,
+                line: "first".to_owned()
+            })
+        );
+        std::fs::write(&seen, "").unwrap();
+        let mut rest: Vec<_> = lines.collect();
+        rest.sort_by_key(|line| line.stream == Stream::Stdout);
+        let rest: Vec<_> = rest.into_iter().map(|line| line.line).collect();
+        assert_eq!(rest, vec!["oops", "last"]);
+        let output = running.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "first\nlast\r");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "oops\n");
+
+        let mut seen = Vec::new();
+        let output = program
+            .run_streaming(|line| seen.push(line.line.clone()))
+            .unwrap();
+        assert!(output.status.success());
+        seen.sort();
+        assert_eq!(seen, vec!["first", "last", "oops"]);
+
+        // Without `lines`, the output is only collected.
+        let mut running = program.spawn().unwrap();
+        assert_eq!(running.lines().next(), None);
+        assert_eq!(running.wait_with_output().unwrap().stdout, output.stdout);
+    }
+}
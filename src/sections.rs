@@ -0,0 +1,272 @@
+//! The sections of a script, which code can be written to in any order.
+use crate::PythonProgram;
+use std::borrow::Cow;
+use std::panic::Location;
+
+/// A part of a script. Scripts are made of each section in turn, whatever order they were written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Section {
+    /// Comments and docstrings, before anything else.
+    Header,
+    /// The import statements requested with `import`, then code written to this section.
+    Imports,
+    /// Definitions of data and helpers.
+    Data,
+    /// The main code, where programs write by default.
+    #[default]
+    Body,
+    /// Code run last, such as `plt.show()`.
+    Footer,
+}
+
+impl Section {
+    /// Every section, in script order.
+    pub const ALL: [Section; 5] = [
+        Section::Header,
+        Section::Imports,
+        Section::Data,
+        Section::Body,
+        Section::Footer,
+    ];
+}
+
+/// A length in a script, in bytes and in lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Span {
+    bytes: usize,
+    lines: usize,
+}
+
+impl Span {
+    fn of(text: &str) -> Span {
+        Span {
+            bytes: text.len(),
+            lines: text.matches('\n').count(),
+        }
+    }
+}
+
+/// Where each section of a script ends, and where code is being written.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Layout {
+    ends: [Span; 5],
+    /// The import statements starting the imports section.
+    imports: Span,
+    /// The sections whose last line is unfinished, and ended by a newline only until it is completed.
+    open: [bool; 5],
+    /// The section being written.
+    pub(crate) cursor: Section,
+}
+
+impl Layout {
+    /// Where `section` starts.
+    pub(crate) fn start(&self, section: Section) -> Span {
+        match section as usize {
+            0 => Span::default(),
+            i => self.ends[i - 1],
+        }
+    }
+
+    /// Where `section` ends.
+    pub(crate) fn end(&self, section: Section) -> Span {
+        self.ends[section as usize]
+    }
+
+    /// Where the import statements end, and the code written to the imports section starts.
+    pub(crate) fn imports_end(&self) -> Span {
+        let start = self.start(Section::Imports);
+        Span {
+            bytes: start.bytes + self.imports.bytes,
+            lines: start.lines + self.imports.lines,
+        }
+    }
+
+    /// Moves the ends of `section` and the following ones, after `removed` was replaced by `added`.
+    fn resize(&mut self, section: Section, removed: Span, added: Span) {
+        for end in &mut self.ends[section as usize..] {
+            end.bytes = end.bytes - removed.bytes + added.bytes;
+            end.lines = end.lines - removed.lines + added.lines;
+        }
+    }
+}
+
+impl PythonProgram {
+    /// Sends the next writes to the end of `section`, `Section::Body` by default.
+    /// The indentation level is shared by all sections: switch between them outside of blocks.
+    pub fn section(&mut self, section: Section) -> &mut Self {
+        self.layout.cursor = section;
+        self
+    }
+
+    /// The section being written.
+    pub fn current_section(&self) -> Section {
+        self.layout.cursor
+    }
+
+    /// Inserts `text` at the end of `section`, attributing each of its lines to the next of `written_by`.
+    /// Text that doesn't end with a newline leaves the section's last line open: the next insertion continues it.
+    pub(crate) fn insert_raw<I>(&mut self, section: Section, text: &str, written_by: I)
+    where
+        I: IntoIterator<Item = Option<&'static Location<'static>>>,
+    {
+        if text.is_empty() {
+            return;
+        }
+        if section == self.layout.cursor {
+            self.closed = None;
+        }
+        let mut written_by = written_by.into_iter();
+        let mut first = written_by.next().flatten();
+        let open = &mut self.layout.open[section as usize];
+        if std::mem::replace(open, !text.ends_with('\n')) {
+            // Reopen the unfinished line, which keeps the call that started it.
+            let end = self.layout.end(section);
+            self.source.remove(end.bytes - 1);
+            first = self.written_by.remove(end.lines - 1).or(first);
+            let newline = Span { bytes: 1, lines: 1 };
+            self.layout.resize(section, newline, Span::default());
+        }
+        let text: Cow<str> = if text.ends_with('\n') {
+            text.into()
+        } else {
+            format!("{}\n", text).into()
+        };
+        let end = self.layout.end(section);
+        let added = Span::of(&text);
+        self.source.insert_str(end.bytes, &text);
+        self.written_by.splice(
+            end.lines..end.lines,
+            std::iter::once(first).chain(written_by).take(added.lines),
+        );
+        self.layout.resize(section, Span::default(), added);
+    }
+
+    /// Replaces the import statements starting the script with `statements`, one per line.
+    pub(crate) fn replace_imports<I>(&mut self, statements: &str, written_by: I)
+    where
+        I: IntoIterator<Item = Option<&'static Location<'static>>>,
+    {
+        let start = self.layout.start(Section::Imports);
+        let end = self.layout.imports_end();
+        let added = Span::of(statements);
+        self.source
+            .replace_range(start.bytes..end.bytes, statements);
+        self.written_by.splice(
+            start.lines..end.lines,
+            written_by.into_iter().take(added.lines),
+        );
+        self.layout
+            .resize(Section::Imports, self.layout.imports, added);
+        self.layout.imports = added;
+    }
+
+    /// The lines written to `section`, with the calls that wrote them.
+    /// The import statements are left out of the imports section.
+    pub(crate) fn section_lines(
+        &self,
+        section: Section,
+    ) -> impl Iterator<Item = (&str, Option<&'static Location<'static>>)> {
+        let start = match section {
+            Section::Imports => self.layout.imports_end(),
+            _ => self.layout.start(section),
+        };
+        let end = self.layout.end(section);
+        self.source[start.bytes..end.bytes]
+            .lines()
+            .zip(self.written_by[start.lines..end.lines].iter().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PythonProgram, Section};
+    use std::io::Write;
+
+    #[test]
+    fn sections() {
+        let mut program = PythonProgram::new();
+        program.write_line("print(x, y)");
+        let footer_at = line!() + 1;
+        program.section(Section::Footer).write_line("print('end')");
+        program
+            .section(Section::Data)
+            .write_line("x = math.pi")
+            .section(Section::Header)
+            .write_line("# generated")
+            .section(Section::Body)
+            .write_line("print(y)")
+            .section(Section::Imports)
+            .write_line("sys.stdout.write('imported\\n')");
+        let imported_at = line!() + 1;
+        program.import("math, sys").section(Section::Data);
+        program.define_variable("y", &2);
+        assert_eq!(program.current_section(), Section::Data);
+        assert_eq!(
+            program.as_str(),
+            "# generated\n\
+             import math\n\
+             import sys\n\
+             sys.stdout.write('imported\\n')\n\
+             x = math.pi\n\
+             y = 2\n\
+             print(x, y)\n\
+             print(y)\n\
+             print('end')\n"
+        );
+        assert_eq!(program.written_by(9).unwrap().line(), footer_at);
+        assert_eq!(program.written_by(2).unwrap().line(), imported_at);
+        let output = program.run_checked().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "imported\n3.141592653589793 2\n2\nend\n"
+        );
+    }
+
+    #[test]
+    fn unfinished_lines() {
+        let mut program = PythonProgram::new();
+        let footer_at = line!() + 1;
+        program.section(Section::Footer).write_line("print('end')");
+        program.section(Section::Body);
+        write!(program, "print('a'").unwrap();
+        assert_eq!(program.as_str(), "print('a'\nprint('end')\n");
+        writeln!(program, ", 'b')").unwrap();
+        let line = line!() + 1;
+        program.write_line("print('c')");
+        assert_eq!(
+            program.as_str(),
+            "print('a', 'b')\nprint('c')\nprint('end')\n"
+        );
+        assert_eq!(program.written_by(1), None);
+        assert_eq!(program.written_by(2).unwrap().line(), line);
+        assert_eq!(program.written_by(3).unwrap().line(), footer_at);
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "a b\nc\nend\n");
+    }
+
+    #[test]
+    fn footer_declarations() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: i32,
+        }
+
+        // Classes first needed in the footer are still defined before the body uses them.
+        let mut program = PythonProgram::new();
+        program
+            .section(Section::Footer)
+            .define_variable("end", &Point { x: 2 });
+        program
+            .write_line("print(end.x)")
+            .section(Section::Body)
+            .define_variable("start", &Point { x: 1 });
+        program.write_line("print(start.x)");
+        let source = program.to_string();
+        assert!(source.find("class Point").unwrap() < source.find("start =").unwrap());
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n2\n");
+    }
+}
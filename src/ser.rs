@@ -0,0 +1,520 @@
+//! A serde `Serializer` that writes Python literals, for types that implement `Serialize` but not `AsPythonLitteral`.
+//!
+//! The mapping follows the defaults of `#[derive(AsPythonLitteral)]`:
+//! - sequences become lists, tuples and tuple structs become tuples, maps and structs become dicts;
+//! - `None`, `()` and unit structs become `None`, newtype structs their inner value;
+//! - bytes become `b'...'` literals;
+//! - unit variants become their name as a string, and other variants `{'Variant': payload}`,
+//!   the payload being written like a newtype struct, tuple or struct respectively.
+//!
+//! Map keys are written in a hashable form: sequences in keys become tuples.
+use crate::{AsPythonLitteral, PythonLiteral};
+use serde::ser::{self, Serialize};
+use std::fmt::Write;
+
+/// The error returned when a value fails to serialize.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SerializeError(String);
+
+impl std::fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+impl ser::Error for SerializeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        SerializeError(msg.to_string())
+    }
+}
+
+impl From<std::fmt::Error> for SerializeError {
+    fn from(_: std::fmt::Error) -> Self {
+        SerializeError("failed to write literal".to_owned())
+    }
+}
+
+/// Serializes `value` as a Python literal.
+pub fn to_python_literal<T: Serialize + ?Sized>(value: &T) -> Result<String, SerializeError> {
+    to_python_literal_with_precision(value, None)
+}
+
+pub(crate) fn to_python_literal_with_precision<T: Serialize + ?Sized>(
+    value: &T,
+    float_precision: Option<usize>,
+) -> Result<String, SerializeError> {
+    let mut serializer = Serializer {
+        output: String::new(),
+        hashable: false,
+        float_precision,
+    };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+struct Serializer {
+    output: String,
+    /// Whether the value being written is (part of) a dict key.
+    hashable: bool,
+    float_precision: Option<usize>,
+}
+
+impl Serializer {
+    fn literal<T: AsPythonLitteral + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        match self.float_precision {
+            Some(precision) => write!(self.output, "{:.*}", precision, PythonLiteral(value))?,
+            None => write!(self.output, "{}", PythonLiteral(value))?,
+        }
+        Ok(())
+    }
+
+    fn open_variant(&mut self, variant: &str) -> Result<(), SerializeError> {
+        self.output.push('{');
+        self.literal(variant)?;
+        self.output.push(':');
+        Ok(())
+    }
+
+    /// Opens a sequence, which is written as a tuple if it needs to be hashable.
+    fn open_seq(&mut self, tuple: bool) -> Compound<'_> {
+        let tuple = tuple || self.hashable;
+        self.output.push(if tuple { '(' } else { '[' });
+        Compound {
+            close: if tuple { ")" } else { "]" },
+            ser: self,
+        }
+    }
+
+    fn open_dict(&mut self, close: &'static str) -> Compound<'_> {
+        self.output.push('{');
+        Compound { close, ser: self }
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = SerializeError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), SerializeError> {
+        self.literal(&v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), SerializeError> {
+        self.literal(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), SerializeError> {
+        Ok(crate::write_bytes_literal(&mut self.output, v)?)
+    }
+
+    fn serialize_none(self) -> Result<(), SerializeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), SerializeError> {
+        self.output.push_str("None");
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), SerializeError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), SerializeError> {
+        self.literal(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.open_variant(variant)?;
+        let hashable = std::mem::replace(&mut self.hashable, false);
+        value.serialize(&mut *self)?;
+        self.hashable = hashable;
+        self.output.push('}');
+        Ok(())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.open_seq(false))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.open_seq(true))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.open_seq(true))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        self.open_variant(variant)?;
+        self.output.push('(');
+        Ok(Compound {
+            close: ")}",
+            ser: self,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.open_dict("}"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        Ok(self.open_dict("}"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, SerializeError> {
+        self.open_variant(variant)?;
+        Ok(self.open_dict("}}"))
+    }
+}
+
+struct Compound<'a> {
+    ser: &'a mut Serializer,
+    close: &'static str,
+}
+
+impl<'a> Compound<'a> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        value.serialize(&mut *self.ser)?;
+        self.ser.output.push(',');
+        Ok(())
+    }
+
+    fn key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerializeError> {
+        let hashable = std::mem::replace(&mut self.ser.hashable, true);
+        key.serialize(&mut *self.ser)?;
+        self.ser.hashable = hashable;
+        self.ser.output.push(':');
+        Ok(())
+    }
+
+    fn value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        let hashable = std::mem::replace(&mut self.ser.hashable, false);
+        self.element(value)?;
+        self.ser.hashable = hashable;
+        Ok(())
+    }
+
+    fn field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.ser.literal(key)?;
+        self.ser.output.push(':');
+        self.value(value)
+    }
+
+    fn close(self) -> Result<(), SerializeError> {
+        self.ser.output.push_str(self.close);
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerializeError> {
+        self.key(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.value(value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = SerializeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), SerializeError> {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PythonProgram;
+    use serde::Serialize;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Serialize)]
+    struct Unit;
+
+    #[derive(Serialize)]
+    struct Meters(f64);
+
+    #[derive(Serialize)]
+    struct Pair(i32, &'static str);
+
+    #[derive(Serialize)]
+    enum Event {
+        Start,
+        Move(Meters),
+        Resize(u32, u32),
+        Rename { from: String, to: String },
+    }
+
+    #[derive(Serialize)]
+    struct Report {
+        title: String,
+        samples: Vec<f64>,
+        missing: Option<u8>,
+        present: Option<u8>,
+        unit: Unit,
+        distance: Meters,
+        pair: Pair,
+        #[serde(with = "serde_bytes_like")]
+        raw: Vec<u8>,
+        events: Vec<Event>,
+        grid: BTreeMap<(u8, u8), bool>,
+        nested_keys: HashMap<Vec<i8>, ()>,
+    }
+
+    mod serde_bytes_like {
+        pub fn serialize<S: serde::Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(bytes)
+        }
+    }
+
+    #[test]
+    fn serialized_literals_are_valid_python_literals() {
+        let mut grid = BTreeMap::new();
+        grid.insert((0, 1), true);
+        let mut nested_keys = HashMap::new();
+        nested_keys.insert(vec![-1, 2], ());
+        let report = Report {
+            title: "it's \"quoted\"\n".to_owned(),
+            samples: vec![0.1, -0.0, 1e300],
+            missing: None,
+            present: Some(255),
+            unit: Unit,
+            distance: Meters(2.5),
+            pair: Pair(-3, "three"),
+            raw: vec![0, b'\'', b'\\', b'a', 0xff, b'\n'],
+            events: vec![
+                Event::Start,
+                Event::Move(Meters(1.0)),
+                Event::Resize(3, 4),
+                Event::Rename {
+                    from: "a".to_owned(),
+                    to: "b".to_owned(),
+                },
+            ],
+            grid,
+            nested_keys,
+        };
+        let literal = to_python_literal(&report).unwrap();
+        let mut program = PythonProgram::new();
+        program.import("ast").define_variable("source", &literal);
+        program
+            .write_line("value = ast.literal_eval(source)")
+            .write_line("assert value == {'title': 'it\\'s \"quoted\"\\n', 'samples': [0.1, -0.0, 1e300], 'missing': None, 'present': 255, 'unit': None, 'distance': 2.5, 'pair': (-3, 'three'), 'raw': b'\\x00\\'\\\\a\\xff\\n', 'events': ['Start', {'Move': 1.0}, {'Resize': (3, 4)}, {'Rename': {'from': 'a', 'to': 'b'}}], 'grid': {(0, 1): True}, 'nested_keys': {(-1, 2): None}}, value")
+            .define_serialized("direct", &report)
+            .unwrap();
+        program
+            .write_line("assert direct == value")
+            .write_line("print('ok')");
+        let output = program.run().unwrap();
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            program,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "ok");
+    }
+}
@@ -0,0 +1,206 @@
+//! A python interpreter kept alive across chunks of code, so that imports and variables persist
+//! and startup costs are only paid once.
+use crate::{Interpreter, PycallError, PythonProgram};
+use std::io::{BufRead, Read, Write};
+
+/// Runs in the interpreter: reads length-prefixed chunks from stdin, executes them in a persistent namespace,
+/// and answers with `<stdout length> <traceback length>\n` followed by both payloads.
+/// The answers go to a copy of the original stdout, while file descriptor 1 is redirected to stderr
+/// so that nothing written directly to it (by subprocesses, say) can corrupt the protocol.
+const BOOTSTRAP: &str = "
+import contextlib, io, os, sys, traceback
+protocol_out = os.fdopen(os.dup(1), 'wb')
+os.dup2(2, 1)
+protocol_in = sys.stdin.buffer
+sys.stdin = open(os.devnull)
+namespace = {'__name__': '__main__', '__builtins__': __builtins__}
+chunk = 0
+while True:
+\theader = protocol_in.readline()
+\tif not header:
+\t\tbreak
+\tcode = protocol_in.read(int(header)).decode('utf-8')
+\tchunk += 1
+\tstdout = io.StringIO()
+\terror = ''
+\ttry:
+\t\twith contextlib.redirect_stdout(stdout):
+\t\t\texec(compile(code, '<chunk %d>' % chunk, 'exec'), namespace)
+\texcept BaseException:
+\t\terror = traceback.format_exc()
+\tstdout = stdout.getvalue().encode('utf-8', 'backslashreplace')
+\terror = error.encode('utf-8', 'backslashreplace')
+\tprotocol_out.write(b'%d %d\\n' % (len(stdout), len(error)) + stdout + error)
+\tprotocol_out.flush()
+";
+
+/// What executing a chunk in a `PythonSession` produced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkOutput {
+    /// Everything the chunk printed to `sys.stdout`.
+    pub stdout: String,
+    /// The traceback of the exception that interrupted the chunk, if any.
+    pub exception: Option<String>,
+}
+
+impl ChunkOutput {
+    pub fn is_success(&self) -> bool {
+        self.exception.is_none()
+    }
+}
+
+/// A python3 process that executes chunks of code on demand, keeping its variables between them.
+/// The interpreter is shut down when the session is dropped.
+pub struct PythonSession {
+    child: std::process::Child,
+    stdin: Option<std::process::ChildStdin>,
+    stdout: std::io::BufReader<std::process::ChildStdout>,
+}
+
+impl PythonSession {
+    /// Starts the interpreter found by `Interpreter::discover()`. Its stderr is inherited.
+    pub fn new() -> Result<PythonSession, PycallError> {
+        PythonSession::with_interpreter(&Interpreter::discover())
+    }
+
+    /// Starts `interpreter`. Its stderr is inherited.
+    pub fn with_interpreter(interpreter: &Interpreter) -> Result<PythonSession, PycallError> {
+        let mut child = interpreter
+            .command()
+            .arg("-c")
+            .arg(BOOTSTRAP)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|e| interpreter.start_error(e))?;
+        Ok(PythonSession {
+            stdin: child.stdin.take(),
+            stdout: std::io::BufReader::new(child.stdout.take().unwrap()),
+            child,
+        })
+    }
+
+    /// Executes `code` at the top level of the session, and waits for it to finish.
+    /// Exceptions raised by `code` are reported in the output; errors are only returned if the interpreter is gone.
+    pub fn exec(&mut self, code: &str) -> Result<ChunkOutput, PycallError> {
+        self.exchange(code).map_err(PycallError::Io)
+    }
+
+    fn exchange(&mut self, code: &str) -> Result<ChunkOutput, std::io::Error> {
+        let stdin = self.stdin.as_mut().unwrap();
+        write!(stdin, "{}\n{}", code.len(), code)?;
+        stdin.flush()?;
+        let mut header = String::new();
+        if self.stdout.read_line(&mut header)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "the python session exited",
+            ));
+        }
+        let invalid =
+            || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid session answer");
+        let mut lengths = header.split_whitespace().map(str::parse::<usize>);
+        let (stdout_len, error_len) = match (lengths.next(), lengths.next()) {
+            (Some(Ok(stdout_len)), Some(Ok(error_len))) => (stdout_len, error_len),
+            _ => return Err(invalid()),
+        };
+        let mut read = |len: usize| -> Result<String, std::io::Error> {
+            let mut buffer = vec![0; len];
+            self.stdout.read_exact(&mut buffer)?;
+            String::from_utf8(buffer).map_err(|_| invalid())
+        };
+        let stdout = read(stdout_len)?;
+        let error = read(error_len)?;
+        Ok(ChunkOutput {
+            stdout,
+            exception: if error.is_empty() { None } else { Some(error) },
+        })
+    }
+
+    /// Executes the code generated so far by `program`, which must not leave a block open.
+    pub fn exec_program(&mut self, program: &PythonProgram) -> Result<ChunkOutput, PycallError> {
+        program.check_blocks()?;
+        self.exec(program.script()?)
+    }
+
+    /// Lets the interpreter finish its work and exit.
+    pub fn close(mut self) -> Result<std::process::ExitStatus, PycallError> {
+        self.stdin = None;
+        self.child.wait().map_err(PycallError::Io)
+    }
+}
+
+impl Drop for PythonSession {
+    fn drop(&mut self) {
+        if self.stdin.take().is_none() {
+            // Already closed.
+            return;
+        }
+        // Closing stdin ends the interpreter's loop: give it a moment before killing it.
+        for _ in 0..50 {
+            match self.child.try_wait() {
+                Ok(None) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                _ => return,
+            }
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_keeps_state_between_chunks() {
+        let mut session = PythonSession::new().unwrap();
+        let output = session.exec("import math\nx = 21").unwrap();
+        assert_eq!(
+            output,
+            ChunkOutput {
+                stdout: String::new(),
+                exception: None
+            }
+        );
+        let output = session.exec("print(x * 2)\nprint('é🐍')").unwrap();
+        assert_eq!(output.stdout, "42\né🐍\n");
+
+        let output = session
+            .exec("print('before')\nraise ValueError(x)")
+            .unwrap();
+        assert!(!output.is_success());
+        assert_eq!(output.stdout, "before\n");
+        assert!(output.exception.unwrap().contains("ValueError: 21"));
+
+        // Raw writes to fd 1 can't corrupt the protocol, and the session survives exceptions.
+        let output = session
+            .exec("import os\nos.write(1, b'raw\\n')\nx += 1")
+            .unwrap();
+        assert!(output.is_success());
+
+        let mut program = PythonProgram::new();
+        program.define_variable("y", &vec![1, 2]);
+        program.write_line("print(math.floor(x + sum(y) + 0.5))");
+        assert_eq!(session.exec_program(&program).unwrap().stdout, "25\n");
+
+        assert!(session
+            .exec("import sys\nsys.exit(3)")
+            .unwrap()
+            .exception
+            .is_some());
+        assert!(session.close().unwrap().success());
+    }
+
+    #[test]
+    fn dropping_a_session_stops_the_interpreter() {
+        let mut session = PythonSession::new().unwrap();
+        let pid = session
+            .exec("import os\nprint(os.getpid())")
+            .unwrap()
+            .stdout;
+        drop(session);
+        let pid: u32 = pid.trim().parse().unwrap();
+        assert!(!std::path::Path::new(&format!("/proc/{}", pid)).exists());
+    }
+}
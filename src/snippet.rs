@@ -0,0 +1,249 @@
+//! Multi-line snippets of python, reindented to fit where they are written, and the templates of `py!`.
+use crate::{AsPythonLitteral, AsPythonSource, PythonProgram};
+use std::panic::Location;
+
+/// A snippet being assembled from python source and interpolated values, which is what `py!` expands to.
+pub struct Snippet<'p> {
+    program: &'p mut PythonProgram,
+    code: String,
+}
+
+impl<'p> Snippet<'p> {
+    /// Appends python source.
+    pub fn source(&mut self, source: &str) -> &mut Self {
+        self.code.push_str(source);
+        self
+    }
+
+    /// Appends `value` as a literal, with the program's float precision, writing the declarations it needs.
+    #[track_caller]
+    pub fn literal<T: AsPythonLitteral + ?Sized>(&mut self, value: &T) -> &mut Self {
+        self.program.declare::<T>();
+        let literal = self.program.literal(value).to_string();
+        self.code.push_str(&literal);
+        self
+    }
+
+    /// Appends `code` as source, writing the declarations it needs.
+    #[track_caller]
+    pub fn raw<C: AsPythonSource + ?Sized>(&mut self, code: &C) -> &mut Self {
+        let code = self.program.source(code);
+        self.code.push_str(&code);
+        self
+    }
+
+    /// Writes the snippet with `PythonProgram::write_snippet`.
+    #[track_caller]
+    pub fn write(self) -> &'p mut PythonProgram {
+        self.program.write_snippet(&self.code)
+    }
+}
+
+/// The whitespace starting `line`.
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Whether each of `lines` starts inside a triple-quoted string.
+pub(crate) fn in_strings(lines: &[&str]) -> Vec<bool> {
+    let mut open: Option<&[u8]> = None;
+    let mut result = Vec::with_capacity(lines.len());
+    for line in lines {
+        result.push(open.is_some());
+        let bytes = line.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match open {
+                Some(_) if bytes[i] == b'\\' => i += 2,
+                Some(quotes) if bytes[i..].starts_with(quotes) => {
+                    open = None;
+                    i += 3;
+                }
+                Some(_) => i += 1,
+                None => match bytes[i] {
+                    b'#' => break,
+                    quote @ (b'\'' | b'"') => {
+                        let triple: &[u8] = if quote == b'"' { b"\"\"\"" } else { b"'''" };
+                        if bytes[i..].starts_with(triple) {
+                            open = Some(triple);
+                            i += 3;
+                            continue;
+                        }
+                        // Skip a string that ends on this line.
+                        i += 1;
+                        while i < bytes.len() && bytes[i] != quote {
+                            i += if bytes[i] == b'\\' { 2 } else { 1 };
+                        }
+                        i += 1;
+                    }
+                    _ => i += 1,
+                },
+            }
+        }
+    }
+    result
+}
+
+impl PythonProgram {
+    /// Starts a snippet, written once assembled. The `py!` macro is usually more convenient.
+    pub fn snippet(&mut self) -> Snippet<'_> {
+        Snippet {
+            program: self,
+            code: String::new(),
+        }
+    }
+
+    /// Writes a multi-line snippet of python at the current indentation level.
+    ///
+    /// The snippet is dedented first, so that it can be indented like the rust code around it,
+    /// and blank lines around it are dropped. Its own indentation, with tabs or any number of spaces,
+    /// is then converted to the program's indent unit; lines inside multi-line strings are reindented too.
+    #[track_caller]
+    pub fn write_snippet(&mut self, code: &str) -> &mut Self {
+        self.write_code(code, false)
+    }
+
+    /// Writes `code` like `write_snippet`, leaving the lines that continue multi-line strings as they are if `keep_strings`.
+    #[track_caller]
+    pub(crate) fn write_code(&mut self, code: &str, keep_strings: bool) -> &mut Self {
+        let lines: Vec<&str> = code.lines().collect();
+        let strings = if keep_strings {
+            in_strings(&lines)
+        } else {
+            vec![false; lines.len()]
+        };
+        let blank = |line: &&str| line.trim().is_empty();
+        let (first, last) = match (
+            lines.iter().position(|line| !blank(line)),
+            lines.iter().rposition(|line| !blank(line)),
+        ) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return self,
+        };
+        let lines = &lines[first..=last];
+        let strings = &strings[first..=last];
+        let code_lines = || {
+            lines
+                .iter()
+                .zip(strings)
+                .filter(|(line, &string)| !string && !blank(line))
+                .map(|(line, _)| line)
+        };
+        let common = code_lines()
+            .map(|line| indentation(line))
+            .reduce(|common, indentation| {
+                let len = common
+                    .bytes()
+                    .zip(indentation.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                &common[..len]
+            })
+            .unwrap_or("");
+        // The snippet's own unit of space indentation: its smallest.
+        let width = code_lines()
+            .map(|line| indentation(&line[common.len()..]))
+            .filter(|indentation| !indentation.contains('\t'))
+            .map(str::len)
+            .filter(|&len| len > 0)
+            .min()
+            .unwrap_or(1);
+        for (line, &string) in lines.iter().zip(strings) {
+            if string {
+                self.write_raw(&format!("{}\n", line), Some(Location::caller()));
+                continue;
+            }
+            if blank(line) {
+                self.write_raw("\n", Some(Location::caller()));
+                continue;
+            }
+            let line = &line[common.len()..];
+            let whitespace = indentation(line);
+            let tabs = whitespace.matches('\t').count();
+            let spaces = whitespace.len() - tabs;
+            let line = format!(
+                "{}{}{}",
+                self.indent_unit.repeat(tabs + spaces / width),
+                " ".repeat(spaces % width),
+                &line[whitespace.len()..]
+            );
+            self.write_line(&line);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Expr, IndentUnit, PythonProgram};
+    use pycall_derive::py;
+
+    #[test]
+    fn snippets() {
+        let mut program = PythonProgram::new();
+        program.indent_unit(IndentUnit::Spaces(2)).r#if("True");
+        program.write_snippet(
+            "
+            def f(x):
+                if x:
+                    return 1
+                return 2
+
+            print(f(0), f(1))
+            ",
+        );
+        program.end_block();
+        let source = program.to_string();
+        assert!(
+            source.contains("\n  def f(x):\n    if x:\n      return 1\n    return 2\n\n  print"),
+            "{}",
+            source
+        );
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "2 1\n");
+    }
+
+    #[test]
+    fn templates() {
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Point {
+            x: f64,
+        }
+
+        let xs = vec![1.5, 2.0];
+        let name = "it's {x}";
+        let function = Expr::name("print");
+        let mut program = PythonProgram::new();
+        program.float_precision(Some(1)).r#for("i in range(2)");
+        py!(
+            program,
+            "print({xs}, {name}, {}, {{'k': {y}}})",
+            i64::MAX,
+            y = ()
+        );
+        py!(
+            program,
+            r#"
+            if i:
+                {function:raw}({point}.x, "{{}}".format({:raw}))
+            "#,
+            Expr::name("i"),
+            point = Point { x: 0.25 },
+        )
+        .end_block();
+        let output = program.run_checked().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "[1.5, 2.0] it's {x} 9223372036854775807 {'k': None}\n".repeat(2) + "0.25 1\n"
+        );
+
+        // The lines of a template are written by the macro call.
+        let mut program = PythonProgram::new();
+        let line = line!() + 1;
+        py!(program, "raise ValueError({name})");
+        assert_eq!(program.written_by(1).unwrap().line(), line);
+    }
+}
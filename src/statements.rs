@@ -0,0 +1,482 @@
+//! Builders for definitions and statements beyond control flow: functions, classes, `with`, `try`, and the simple statements.
+use crate::{blocks::BlockKind, AsPythonSource, Declarations, PythonProgram};
+use std::fmt::{Display, Error, Formatter};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Parameter {
+    /// `*` or `**` for variadic parameters.
+    stars: &'static str,
+    name: String,
+    annotation: Option<String>,
+    default: Option<String>,
+}
+
+/// The parameters of a function, written as python source by `Display`.
+///
+/// Annotations and defaults are python source, such as `Expr`s, whose declarations are written along with the function.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Parameters {
+    parameters: Vec<Parameter>,
+    /// The definitions needed by the literals in annotations and defaults.
+    pub(crate) declarations: Vec<String>,
+}
+
+impl Parameters {
+    pub fn new() -> Parameters {
+        Parameters::default()
+    }
+
+    fn push(
+        &mut self,
+        stars: &'static str,
+        name: &str,
+        annotation: Option<String>,
+        default: Option<String>,
+    ) -> &mut Self {
+        self.parameters.push(Parameter {
+            stars,
+            name: name.to_owned(),
+            annotation,
+            default,
+        });
+        self
+    }
+
+    /// The source of `code`, keeping the declarations it needs.
+    fn source<C: AsPythonSource + ?Sized>(&mut self, code: &C) -> String {
+        let mut declarations = Declarations::default();
+        code.declare(&mut declarations);
+        self.declarations.extend(declarations.take_pending());
+        code.source().into_owned()
+    }
+
+    /// Adds a plain parameter.
+    pub fn param(&mut self, name: &str) -> &mut Self {
+        self.push("", name, None, None)
+    }
+
+    /// Adds a parameter annotated with `annotation`, such as `int`.
+    pub fn typed<A: AsPythonSource + ?Sized>(&mut self, name: &str, annotation: &A) -> &mut Self {
+        let annotation = self.source(annotation);
+        self.push("", name, Some(annotation), None)
+    }
+
+    /// Adds a parameter defaulting to `default`.
+    pub fn optional<D: AsPythonSource + ?Sized>(&mut self, name: &str, default: &D) -> &mut Self {
+        let default = self.source(default);
+        self.push("", name, None, Some(default))
+    }
+
+    /// Adds a parameter annotated with `annotation`, defaulting to `default`.
+    pub fn typed_optional<A: AsPythonSource + ?Sized, D: AsPythonSource + ?Sized>(
+        &mut self,
+        name: &str,
+        annotation: &A,
+        default: &D,
+    ) -> &mut Self {
+        let annotation = self.source(annotation);
+        let default = self.source(default);
+        self.push("", name, Some(annotation), Some(default))
+    }
+
+    /// Adds `*name`, collecting the remaining positional arguments.
+    pub fn var_args(&mut self, name: &str) -> &mut Self {
+        self.push("*", name, None, None)
+    }
+
+    /// Adds a bare `*`: the parameters after it are keyword-only.
+    pub fn keyword_only(&mut self) -> &mut Self {
+        self.push("*", "", None, None)
+    }
+
+    /// Adds `**name`, collecting the remaining keyword arguments.
+    pub fn var_kwargs(&mut self, name: &str) -> &mut Self {
+        self.push("**", name, None, None)
+    }
+
+    /// Annotates the last parameter added, which is how variadic parameters get their type.
+    pub fn annotated<A: AsPythonSource + ?Sized>(&mut self, annotation: &A) -> &mut Self {
+        let annotation = self.source(annotation);
+        if let Some(parameter) = self.parameters.last_mut() {
+            parameter.annotation = Some(annotation);
+        }
+        self
+    }
+
+    /// A lambda taking these parameters, without their annotations, and returning `body`.
+    pub fn lambda(&self, body: &str) -> String {
+        let mut lambda = String::from("lambda");
+        for (i, parameter) in self.parameters.iter().enumerate() {
+            lambda.push_str(if i == 0 { " " } else { ", " });
+            lambda.push_str(parameter.stars);
+            lambda.push_str(&parameter.name);
+            if let Some(default) = &parameter.default {
+                lambda.push('=');
+                lambda.push_str(default);
+            }
+        }
+        lambda.push_str(": ");
+        lambda.push_str(body);
+        lambda
+    }
+}
+
+impl Display for Parameters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        for (i, parameter) in self.parameters.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}{}", parameter.stars, parameter.name)?;
+            match (&parameter.annotation, &parameter.default) {
+                (Some(annotation), Some(default)) => write!(f, ": {} = {}", annotation, default)?,
+                (Some(annotation), None) => write!(f, ": {}", annotation)?,
+                (None, Some(default)) => write!(f, "={}", default)?,
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PythonProgram {
+    #[track_caller]
+    fn function(
+        &mut self,
+        keyword: &str,
+        name: &str,
+        parameters: &Parameters,
+        returns: Option<&dyn AsPythonSource>,
+    ) -> &mut Self {
+        for code in &parameters.declarations {
+            self.declarations.declare(code);
+        }
+        self.write_pending();
+        let returns = returns.map(|returns| self.source(returns));
+        let header = match returns {
+            Some(returns) => format!("{} {}({}) -> {}:", keyword, name, parameters, returns),
+            None => format!("{} {}({}):", keyword, name, parameters),
+        };
+        self.open_block(BlockKind::Other, &header)
+    }
+
+    /// Writes "def `name`(`parameters`) -> `returns`:", and increments indentation.
+    #[track_caller]
+    pub fn def(
+        &mut self,
+        name: &str,
+        parameters: &Parameters,
+        returns: Option<&dyn AsPythonSource>,
+    ) -> &mut Self {
+        self.function("def", name, parameters, returns)
+    }
+
+    /// Writes "async def `name`(`parameters`) -> `returns`:", and increments indentation.
+    #[track_caller]
+    pub fn async_def(
+        &mut self,
+        name: &str,
+        parameters: &Parameters,
+        returns: Option<&dyn AsPythonSource>,
+    ) -> &mut Self {
+        self.function("async def", name, parameters, returns)
+    }
+
+    /// Writes "class `name`(`bases`):", and increments indentation.
+    #[track_caller]
+    pub fn class(&mut self, name: &str, bases: &[&dyn AsPythonSource]) -> &mut Self {
+        let bases: Vec<String> = bases.iter().map(|base| self.source(*base)).collect();
+        if bases.is_empty() {
+            self.open_block(BlockKind::Other, &format!("class {}:", name))
+        } else {
+            self.open_block(
+                BlockKind::Other,
+                &format!("class {}({}):", name, bases.join(", ")),
+            )
+        }
+    }
+
+    /// Writes "@`decorator`", to be followed by a `def` or a `class`.
+    #[track_caller]
+    pub fn decorator<C: AsPythonSource + ?Sized>(&mut self, decorator: &C) -> &mut Self {
+        let decorator = self.source(decorator);
+        self.write_line(&format!("@{}", decorator))
+    }
+
+    /// Writes "with `context` as `target`:", and increments indentation.
+    #[track_caller]
+    pub fn with<C: AsPythonSource + ?Sized>(
+        &mut self,
+        context: &C,
+        target: Option<&dyn AsPythonSource>,
+    ) -> &mut Self {
+        let context = self.source(context);
+        let target = target.map(|target| self.source(target));
+        match target {
+            Some(target) => self.open_block(
+                BlockKind::Other,
+                &format!("with {} as {}:", context, target),
+            ),
+            None => self.open_block(BlockKind::Other, &format!("with {}:", context)),
+        }
+    }
+
+    /// Writes a try, and increments indentation.
+    /// Follow it with `except`, `r#else` and `finally`.
+    #[track_caller]
+    pub fn r#try(&mut self) -> &mut Self {
+        self.open_block(BlockKind::Other, "try:")
+    }
+
+    /// Closes the current block, writes "except `exception` as `name`:", and increments indentation.
+    /// Without an `exception`, catches everything.
+    #[track_caller]
+    pub fn except(
+        &mut self,
+        exception: Option<&dyn AsPythonSource>,
+        name: Option<&str>,
+    ) -> &mut Self {
+        let exception = exception.map(|exception| self.source(exception));
+        let header = match (exception, name) {
+            (Some(exception), Some(name)) => format!("except {} as {}:", exception, name),
+            (Some(exception), None) => format!("except {}:", exception),
+            (None, _) => "except:".to_owned(),
+        };
+        self.end_block().open_block(BlockKind::Except, &header)
+    }
+
+    /// Closes the current block, writes a finally, and increments indentation.
+    #[track_caller]
+    pub fn finally(&mut self) -> &mut Self {
+        self.end_block().open_block(BlockKind::Other, "finally:")
+    }
+
+    /// Writes "return `value`", or a bare return if `value` is empty.
+    #[track_caller]
+    pub fn r#return<C: AsPythonSource + ?Sized>(&mut self, value: &C) -> &mut Self {
+        self.keyword_statement("return", value)
+    }
+
+    /// Writes "yield `value`", or a bare yield if `value` is empty.
+    #[track_caller]
+    pub fn r#yield<C: AsPythonSource + ?Sized>(&mut self, value: &C) -> &mut Self {
+        self.keyword_statement("yield", value)
+    }
+
+    /// Writes "await `value`".
+    #[track_caller]
+    pub fn r#await<C: AsPythonSource + ?Sized>(&mut self, value: &C) -> &mut Self {
+        self.keyword_statement("await", value)
+    }
+
+    /// Writes "raise `exception`", or a bare raise, re-raising the current exception, if `exception` is empty.
+    #[track_caller]
+    pub fn raise<C: AsPythonSource + ?Sized>(&mut self, exception: &C) -> &mut Self {
+        self.keyword_statement("raise", exception)
+    }
+
+    /// Writes a break.
+    #[track_caller]
+    pub fn r#break(&mut self) -> &mut Self {
+        self.write_line("break")
+    }
+
+    /// Writes a continue.
+    #[track_caller]
+    pub fn r#continue(&mut self) -> &mut Self {
+        self.write_line("continue")
+    }
+
+    /// Writes "global `names`".
+    #[track_caller]
+    pub fn global(&mut self, names: &[&str]) -> &mut Self {
+        self.keyword_statement("global", &names.join(", "))
+    }
+
+    /// Writes "nonlocal `names`".
+    #[track_caller]
+    pub fn nonlocal(&mut self, names: &[&str]) -> &mut Self {
+        self.keyword_statement("nonlocal", &names.join(", "))
+    }
+
+    #[track_caller]
+    fn keyword_statement<C: AsPythonSource + ?Sized>(
+        &mut self,
+        keyword: &str,
+        operand: &C,
+    ) -> &mut Self {
+        let operand = self.source(operand);
+        if operand.is_empty() {
+            self.write_line(keyword)
+        } else {
+            self.write_line(&format!("{} {}", keyword, operand))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Parameters;
+    use crate::PythonProgram;
+
+    #[test]
+    fn parameters() {
+        let mut parameters = Parameters::new();
+        parameters
+            .param("a")
+            .typed("b", "int")
+            .optional("c", "None")
+            .typed_optional("d", "float", "1.0")
+            .var_args("rest")
+            .annotated("str")
+            .keyword_only()
+            .param("e")
+            .var_kwargs("options");
+        assert_eq!(
+            parameters.to_string(),
+            "a, b: int, c=None, d: float = 1.0, *rest: str, *, e, **options"
+        );
+        assert_eq!(
+            parameters.lambda("a"),
+            "lambda a, b, c=None, d=1.0, *rest, *, e, **options: a"
+        );
+        assert_eq!(Parameters::new().lambda("0"), "lambda: 0");
+        assert_eq!(Parameters::new().to_string(), "");
+    }
+
+    #[test]
+    fn statements() {
+        let mut program = PythonProgram::new();
+        program
+            .import("asyncio, contextlib, functools")
+            .write_line("calls = 0")
+            .class("Counter", &[])
+            .def("__init__", Parameters::new().param("self"), None)
+            .write_line("self.count = 0")
+            .end_block()
+            .decorator("property")
+            .def("double", Parameters::new().param("self"), Some(&"int"))
+            .r#return("self.count * 2")
+            .end_block()
+            .end_block()
+            .class("Empty", &[&"Exception"])
+            .end_block()
+            .decorator("functools.lru_cache(maxsize=None)")
+            .def(
+                "scaled",
+                Parameters::new()
+                    .typed("x", "int")
+                    .typed_optional("factor", "int", "2")
+                    .var_args("rest")
+                    .var_kwargs("options"),
+                Some(&"int"),
+            )
+            .global(&["calls"])
+            .write_line("calls += 1")
+            .r#return("x * factor + len(rest) + len(options)")
+            .end_block()
+            .def("evens", Parameters::new().param("n"), None)
+            .r#for("i in range(n)")
+            .r#if("i % 2")
+            .r#continue()
+            .end_block()
+            .r#if("i > 4")
+            .r#break()
+            .end_block()
+            .r#yield("i")
+            .end_block()
+            .end_block()
+            .def("counter", &Parameters::new(), None)
+            .write_line("total = 0")
+            .def("add", Parameters::new().param("n"), None)
+            .nonlocal(&["total"])
+            .write_line("total += n")
+            .r#return("total")
+            .end_block()
+            .r#return("add")
+            .end_block()
+            .decorator("contextlib.contextmanager")
+            .def("tagged", Parameters::new().param("tag"), None)
+            .write_line("print('enter', tag)")
+            .r#yield("tag.upper()")
+            .write_line("print('exit', tag)")
+            .end_block()
+            .async_def("twice", Parameters::new().param("x"), None)
+            .r#await("asyncio.sleep(0)")
+            .r#return("x * 2")
+            .end_block()
+            .with("tagged('a')", Some(&"tag"))
+            .write_line("print(tag)")
+            .end_block()
+            .r#try()
+            .raise("Empty('oops')")
+            .except(Some(&"(KeyError, Empty)"), Some("e"))
+            .write_line("print('caught', e)")
+            .r#else()
+            .write_line("print('not reached')")
+            .finally()
+            .write_line("print('finally')")
+            .end_block()
+            .r#try()
+            .r#try()
+            .raise("KeyError('k')")
+            .except(None, None)
+            .raise("")
+            .end_block()
+            .except(Some(&"KeyError"), None)
+            .write_line("print('re-raised')")
+            .end_block()
+            .write_line("counter_ = Counter()")
+            .write_line("counter_.count = 4")
+            .write_line("add = counter()")
+            .write_line("add(1)")
+            .write_line(&format!(
+                "print(counter_.double, scaled(3), scaled(3), calls, scaled(1, 3, 'r', k=0), list(evens(10)), add(2), asyncio.run(twice(21)), ({})(5))",
+                Parameters::new().param("x").optional("y", "1").lambda("x + y")
+            ));
+        let output = program.run_checked().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "enter a\nA\nexit a\ncaught oops\nfinally\nre-raised\n8 6 6 1 5 [0, 2, 4] 3 42 6\n"
+        );
+        assert!(program
+            .to_string()
+            .contains("class Empty(Exception):\n\tpass\n"));
+    }
+
+    #[test]
+    fn expressions() {
+        use crate::Expr;
+        use pycall_derive::AsPythonLitteral;
+
+        #[derive(AsPythonLitteral)]
+        #[python(dataclass)]
+        struct Scale {
+            factor: i32,
+        }
+
+        // Annotations, defaults, bases and exceptions may be expressions, which write the declarations they need.
+        let scale = Expr::literal(&Scale { factor: 3 });
+        let mut program = PythonProgram::new();
+        program
+            .class("Failure", &[&Expr::name("ValueError")])
+            .end_block()
+            .def(
+                "scaled",
+                Parameters::new()
+                    .param("x")
+                    .typed_optional("by", &Expr::name("object"), &scale),
+                Some(&Expr::name("int")),
+            )
+            .r#return("x * by.factor")
+            .end_block()
+            .r#try()
+            .raise("Failure(scaled(2))")
+            .except(Some(&Expr::name("Failure")), Some("e"))
+            .write_line("print(e)")
+            .end_block();
+        let output = program.run_checked().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "6\n");
+        assert!(program.to_string().starts_with("import dataclasses\n"));
+    }
+}
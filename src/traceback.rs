@@ -0,0 +1,262 @@
+//! Structured python exceptions, parsed from the tracebacks python prints.
+use std::fmt::{Display, Error, Formatter};
+use std::panic::Location;
+
+const HEADER: &str = "Traceback (most recent call last):";
+
+/// One entry of a python traceback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// The file python was executing, as it reported it.
+    pub file: String,
+    /// The 1-based line number in `file`.
+    pub line: usize,
+    /// The function being executed, `<module>` at the top level. Empty for syntax errors.
+    pub function: String,
+    /// The source of the line, when python could show it.
+    pub code: Option<String>,
+    /// Whether `file` is the generated script.
+    pub in_program: bool,
+    /// The rust call that wrote this line of the generated script, if known.
+    pub written_by: Option<&'static Location<'static>>,
+}
+
+/// An uncaught python exception.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PythonException {
+    /// The exception's type, such as `ValueError` or `mymodule.MyError`.
+    pub type_name: String,
+    /// The exception's message, possibly spanning several lines. Empty if the exception had none.
+    pub message: String,
+    /// The frames leading to the exception, outermost first.
+    pub traceback: Vec<Frame>,
+    /// The traceback as python printed it.
+    pub raw: String,
+}
+
+impl PythonException {
+    /// Parses the last exception reported in `stderr`.
+    /// Earlier output, as well as the exceptions it was raised while handling, are ignored.
+    pub fn parse(stderr: &str) -> Option<PythonException> {
+        // Syntax errors are reported without a header, as the script never started.
+        let start = stderr.rfind(HEADER).or_else(|| stderr.find("  File \""))?;
+        let raw = stderr[start..].trim_end();
+        let mut traceback: Vec<Frame> = Vec::new();
+        let mut lines = raw.lines().skip_while(|line| *line == HEADER);
+        while let Some(line) = lines.next() {
+            if let Some(location) = line.strip_prefix("  File \"") {
+                traceback.push(parse_frame(location)?);
+            } else if let Some(code) = line.strip_prefix("    ") {
+                let frame = traceback.last_mut()?;
+                let code = code.trim();
+                let is_marker = code.chars().all(|c| matches!(c, '^' | '~' | ' '));
+                if frame.code.is_none() && !is_marker {
+                    frame.code = Some(code.to_owned());
+                }
+            } else if line.starts_with(' ') || line.is_empty() {
+                // Such as "[Previous line repeated 996 more times]".
+                continue;
+            } else {
+                let (type_name, first) = match line.find(": ") {
+                    Some(colon) if is_type_name(&line[..colon]) => {
+                        (&line[..colon], &line[colon + 2..])
+                    }
+                    _ if is_type_name(line) => (line, ""),
+                    _ => return None,
+                };
+                let mut message = first.to_owned();
+                for line in lines {
+                    message.push('\n');
+                    message.push_str(line);
+                }
+                return Some(PythonException {
+                    type_name: type_name.to_owned(),
+                    message,
+                    traceback,
+                    raw: raw.to_owned(),
+                });
+            }
+        }
+        None
+    }
+
+    /// Wraps a traceback that couldn't be parsed, keeping it whole as the message.
+    pub(crate) fn unparsed(traceback: &str) -> PythonException {
+        PythonException {
+            type_name: String::new(),
+            message: traceback.trim_end().to_owned(),
+            traceback: Vec::new(),
+            raw: traceback.trim_end().to_owned(),
+        }
+    }
+
+    /// Marks the frames executing `script`, and attributes them using `written_by`.
+    pub(crate) fn locate<F>(&mut self, script: &std::path::Path, written_by: F)
+    where
+        F: Fn(usize) -> Option<&'static Location<'static>>,
+    {
+        for frame in &mut self.traceback {
+            if std::path::Path::new(&frame.file) == script {
+                frame.in_program = true;
+                frame.written_by = written_by(frame.line);
+            }
+        }
+    }
+
+    /// The innermost frame that executed the generated script.
+    pub fn program_frame(&self) -> Option<&Frame> {
+        self.traceback.iter().rev().find(|frame| frame.in_program)
+    }
+}
+
+/// Parses what follows `  File "` in a traceback: `<file>", line <n>[, in <function>]`.
+fn parse_frame(location: &str) -> Option<Frame> {
+    let quote = location.rfind("\", line ")?;
+    let rest = &location[quote + "\", line ".len()..];
+    let (line, function) = match rest.find(", in ") {
+        Some(comma) => (&rest[..comma], &rest[comma + ", in ".len()..]),
+        None => (rest, ""),
+    };
+    Some(Frame {
+        file: location[..quote].to_owned(),
+        line: line.parse().ok()?,
+        function: function.to_owned(),
+        code: None,
+        in_program: false,
+        written_by: None,
+    })
+}
+
+fn is_type_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .split('.')
+            .all(|part| part.chars().next().is_some_and(|c| !c.is_ascii_digit()))
+        && name
+            .chars()
+            .all(|c| c == '.' || c == '_' || c.is_alphanumeric())
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        if self.in_program {
+            write!(f, "line {} of the generated script", self.line)?;
+            if let Some(location) = self.written_by {
+                write!(f, ", written by {}", location)?;
+            }
+        } else {
+            write!(f, "File \"{}\", line {}", self.file, self.line)?;
+        }
+        if !self.function.is_empty() && self.function != "<module>" {
+            write!(f, ", in {}", self.function)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for PythonException {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        if self.type_name.is_empty() {
+            return f.write_str(&self.raw);
+        }
+        if !self.traceback.is_empty() {
+            writeln!(f, "{}", HEADER)?;
+        }
+        for frame in &self.traceback {
+            writeln!(f, "  {}", frame)?;
+            if let Some(code) = &frame.code {
+                writeln!(f, "    {}", code)?;
+            }
+        }
+        f.write_str(&self.type_name)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PythonException {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tracebacks() {
+        let stderr = "warming up
+Traceback (most recent call last):
+  File \"/tmp/script.py\", line 3, in <module>
+    helper(x)
+    ^^^^^^^^^
+  File \"/usr/lib/python3.11/json/__init__.py\", line 346, in helper
+    return _default_decoder.decode(s)
+           ~~~~~~~~~~~~~~~~^^^
+  [Previous line repeated 2 more times]
+json.decoder.JSONDecodeError: Expecting value: line 1 column 1 (char 0)
+";
+        let exception = PythonException::parse(stderr).unwrap();
+        assert_eq!(exception.type_name, "json.decoder.JSONDecodeError");
+        assert_eq!(
+            exception.message,
+            "Expecting value: line 1 column 1 (char 0)"
+        );
+        assert!(exception.raw.starts_with(HEADER));
+        assert_eq!(
+            exception.traceback,
+            vec![
+                Frame {
+                    file: "/tmp/script.py".to_owned(),
+                    line: 3,
+                    function: "<module>".to_owned(),
+                    code: Some("helper(x)".to_owned()),
+                    in_program: false,
+                    written_by: None,
+                },
+                Frame {
+                    file: "/usr/lib/python3.11/json/__init__.py".to_owned(),
+                    line: 346,
+                    function: "helper".to_owned(),
+                    code: Some("return _default_decoder.decode(s)".to_owned()),
+                    in_program: false,
+                    written_by: None,
+                },
+            ]
+        );
+
+        let chained = "Traceback (most recent call last):
+  File \"a.py\", line 1, in <module>
+KeyError: 'x'
+
+During handling of the above exception, another exception occurred:
+
+Traceback (most recent call last):
+  File \"a.py\", line 3, in <module>
+KeyboardInterrupt
+";
+        let exception = PythonException::parse(chained).unwrap();
+        assert_eq!(exception.type_name, "KeyboardInterrupt");
+        assert_eq!(exception.message, "");
+        assert_eq!(exception.traceback[0].line, 3);
+
+        let syntax = "  File \"/tmp/script.py\", line 2
+    if x
+        ^
+SyntaxError: expected ':'
+";
+        let exception = PythonException::parse(syntax).unwrap();
+        assert_eq!(exception.type_name, "SyntaxError");
+        assert_eq!(exception.traceback[0].function, "");
+        assert_eq!(exception.traceback[0].code.as_deref(), Some("if x"));
+
+        let multiline = "Traceback (most recent call last):
+  File \"a.py\", line 1, in <module>
+AssertionError: first
+second";
+        assert_eq!(
+            PythonException::parse(multiline).unwrap().message,
+            "first\nsecond"
+        );
+        assert_eq!(PythonException::parse("Segmentation fault"), None);
+    }
+}
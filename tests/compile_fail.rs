@@ -0,0 +1,6 @@
+//! Templates of `py!` and derived types that must fail to compile.
+
+#[test]
+fn compile_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
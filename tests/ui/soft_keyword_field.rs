@@ -0,0 +1,9 @@
+use pycall_derive::AsPythonLitteral;
+
+#[derive(AsPythonLitteral)]
+#[python(dataclass)]
+struct Token {
+    r#type: u8,
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+use pycall::PythonProgram;
+use pycall_derive::py;
+
+fn main() {
+    let mut program = PythonProgram::new();
+    py!(program, "print({x}");
+}